@@ -1,13 +1,11 @@
 use rusty_butler_lib::plugins;
-use rusty_butler_lib::plugins::AsyncMutableHandler;
-use rusty_butler_lib::plugins::Handler;
-use rusty_butler_lib::plugins::MutableHandler;
+use rusty_butler_lib::plugins::Plugin;
 
 use futures::prelude::*;
 use irc::client::prelude::*;
-use std::sync::Mutex;
 
 use clap::{App, Arg};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
 #[macro_use]
 extern crate log;
@@ -28,125 +26,371 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Use a different configuration file")
                 .default_value("bot.toml"),
         )
+        .arg(
+            Arg::with_name("plugins-config")
+                .long("plugins-config")
+                .value_name("FILE")
+                .help("Use a different plugins configuration file")
+                .default_value("plugins.toml"),
+        )
+        .arg(
+            Arg::with_name("console")
+                .long("console")
+                .help("Talk to the plugins on stdin instead of connecting to a server"),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("Check both configuration files for problems and exit without connecting"),
+        )
         .get_matches();
 
     let config_file_name = matches.value_of("config").unwrap();
+    let plugins_file_name = matches.value_of("plugins-config").unwrap().to_owned();
+    let console = matches.is_present("console");
+    if matches.is_present("check-config") {
+        let report = plugins::check::check(config_file_name, &plugins_file_name);
+        for warning in &report.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        for error in &report.errors {
+            eprintln!("error: {}", error);
+        }
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        println!("{} and {} look fine.", config_file_name, plugins_file_name);
+        return Ok(());
+    }
     let config = Config::load(config_file_name).expect("Failed to load config");
     let config_for_handlers = Config::load(config_file_name).expect("Failed to load config");
-    let plugin_config = plugins::config::Config::new();
-
-    let mut client = Client::from_config(config.clone()).await?;
-    // Asks server if it can do SASL, once acknowledged (see later, we can ask to authenticate with
-    // it).
-    client.send_cap_req(&[Capability::Sasl])?;
-    // Identify with SASL instead of nickserv password sending
-    // Need to set client_cert_path and client_cert_pass in bot.toml
-    // The cert needs to be p12 format. Probably need to set use_ssl and use_tls to true too
-    // client.identify().expect("Failed to identify");
-    // .identify() would send these for us, so just emulate that
-    client.send(Command::NICK(config.nickname()?.to_string()))?;
-    client.send(Command::USER(
-        config.username().to_string(),
-        "0".to_owned(),
-        config.real_name().to_string(),
-    ))?;
-    let mut stream = client.stream()?;
-
-    // Non mutable handlers
-    let mut help_handler = plugins::help::HelpHandler::new();
-    let time_handler = plugins::time::TimeHandler::new();
-    help_handler.add_help(&time_handler);
-    let simple_reply_handler = plugins::simple_reply::SimpleReplyHandler::new(&plugin_config);
-    help_handler.add_help(&simple_reply_handler);
-    let mut handlers: Vec<Box<dyn Handler>> =
-        vec![Box::new(time_handler), Box::new(simple_reply_handler)];
-
-    // Mutable handlers
-    let nickname_handler = plugins::nickname::NicknameHandler::new(&config_for_handlers);
-    help_handler.add_help(&nickname_handler);
-    let calc_handler = plugins::calc::CalcHandler::new();
-    help_handler.add_help(&calc_handler);
-    let last_seen_handler = plugins::lastseen::LastSeenHandler::new();
-    help_handler.add_help(&last_seen_handler);
-    let mutable_handlers: Vec<Mutex<Box<dyn MutableHandler>>> = vec![
-        Mutex::new(Box::new(nickname_handler)),
-        Mutex::new(Box::new(calc_handler)),
-        Mutex::new(Box::new(last_seen_handler)),
-    ];
-
-    // Async mutable handlers
-    let elo_handler = plugins::elo::EloHandler::new();
-    help_handler.add_help(&elo_handler);
-    let ranking_handler = plugins::leagueranking::LeagueRankingHandler::new();
-    help_handler.add_help(&ranking_handler);
-    let strava_handler = plugins::strava::StravaHandler::new(&plugin_config);
-    help_handler.add_help(&strava_handler);
-    let untappd_handler = plugins::untappd::UntappdHandler::new(&config_for_handlers);
-    help_handler.add_help(&untappd_handler);
-    let games_handler = plugins::games::GamesHandler::new().await;
-    help_handler.add_help(&games_handler);
-    let third_place_handler = plugins::thirdplace::ThirdPlaceHandler::new().await;
-    help_handler.add_help(&third_place_handler);
-    let async_mutable_handlers: Vec<Mutex<Box<dyn AsyncMutableHandler>>> = vec![
-        Mutex::new(Box::new(elo_handler)),
-        Mutex::new(Box::new(ranking_handler)),
-        Mutex::new(Box::new(strava_handler)),
-        Mutex::new(Box::new(untappd_handler)),
-        Mutex::new(Box::new(games_handler)),
-        Mutex::new(Box::new(third_place_handler)),
-    ];
-
-    // Could not move help_handler before
-    handlers.push(Box::new(help_handler));
-
-    // TODO Should these handlers all become async? There should not be much intersection so
-    // perhaps not worth the effort. Only one will _truly_ react to a message.
-    while let Some(irc_msg) = stream.next().await.transpose()? {
-        plugins::print_msg(&irc_msg);
-
-        // Should I move this SASL stuff to its own module?
-        // Cleaner still would be seeing how I can get it into upstream.
-        match irc_msg.command {
-            Command::CAP(_, ref subcommand, _, _) => {
-                if subcommand.to_str() == "ACK" {
-                    info!("Recieved ack for sasl");
-                    // client.send_sasl_plain()?;
-                    client.send_sasl_external()?;
+    let plugin_config = match plugins::config::Config::from_file(&plugins_file_name) {
+        Ok(plugin_config) => plugin_config,
+        Err(e) => {
+            eprintln!(
+                "Failed to load {}: {}\nTry --check-config for the details.",
+                plugins_file_name, e
+            );
+            std::process::exit(1);
+        }
+    };
+    let sasl_config = plugins::sasl::SaslConfig::new(&config)?;
+    let storage = plugins::storage::Storage::open(&plugin_config.storage)?;
+    let http = plugins::http::Http::new(&plugin_config.http)?;
+    // Admin commands that need main to do something come in through here
+    let (admin_requests, admin_actions) = tokio::sync::mpsc::unbounded_channel();
+
+    // Not a regular plugin, it rewrites messages before the plugins get to see them
+    let alias_plugin = plugins::alias::AliasPlugin::new(&plugin_config);
+
+    // Plugins each run in their own task, see plugins::dispatch, and keep their data up to date
+    // in tasks of their own. They, the dispatcher and the outbound queue outlive any single
+    // connection.
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async move {
+            // How plugins talk to each other
+            let bus = plugins::bus::Bus::new();
+            let handlers = plugins::build(
+                &config_for_handlers,
+                &plugin_config,
+                &storage,
+                &http,
+                &bus,
+                admin_requests,
+            )
+            .await;
+            if let Some(ref listen) = plugin_config.metrics.listen {
+                plugins::metrics::serve(listen);
+            }
+            let outbound = plugins::outbound::Outbound::new(&config, &plugin_config.outbound);
+            let limiter = plugins::ratelimit::RateLimiter::new(plugin_config.ratelimit.clone());
+            let mut dispatcher = plugins::dispatch::Dispatcher::new(
+                outbound.clone(),
+                limiter,
+                plugin_config.quarantine.clone(),
+            );
+            for handler in handlers {
+                let settings = plugin_config.plugin(&handler.name());
+                dispatcher.add(handler, settings);
+            }
+
+            let mut bot = Bot {
+                backoff: plugins::connection::Backoff::new(&plugin_config.reconnect),
+                channels: plugins::connection::Channels::new(),
+                config,
+                sasl_config,
+                plugin_config,
+                plugins_file_name,
+                storage,
+                http,
+                bus,
+                outbound,
+                dispatcher,
+                alias_plugin,
+                admin_actions,
+                hangup: signal(SignalKind::hangup())?,
+            };
+            if console {
+                return bot.console().await;
+            }
+            loop {
+                match bot.session().await {
+                    Ok(Ended::Quit) => break Ok(()),
+                    Ok(Ended::Closed) => warn!("Connection closed"),
+                    // Trying again will not change anything
+                    Err(e) if e.is::<plugins::sasl::SaslError>() => break Err(e),
+                    Err(e) => error!("Connection lost: {}", e),
+                }
+                bot.outbound.disconnect();
+                plugins::metrics::reconnected();
+                let delay = bot.backoff.next_delay();
+                info!("Reconnecting in {}s", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+        })
+        .await
+}
+
+enum Ended {
+    /// By the server, or the network
+    Closed,
+    /// By an admin
+    Quit,
+}
+
+/// Everything that outlives a single connection.
+struct Bot {
+    config: Config,
+    sasl_config: plugins::sasl::SaslConfig,
+    plugin_config: plugins::config::Config,
+    plugins_file_name: String,
+    storage: plugins::storage::Storage,
+    /// Settings only change with a restart
+    http: plugins::http::Http,
+    /// For plugins built again on reload
+    bus: plugins::bus::Bus,
+    outbound: plugins::outbound::Outbound,
+    dispatcher: plugins::dispatch::Dispatcher,
+    alias_plugin: plugins::alias::AliasPlugin,
+    channels: plugins::connection::Channels,
+    backoff: plugins::connection::Backoff,
+    admin_actions: tokio::sync::mpsc::UnboundedReceiver<plugins::admin::AdminRequest>,
+    /// `kill -HUP` reloads the plugins config
+    hangup: Signal,
+}
+
+impl Bot {
+    /// One connection, from building the client until the stream ends or errors.
+    async fn session(&mut self) -> Result<Ended, Box<dyn std::error::Error>> {
+        let config = &self.config;
+        let mut client = Client::from_config(config.clone()).await?;
+        let mut sasl = plugins::sasl::Sasl::new(self.sasl_config.clone());
+        let mut caps = plugins::caps::Caps::new(self.sasl_config.mechanism.is_some());
+        for command in caps.start().into_iter().chain(sasl.start()) {
+            client.send(command)?;
+        }
+        // .identify() would send these for us, but it also ends capability negotiation right away
+        client.send(Command::NICK(config.nickname()?.to_string()))?;
+        client.send(Command::USER(
+            config.username().to_string(),
+            "0".to_owned(),
+            config.real_name().to_string(),
+        ))?;
+        let mut stream = client.stream()?;
+
+        let mut quitting = false;
+        loop {
+            tokio::select! {
+                irc_msg = stream.next() => {
+                    let irc_msg = match irc_msg.transpose()? {
+                        Some(irc_msg) => irc_msg,
+                        None => break,
+                    };
+                    plugins::print_msg(&irc_msg);
+                    self.outbound.observe(&irc_msg);
+                    self.channels.observe(client.current_nickname(), &irc_msg);
+
+                    for command in caps.handle(&irc_msg).into_iter().chain(sasl.handle(&irc_msg)?) {
+                        client.send(command)?;
+                    }
+                    self.outbound.set_message_tags(caps.has("message-tags"));
+                    match irc_msg.command {
+                        Command::Response(Response::RPL_WELCOME, _) => {
+                            self.outbound.connect(client.sender());
+                            self.backoff.reset();
+                        }
+                        // The client joins the channels from bot.toml at this point, we do the
+                        // rest
+                        Command::Response(Response::RPL_ENDOFMOTD, _)
+                        | Command::Response(Response::ERR_NOMOTD, _) => {
+                            let rejoin = self.channels.to_rejoin(&self.config);
+                            if !rejoin.is_empty() {
+                                client.send_join(rejoin.join(","))?;
+                            }
+                        }
+                        _ => {}
+                    };
+
+                    self.dispatcher.dispatch(self.alias_plugin.rewrite(irc_msg));
+                }
+                Some(request) = self.admin_actions.recv() => {
+                    if let Some(message) = self.admin(request) {
+                        client.send_quit(message)?;
+                        quitting = true;
+                    }
+                }
+                Some(()) = self.hangup.recv() => {
+                    info!("Got SIGHUP. {}", self.reload());
+                }
+            }
+        }
+
+        Ok(if quitting { Ended::Quit } else { Ended::Closed })
+    }
+
+    /// `--console`: stdin instead of a server, until stdin closes or an admin quits.
+    async fn console(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::AsyncBufReadExt;
+        // Plugins may still be working on the last line when stdin closes (piped input), give
+        // them until it has been quiet for this long
+        const LINGER: std::time::Duration = std::time::Duration::from_secs(5);
+
+        let console_config = self.plugin_config.console.clone();
+        let own_nick = self.config.nickname()?.to_owned();
+        let (console, mut sent) = tokio::sync::mpsc::unbounded_channel();
+        self.outbound.connect(console);
+        let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        println!(
+            "Talking as {} in {}, end with Ctrl-D.",
+            console_config.nick, console_config.channel
+        );
+        loop {
+            tokio::select! {
+                line = lines.next_line() => match line? {
+                    Some(line) => {
+                        let msg = console_config.message(&line);
+                        self.dispatcher.dispatch(self.alias_plugin.rewrite(msg));
+                    }
+                    None => break,
+                },
+                Some(message) = sent.recv() => {
+                    if let Some(line) = plugins::console::render(&own_nick, &message.command) {
+                        println!("{}", line);
+                    }
                 }
+                Some(request) = self.admin_actions.recv() => {
+                    if self.admin(request).is_some() {
+                        return Ok(());
+                    }
+                }
+                Some(()) = self.hangup.recv() => {
+                    info!("Got SIGHUP. {}", self.reload());
+                }
+            }
+        }
+        while let Ok(Some(message)) = tokio::time::timeout(LINGER, sent.recv()).await {
+            if let Some(line) = plugins::console::render(&own_nick, &message.command) {
+                println!("{}", line);
             }
-            Command::AUTHENTICATE(_) => {
-                info!("Got signal to continue authenticating");
-                client.send(Command::AUTHENTICATE(String::from('+')))?;
-                // client.send(Command::AUTHENTICATE(base64::encode(format!(
-                //     "{}\x00{}\x00{}",
-                //     config.nickname()?.to_string(),
-                //     config.nickname()?.to_string(),
-                //     config.password().to_string()
-                // ))))?;
-                client.send(Command::CAP(None, "END".parse()?, None, None))?;
+        }
+        Ok(())
+    }
+
+    /// Does what the admin plugin asked for. Returns the quit message if we should quit.
+    fn admin(&mut self, request: plugins::admin::AdminRequest) -> Option<String> {
+        use plugins::admin::AdminAction;
+        let reply = match request.action {
+            AdminAction::Reload => self.reload(),
+            AdminAction::Disable(ref name) if name == "admin" => {
+                String::from("Not disabling admin, you would have no way to turn it back on.")
             }
-            Command::Response(code, _) => {
-                if code == Response::RPL_SASLSUCCESS {
-                    info!("Successfully authenticated");
-                    client.send(Command::CAP(None, "END".parse()?, None, None))?;
+            AdminAction::Enable(ref name) | AdminAction::Disable(ref name) => {
+                let enable = matches!(request.action, AdminAction::Enable(_));
+                if self.dispatcher.set_enabled(name, enable) {
+                    format!("{} {}.", if enable { "Enabled" } else { "Disabled" }, name)
+                } else {
+                    format!("There is no plugin {} running.", name)
                 }
             }
-            _ => {}
+            AdminAction::Quit(message) => return Some(message),
         };
+        self.outbound.reply(&request.msg, &reply);
+        None
+    }
 
-        for handler in &handlers {
-            handler.handle(&client, &irc_msg);
+    /// Reads the plugins config (plugins.toml) again. Only the plugins whose config changed get built again, the
+    /// others keep whatever they have in memory. If the new file has a problem, nothing changes.
+    /// Says how it went, including what will only change with a restart.
+    fn reload(&mut self) -> String {
+        let file_name = &self.plugins_file_name;
+        let new_config = match plugins::config::Config::from_file(file_name) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                return format!(
+                    "Keeping the old settings, {} has a problem: {}",
+                    file_name, e
+                )
+            }
+        };
+        let affected = self.plugin_config.affected_plugins(&new_config);
+        let mut restart_needed: Vec<String> = self
+            .plugin_config
+            .restart_needed(&new_config)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        for name in self.plugin_config.newly_enabled(&new_config) {
+            if !self.dispatcher.has(name) {
+                restart_needed.push(format!("{} (newly enabled)", name));
+            }
         }
-        for mutable_handler in &mutable_handlers {
-            // TODO Is there a possibility of this slowing things down in unforseen ways?
-            let mut mutable_handler = mutable_handler.lock().expect("Likely fatal! Getting a lock failed which implies another thread holding the lock panicked");
-            mutable_handler.handle(&client, &irc_msg);
+        self.dispatcher.reconfigure(&new_config);
+        let mut rebuilt = vec![];
+        for name in &affected {
+            let plugin: Box<dyn Plugin> = match *name {
+                "alias" => {
+                    self.alias_plugin = plugins::alias::AliasPlugin::new(&new_config);
+                    rebuilt.push(*name);
+                    continue;
+                }
+                "simple_reply" => {
+                    Box::new(plugins::simple_reply::SimpleReplyHandler::new(&new_config))
+                }
+                "league_ranking" => Box::new(plugins::leagueranking::LeagueRankingHandler::new(
+                    &new_config,
+                    &self.http,
+                )),
+                "strava" => Box::new(plugins::strava::StravaHandler::new(
+                    &new_config,
+                    &self.storage,
+                    &self.http,
+                    &self.bus,
+                )),
+                _ => continue,
+            };
+            // Nothing to do for plugins that are not running
+            if self.dispatcher.replace(plugin) {
+                rebuilt.push(*name);
+            }
         }
-        for async_mutable_handler in &async_mutable_handlers {
-            let mut async_mutable_handler = async_mutable_handler.lock().expect("Likely fatal! Getting a lock failed which implies another thread holding the lock panicked");
-            async_mutable_handler.handle(&client, &irc_msg).await;
+        if !rebuilt.is_empty() {
+            // Their commands or help may have changed
+            let help = self.dispatcher.help();
+            self.dispatcher.replace(Box::new(help));
         }
+        self.plugin_config = new_config;
+        let mut reply = format!("Reloaded {}", self.plugins_file_name);
+        if !rebuilt.is_empty() {
+            reply.push_str(&format!(", rebuilt {}", rebuilt.join(", ")));
+        }
+        if !restart_needed.is_empty() {
+            reply.push_str(&format!(
+                ". Restart needed for: {}",
+                restart_needed.join(", ")
+            ));
+        }
+        reply.push('.');
+        reply
     }
-
-    Ok(())
 }
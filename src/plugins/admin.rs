@@ -0,0 +1,492 @@
+//! Commands for whoever runs the bot, so not everything needs a restart. Who counts as an admin
+//! is set in the `[options]` of bot.toml:
+//!
+//! - `admin_hostmasks`: masks like `ward!*@user/ward`, `*` and `?` work as wildcards.
+//! - `admin_accounts`: NickServ accounts. If the server tags messages with the account (see
+//!   `caps`) we go by that. Otherwise we ask the server with a WHOIS which account someone is
+//!   logged in to, so the command only runs once the answer comes in. Without an answer within
+//!   `WHOIS_TIMEOUT_SECS`, the commands waiting for it are dropped and the next one asks again.
+//!
+//! Both take a comma separated list. Every attempt, allowed or not, ends up in the audit log in
+//! storage.
+//!
+//! Joining, parting and talking happen right here. Whatever touches the other plugins or the
+//! connection is handed to main as an `AdminRequest`.
+
+use super::caps::Context;
+use super::clock;
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use super::storage::Storage;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use irc::client::prelude::*;
+use regex::Regex;
+use rusqlite::params;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+const MIGRATIONS: &[&str] = &["CREATE TABLE admin_audit (
+    id INTEGER PRIMARY KEY,
+    at TEXT NOT NULL,
+    who TEXT NOT NULL,
+    account TEXT,
+    command TEXT NOT NULL,
+    allowed INTEGER NOT NULL
+);"];
+
+const RPL_WELCOME: u16 = 1;
+const RPL_WHOISACCOUNT: u16 = 330;
+const RPL_ENDOFWHOIS: u16 = 318;
+
+/// How long to wait for the answer to a WHOIS. It never comes when it went out while
+/// disconnected, or when the server drops it.
+const WHOIS_TIMEOUT_SECS: i64 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAction {
+    /// Read plugins.toml again
+    Reload,
+    Enable(String),
+    Disable(String),
+    Quit(String),
+}
+
+/// What main gets to do, with the message that asked for it so it can reply.
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub action: AdminAction,
+    pub msg: Message,
+}
+
+/// Who is allowed to use admin commands.
+#[derive(Debug, Default)]
+pub struct Acl {
+    hostmasks: Vec<Regex>,
+    accounts: Vec<String>,
+}
+
+impl Acl {
+    pub fn new(irc_config: &Config) -> Self {
+        let list = |name: &str| -> Vec<String> {
+            irc_config
+                .options
+                .get(name)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|entry| entry.trim().to_owned())
+                        .filter(|entry| !entry.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let hostmasks = list("admin_hostmasks")
+            .iter()
+            .map(|mask| hostmask_regex(mask))
+            .collect();
+        let accounts = list("admin_accounts")
+            .into_iter()
+            .map(|account| account.to_lowercase())
+            .collect();
+        Self {
+            hostmasks,
+            accounts,
+        }
+    }
+
+    fn allows_hostmask(&self, msg: &Message) -> bool {
+        match hostmask(msg) {
+            Some(hostmask) => self.hostmasks.iter().any(|mask| mask.is_match(&hostmask)),
+            None => false,
+        }
+    }
+
+    fn allows_account(&self, account: &str) -> bool {
+        self.accounts.contains(&account.to_lowercase())
+    }
+}
+
+/// Turns a hostmask with wildcards into a (case insensitive) regex that matches all of it.
+fn hostmask_regex(mask: &str) -> Regex {
+    let pattern = regex::escape(mask).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("(?i)^{}$", pattern)).expect("Escaped hostmask should be a valid regex")
+}
+
+fn hostmask(msg: &Message) -> Option<String> {
+    match msg.prefix {
+        Some(Prefix::Nickname(ref nick, ref user, ref host)) => {
+            Some(format!("{}!{}@{}", nick, user, host))
+        }
+        _ => None,
+    }
+}
+
+/// The numeric of a server reply, whether or not the irc crate knows it by name.
+fn numeric(command: &Command) -> Option<(u16, &[String])> {
+    match command {
+        Command::Response(response, args) => Some((*response as u16, args)),
+        Command::Raw(code, args) => code.parse().ok().map(|code| (code, args.as_slice())),
+        _ => None,
+    }
+}
+
+pub struct AdminPlugin {
+    acl: Acl,
+    actions: mpsc::UnboundedSender<AdminRequest>,
+    storage: Storage,
+    /// Commands waiting for a WHOIS to tell us the account of whoever used them, by lowercased
+    /// nick
+    pending: HashMap<String, Pending>,
+}
+
+struct Pending {
+    /// When the WHOIS went out
+    asked_at: DateTime<Utc>,
+    commands: Vec<(Message, Invocation)>,
+}
+
+impl AdminPlugin {
+    pub fn new(
+        irc_config: &Config,
+        storage: &Storage,
+        actions: mpsc::UnboundedSender<AdminRequest>,
+    ) -> Self {
+        if let Err(e) = storage.migrate("admin", MIGRATIONS) {
+            log::error!("Failed to set up the admin audit log. {}", e);
+        }
+        Self {
+            acl: Acl::new(irc_config),
+            actions,
+            storage: storage.clone(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn audit(&self, msg: &Message, account: Option<&str>, allowed: bool) {
+        let who = hostmask(msg).unwrap_or_default();
+        let command = match msg.command {
+            Command::PRIVMSG(_, ref text) => text.as_str(),
+            _ => "",
+        };
+        if allowed {
+            log::info!("Admin command by {}: {}", who, command);
+        } else {
+            log::warn!("Refused admin command by {}: {}", who, command);
+        }
+        let result = self.storage.connection().execute(
+            "INSERT INTO admin_audit (at, who, account, command, allowed)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![clock::now(), who, account, command, allowed],
+        );
+        if let Err(e) = result {
+            log::error!("Failed to write admin audit log. {}", e);
+        }
+    }
+
+    fn refuse(&self, outbound: &Outbound, msg: &Message, account: Option<&str>) {
+        self.audit(msg, account, false);
+        outbound.reply(msg, "You are not allowed to do that.");
+    }
+
+    fn run(
+        &self,
+        outbound: &Outbound,
+        msg: &Message,
+        invocation: &Invocation,
+        account: Option<&str>,
+    ) {
+        self.audit(msg, account, true);
+        let request = |action| AdminRequest {
+            action,
+            msg: msg.clone(),
+        };
+        match invocation.command.as_str() {
+            "join" => {
+                let channel = invocation.get("CHANNEL").unwrap_or_default();
+                outbound.send(Command::JOIN(channel.to_owned(), None, None));
+            }
+            "part" => {
+                let here = msg
+                    .response_target()
+                    .filter(|target| target.is_channel_name());
+                match invocation.get("CHANNEL").or(here) {
+                    Some(channel) => outbound.send(Command::PART(channel.to_owned(), None)),
+                    None => outbound.reply(msg, "Part which channel?"),
+                }
+            }
+            "say" => {
+                let target = invocation.get("TARGET").unwrap_or_default();
+                outbound.privmsg(target, invocation.get("TEXT").unwrap_or_default());
+            }
+            "reload" => self.send(request(AdminAction::Reload)),
+            "plugin" => {
+                let plugin = invocation.get("PLUGIN").unwrap_or_default().to_owned();
+                match invocation.get("ACTION").unwrap_or_default() {
+                    "enable" => self.send(request(AdminAction::Enable(plugin))),
+                    "disable" => self.send(request(AdminAction::Disable(plugin))),
+                    other => outbound.reply(
+                        msg,
+                        &format!("Can only enable or disable a plugin, not '{}'.", other),
+                    ),
+                }
+            }
+            "quit" => {
+                let message = invocation.get("MESSAGE").unwrap_or("Bye");
+                self.send(request(AdminAction::Quit(message.to_owned())));
+            }
+            _ => {}
+        }
+    }
+
+    fn send(&self, request: AdminRequest) {
+        if self.actions.send(request).is_err() {
+            log::error!("Nobody is listening for admin actions");
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl super::Plugin for AdminPlugin {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new("join", "Join CHANNEL. Admins only.").arg(Arg::word("CHANNEL")),
+            CommandSpec::new("part", "Leave CHANNEL, or this channel. Admins only.")
+                .arg(Arg::word("CHANNEL").optional()),
+            CommandSpec::new("say", "Say TEXT to TARGET. Admins only.")
+                .arg(Arg::word("TARGET"))
+                .arg(Arg::text("TEXT")),
+            CommandSpec::new("reload", "Read plugins.toml again. Admins only."),
+            CommandSpec::new(
+                "plugin",
+                "Enable or disable PLUGIN, enabling also lifts a quarantine. Admins only.",
+            )
+            .arg(Arg::word("ACTION"))
+            .arg(Arg::word("PLUGIN")),
+            CommandSpec::new("quit", "Disconnect and stop. Admins only.")
+                .arg(Arg::text("MESSAGE").optional()),
+        ]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        if self.acl.allows_hostmask(msg) {
+            self.run(outbound, msg, invocation, None);
+            return;
+        }
+        // The server already told us who this is, no need to ask
+        if let Some(account) = Context::new(msg).account {
+            if self.acl.allows_account(&account) {
+                self.run(outbound, msg, invocation, Some(&account));
+            } else {
+                self.refuse(outbound, msg, Some(&account));
+            }
+            return;
+        }
+        match msg.source_nickname() {
+            Some(nick) if !self.acl.accounts.is_empty() => {
+                let now = clock::now();
+                let key = nick.to_lowercase();
+                let timed_out = self.pending.get(&key).is_some_and(|pending| {
+                    now - pending.asked_at >= Duration::seconds(WHOIS_TIMEOUT_SECS)
+                });
+                if timed_out {
+                    for (msg, _) in self
+                        .pending
+                        .remove(&key)
+                        .map(|pending| pending.commands)
+                        .unwrap_or_default()
+                    {
+                        self.audit(&msg, None, false);
+                        outbound.reply(&msg, "The server never said who you are, try again.");
+                    }
+                }
+                let pending = self.pending.entry(key).or_insert_with(|| {
+                    outbound.send(Command::WHOIS(None, nick.to_owned()));
+                    Pending {
+                        asked_at: now,
+                        commands: vec![],
+                    }
+                });
+                pending.commands.push((msg.clone(), invocation.clone()));
+            }
+            _ => self.refuse(outbound, msg, None),
+        }
+    }
+
+    async fn handle(&mut self, outbound: &Outbound, msg: &Message) {
+        // Both replies go: our nick, their nick, ...
+        match numeric(&msg.command) {
+            Some((RPL_WHOISACCOUNT, [_, nick, account, ..]))
+                if self.acl.allows_account(account) =>
+            {
+                for (msg, invocation) in self
+                    .pending
+                    .remove(&nick.to_lowercase())
+                    .map(|pending| pending.commands)
+                    .unwrap_or_default()
+                {
+                    self.run(outbound, &msg, &invocation, Some(account));
+                }
+            }
+            Some((RPL_ENDOFWHOIS, [_, nick, ..])) => {
+                for (msg, _) in self
+                    .pending
+                    .remove(&nick.to_lowercase())
+                    .map(|pending| pending.commands)
+                    .unwrap_or_default()
+                {
+                    self.refuse(outbound, &msg, None);
+                }
+            }
+            // A new connection, answers to what we asked on the old one will not come
+            Some((RPL_WELCOME, _)) if !self.pending.is_empty() => {
+                log::warn!(
+                    "Dropping admin commands still waiting for a WHOIS from {} nick(s)",
+                    self.pending.len()
+                );
+                self.pending.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl super::help::Help for AdminPlugin {
+    fn name(&self) -> String {
+        String::from("admin")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(hostmasks: &str, accounts: &str) -> Acl {
+        let mut config = Config::default();
+        config
+            .options
+            .insert(String::from("admin_hostmasks"), hostmasks.to_owned());
+        config
+            .options
+            .insert(String::from("admin_accounts"), accounts.to_owned());
+        Acl::new(&config)
+    }
+
+    fn from(prefix: &str) -> Message {
+        format!(":{} PRIVMSG #chan :!quit", prefix).parse().unwrap()
+    }
+
+    #[test]
+    fn hostmasks() {
+        let acl = acl("ward!*@user/ward, *!*@admin.example.org", "");
+        assert!(acl.allows_hostmask(&from("ward!~ward@user/ward")));
+        assert!(acl.allows_hostmask(&from("WARD!ward@USER/WARD")));
+        assert!(acl.allows_hostmask(&from("someone!else@admin.example.org")));
+        assert!(!acl.allows_hostmask(&from("ward!ward@user/ward.evil")));
+        assert!(!acl.allows_hostmask(&from("someone!else@adminXexample.org")));
+        assert!(!acl.allows_hostmask(&from("someone!else@example.org")));
+    }
+
+    #[test]
+    fn accounts() {
+        let acl = acl("", "Ward, butler_owner");
+        assert!(acl.allows_account("ward"));
+        assert!(acl.allows_account("BUTLER_OWNER"));
+        assert!(!acl.allows_account("someone"));
+        assert!(Acl::default().hostmasks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn account_tag() {
+        use super::super::router::Router;
+        use super::super::Plugin;
+
+        let mut config = Config::default();
+        config
+            .options
+            .insert(String::from("admin_accounts"), String::from("ward"));
+        let (actions, mut requests) = mpsc::unbounded_channel();
+        let mut admin = AdminPlugin::new(&config, &Storage::in_memory().unwrap(), actions);
+        let (outbound, recorder) = Outbound::recording();
+        let mut router = Router::new();
+        router.add(0, "admin", admin.commands());
+        let (_, invocation) = router.route("!reload").unwrap();
+        let invocation = invocation.unwrap();
+
+        let msg: Message = "@account=ward :w!w@host PRIVMSG #chan :!reload"
+            .parse()
+            .unwrap();
+        admin.command(&outbound, &msg, &invocation).await;
+        assert_eq!(requests.try_recv().unwrap().action, AdminAction::Reload);
+        assert!(recorder.take().is_empty());
+
+        // Someone else's account, no need to ask the server
+        let msg: Message = "@account=nope :w!w@host PRIVMSG #chan :!reload"
+            .parse()
+            .unwrap();
+        admin.command(&outbound, &msg, &invocation).await;
+        assert!(requests.try_recv().is_err());
+        assert_eq!(
+            recorder.take(),
+            vec!["PRIVMSG #chan You are not allowed to do that."]
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_whois() {
+        use super::super::router::Router;
+        use super::super::Plugin;
+
+        clock::freeze("2026-06-20T18:00:00Z".parse().unwrap());
+        let mut config = Config::default();
+        config
+            .options
+            .insert(String::from("admin_accounts"), String::from("ward"));
+        let (actions, mut requests) = mpsc::unbounded_channel();
+        let mut admin = AdminPlugin::new(&config, &Storage::in_memory().unwrap(), actions);
+        let (outbound, recorder) = Outbound::recording();
+        let mut router = Router::new();
+        router.add(0, "admin", admin.commands());
+        let (_, invocation) = router.route("!reload").unwrap();
+        let invocation = invocation.unwrap();
+        let msg: Message = ":w!w@host PRIVMSG #chan :!reload".parse().unwrap();
+
+        admin.command(&outbound, &msg, &invocation).await;
+        assert_eq!(recorder.take(), vec!["WHOIS w"]);
+        // Still waiting for that one
+        admin.command(&outbound, &msg, &invocation).await;
+        assert!(recorder.take().is_empty());
+
+        // It is not coming, the next command asks again
+        clock::freeze(clock::now() + Duration::seconds(WHOIS_TIMEOUT_SECS));
+        admin.command(&outbound, &msg, &invocation).await;
+        let never = "PRIVMSG #chan The server never said who you are, try again.";
+        assert_eq!(recorder.take(), vec![never, never, "WHOIS w"]);
+        let answer: Message = ":server 330 butler w ward :is logged in as"
+            .parse()
+            .unwrap();
+        admin.handle(&outbound, &answer).await;
+        assert_eq!(requests.try_recv().unwrap().action, AdminAction::Reload);
+        assert!(requests.try_recv().is_err());
+
+        // Reconnected before the answer came
+        admin.command(&outbound, &msg, &invocation).await;
+        assert_eq!(recorder.take(), vec!["WHOIS w"]);
+        let welcome: Message = ":server 001 butler :Welcome".parse().unwrap();
+        admin.handle(&outbound, &welcome).await;
+        admin.command(&outbound, &msg, &invocation).await;
+        assert_eq!(recorder.take(), vec!["WHOIS w"]);
+    }
+
+    #[test]
+    fn whois_numerics() {
+        let msg: Message = ":server 330 butler ward wardaccount :is logged in as"
+            .parse()
+            .unwrap();
+        let (code, args) = numeric(&msg.command).unwrap();
+        assert_eq!(code, RPL_WHOISACCOUNT);
+        assert_eq!(args[2], "wardaccount");
+        let msg: Message = ":server 376 butler :End of MOTD".parse().unwrap();
+        assert_eq!(numeric(&msg.command).map(|(code, _)| code), Some(376));
+    }
+}
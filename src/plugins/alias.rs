@@ -1,15 +1,21 @@
 use irc::client::prelude::*;
 use regex::Regex;
 
+/// An alias can rewrite a message into one that triggers another alias, but only this many times.
+const MAX_ALIAS_CHAIN: usize = 10;
+
 /// Special plugin that gets used before messages are sent to the other plugins. Lets you rewrite
 /// the input to match something else.
+///
+/// Aliases chain: after a rewrite the result is matched against the aliases again. Every alias
+/// fires at most once per message, so aliases that refer to each other cannot loop forever.
 #[derive(Debug)]
-struct AliasPlugin {
+pub struct AliasPlugin {
     replacements: Vec<(Regex, String)>,
 }
 
 impl AliasPlugin {
-    fn new(config: &super::config::Config) -> Self {
+    pub fn new(config: &super::config::Config) -> Self {
         let mut replacements = vec![];
         if let Some(aliases) = &config.alias {
             for (needle, repl) in aliases {
@@ -19,38 +25,65 @@ impl AliasPlugin {
                 }
             }
         }
+        // The config is a HashMap, sort so it is predictable which alias wins when several match
+        replacements.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
         AliasPlugin { replacements }
     }
 
-    fn rewrite(&self, msg: Message) -> Message {
-        log::debug!("{:#?}", msg);
+    pub fn rewrite(&self, msg: Message) -> Message {
         match msg.command {
             Command::PRIVMSG(ref msgtarget, ref messagetext) => {
-                for (needle, repl) in &self.replacements {
-                    log::debug!("{}", needle.is_match(messagetext));
-                    if needle.is_match(messagetext) {
-                        let replaced = needle.replace(messagetext, repl);
-                        return Message {
-                            tags: msg.tags,
-                            prefix: msg.prefix,
-                            command: Command::PRIVMSG(msgtarget.to_string(), replaced.to_string()),
-                        };
+                if let Some(replaced) = self.rewrite_text(messagetext) {
+                    Message {
+                        tags: msg.tags,
+                        prefix: msg.prefix,
+                        command: Command::PRIVMSG(msgtarget.to_string(), replaced),
                     }
+                } else {
+                    // No replacement
+                    msg
                 }
-
-                // No replacement
-                msg
             }
             _ => msg,
         }
     }
+
+    /// Keeps applying aliases until none match anymore. None if no alias matched at all.
+    fn rewrite_text(&self, text: &str) -> Option<String> {
+        let mut fired = vec![false; self.replacements.len()];
+        let mut current = text.to_owned();
+        for _ in 0..MAX_ALIAS_CHAIN {
+            let next = self
+                .replacements
+                .iter()
+                .enumerate()
+                .find(|(idx, (needle, _))| !fired[*idx] && needle.is_match(&current));
+            match next {
+                Some((idx, (needle, repl))) => {
+                    fired[idx] = true;
+                    let replaced = needle.replace(&current, repl.as_str()).to_string();
+                    log::debug!(
+                        "Alias '{}' rewrote '{}' to '{}'",
+                        needle.as_str(),
+                        current,
+                        replaced
+                    );
+                    current = replaced;
+                }
+                None => break,
+            }
+        }
+        if fired.contains(&true) {
+            Some(current)
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::config::Config;
-    use super::super::config::LeagueRankingConfig;
-    use super::super::config::SimpleReplyConfig;
     // Also includes what I `use`d above
     use super::*;
     use std::collections::HashMap;
@@ -64,17 +97,8 @@ mod tests {
             "^!cl( .*)?$".to_string(),
             "!games --country Champions League$1".to_string(),
         );
-        let config = Config {
-            league_ranking: LeagueRankingConfig {
-                leagues: HashMap::new(),
-                competitions: HashMap::new(),
-            },
-            simple_reply: SimpleReplyConfig {
-                replies: HashMap::new(),
-            },
-            strava: None,
-            alias: Some(alias),
-        };
+        let mut config: Config = toml::from_str("").unwrap();
+        config.alias = Some(alias);
 
         let plug = AliasPlugin::new(&config);
 
@@ -91,8 +115,40 @@ mod tests {
             let replmsg = plug.rewrite(msg);
             match replmsg.command {
                 Command::PRIVMSG(_, replmsg) => assert_eq!(replmsg, replaced),
-                _ => assert!(false),
+                _ => panic!("Rewrite should keep it a PRIVMSG"),
             }
         }
     }
+
+    fn alias_plugin(aliases: &[(&str, &str)]) -> AliasPlugin {
+        let alias = aliases
+            .iter()
+            .map(|(needle, repl)| (needle.to_string(), repl.to_string()))
+            .collect();
+        let mut config: Config = toml::from_str("").unwrap();
+        config.alias = Some(alias);
+        AliasPlugin::new(&config)
+    }
+
+    #[test]
+    fn chained_aliases() {
+        let plug = alias_plugin(&[
+            ("^!g( .*)?$", "!games$1"),
+            ("^!games genk$", "!games --country Belgium genk"),
+        ]);
+        assert_eq!(
+            plug.rewrite_text("!g genk").unwrap(),
+            "!games --country Belgium genk"
+        );
+        assert_eq!(plug.rewrite_text("!g"), Some("!games".to_string()));
+        assert_eq!(plug.rewrite_text("!games anderlecht"), None);
+    }
+
+    #[test]
+    fn recursive_aliases_terminate() {
+        let plug = alias_plugin(&[("^!a$", "!b"), ("^!b$", "!a")]);
+        assert_eq!(plug.rewrite_text("!a"), Some("!a".to_string()));
+        let plug = alias_plugin(&[("^!loop(.*)$", "!loop more$1")]);
+        assert_eq!(plug.rewrite_text("!loop"), Some("!loop more".to_string()));
+    }
 }
@@ -0,0 +1,167 @@
+//! How plugins tell each other things without knowing about each other. Events are plain structs,
+//! defined in here so nobody has to depend on another plugin's types. Whoever cares about one
+//! subscribes to its type, whoever knows something publishes it. A plugin can also `provide` a
+//! service (a trait object, like `Seen`) that others look up when they need it.
+//!
+//! Everything runs on the one thread the plugins run on (see `dispatch`). Subscribers are called
+//! right away, from within `publish`, so keep them short: update some state and get out.
+//!
+//! Plugins are built in some order, so look services up when you use them, not when you are built.
+
+use chrono::{DateTime, Utc};
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Someone said or did something where we could see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserActivity {
+    pub nick: String,
+    /// None for things like QUIT and NICK that do not happen in one channel, and for private
+    /// messages.
+    pub channel: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+/// A game the games plugin knows about changed score or status, say because it kicked off or
+/// ended. Games that show up or disappear do not count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameUpdate {
+    pub country: String,
+    pub competition: String,
+    pub home_team: String,
+    pub away_team: String,
+    pub home_score: Option<u32>,
+    pub away_score: Option<u32>,
+    /// Like "ended", or the minute for games that are on
+    pub status: String,
+}
+
+/// The strava plugin fetched the leaderboard again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderboardUpdate {
+    pub club: String,
+    /// By distance, with the IRC nick instead of the name for those who linked theirs
+    pub athletes: Vec<String>,
+}
+
+/// Provided by the seen plugin.
+pub trait Seen {
+    /// When we last saw `nick` do anything.
+    fn last_seen(&self, nick: &str) -> Option<DateTime<Utc>>;
+}
+
+type Subscriber = Rc<dyn Fn(&dyn Any)>;
+
+#[derive(Default)]
+struct Inner {
+    subscribers: HashMap<TypeId, Vec<Subscriber>>,
+    /// Each an `Rc<S>`, by the `TypeId` of `S`
+    services: HashMap<TypeId, Box<dyn Any>>,
+}
+
+/// Cheap to clone, every clone is the same bus.
+#[derive(Clone, Default)]
+pub struct Bus {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands the event to everyone subscribed to its type.
+    pub fn publish<E: 'static>(&self, event: E) {
+        // Subscribers are free to subscribe or publish themselves
+        let subscribers = self
+            .inner
+            .borrow()
+            .subscribers
+            .get(&TypeId::of::<E>())
+            .cloned()
+            .unwrap_or_default();
+        for subscriber in subscribers {
+            subscriber(&event);
+        }
+    }
+
+    /// Calls `handler` with every `E` published from now on.
+    pub fn subscribe<E: 'static>(&self, handler: impl Fn(&E) + 'static) {
+        let subscriber: Subscriber = Rc::new(move |event: &dyn Any| {
+            if let Some(event) = event.downcast_ref::<E>() {
+                handler(event);
+            }
+        });
+        self.inner
+            .borrow_mut()
+            .subscribers
+            .entry(TypeId::of::<E>())
+            .or_default()
+            .push(subscriber);
+    }
+
+    /// Makes `service` available as `S`, usually a trait: `bus.provide::<dyn Seen>(...)`. Replaces
+    /// whatever provided `S` before.
+    pub fn provide<S: ?Sized + 'static>(&self, service: Rc<S>) {
+        self.inner
+            .borrow_mut()
+            .services
+            .insert(TypeId::of::<S>(), Box::new(service));
+    }
+
+    /// None if nobody provides `S`, say because that plugin is disabled.
+    pub fn service<S: ?Sized + 'static>(&self) -> Option<Rc<S>> {
+        self.inner
+            .borrow()
+            .services
+            .get(&TypeId::of::<S>())
+            .and_then(|service| service.downcast_ref::<Rc<S>>())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_and_subscribe() {
+        let bus = Bus::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        {
+            let seen = Rc::clone(&seen);
+            bus.subscribe(move |activity: &UserActivity| {
+                seen.borrow_mut().push(activity.nick.clone());
+            });
+        }
+        // Nobody listens for these
+        bus.publish(String::from("ignored"));
+        let at = "2026-06-20T18:00:00Z".parse().unwrap();
+        bus.publish(UserActivity {
+            nick: String::from("ward"),
+            channel: Some(String::from("#running")),
+            at,
+        });
+        assert_eq!(*seen.borrow(), vec!["ward"]);
+    }
+
+    struct Always(DateTime<Utc>);
+
+    impl Seen for Always {
+        fn last_seen(&self, _nick: &str) -> Option<DateTime<Utc>> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn services() {
+        let bus = Bus::new();
+        assert!(bus.service::<dyn Seen>().is_none());
+        let at = "2026-06-20T18:00:00Z".parse().unwrap();
+        bus.provide::<dyn Seen>(Rc::new(Always(at)));
+        let seen = bus.clone().service::<dyn Seen>().unwrap();
+        assert_eq!(seen.last_seen("ward"), Some(at));
+    }
+}
@@ -0,0 +1,463 @@
+//! Keeps what plugins fetched from upstream, so not every command means another request. Every
+//! source (clubelo, strava, ...) gets a `Cache` with its own times, which `[cache.<source>]` in
+//! plugins.toml can change:
+//!
+//! - `ttl_secs`: how long a value is good. Until then it is used as is.
+//! - `stale_secs`: how much longer it is good enough. It still gets used, while a new one is
+//!   fetched in the background.
+//! - `error_secs`: after a failed fetch, how long to leave the source alone. Whatever we had
+//!   before (if anything) gets used in the meantime.
+//!
+//! Past `ttl_secs` + `stale_secs` the value is only used if fetching a new one fails.
+//!
+//! Sources that are slow or asked for a lot also get fetched in the background every
+//! `refresh_secs` (0 turns that off). Their commands then always answer right away with the
+//! latest we have, however old, and only wait when there is nothing at all yet.
+//!
+//! How often a cache got used and how that went is in `stats`, `!updated` says how old the data
+//! of each source is.
+
+use super::clock;
+use super::metrics;
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use irc::client::prelude::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::future::Future;
+use std::rc::{Rc, Weak};
+use std::sync::Mutex;
+
+static STATS: Mutex<BTreeMap<String, Stats>> = Mutex::new(BTreeMap::new());
+
+/// Overrides for the times a plugin picked for a source. Anything not given keeps the plugin's.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TtlConfig {
+    pub ttl_secs: Option<u64>,
+    pub stale_secs: Option<u64>,
+    pub error_secs: Option<u64>,
+    pub refresh_secs: Option<u64>,
+}
+
+/// How long a value is fresh, then how much longer stale, and how long a failure is remembered.
+/// With `refresh`, a new value is fetched that often whether anyone asks or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ttl {
+    pub fresh: Duration,
+    pub stale: Duration,
+    pub error: Duration,
+    pub refresh: Option<Duration>,
+}
+
+impl Ttl {
+    fn configured(self, config: Option<&TtlConfig>) -> Self {
+        let config = match config {
+            Some(config) => config,
+            None => return self,
+        };
+        let secs = |secs: Option<u64>, default: Duration| {
+            secs.map(|secs| Duration::seconds(secs as i64))
+                .unwrap_or(default)
+        };
+        Self {
+            fresh: secs(config.ttl_secs, self.fresh),
+            stale: secs(config.stale_secs, self.stale),
+            error: secs(config.error_secs, self.error),
+            refresh: match config.refresh_secs {
+                Some(0) => None,
+                Some(secs) => Some(Duration::seconds(secs as i64)),
+                None => self.refresh,
+            },
+        }
+    }
+}
+
+/// What happened to the lookups of one source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// When the last successful fetch was done
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Answered with a fresh value
+    pub hits: u64,
+    /// Answered with a stale value, while refreshing in the background
+    pub stale: u64,
+    /// Not fetched because the last attempt failed not long ago
+    pub negative: u64,
+    /// Had to wait for a fetch
+    pub misses: u64,
+    /// Fetches that failed, in the background or not
+    pub errors: u64,
+}
+
+/// The stats of every cache, by source.
+pub fn stats() -> BTreeMap<String, Stats> {
+    STATS.lock().map(|stats| stats.clone()).unwrap_or_default()
+}
+
+fn count(source: &str, f: impl FnOnce(&mut Stats)) {
+    if let Ok(mut stats) = STATS.lock() {
+        f(stats.entry(source.to_owned()).or_default());
+    }
+}
+
+struct Entry<T> {
+    value: Option<Rc<T>>,
+    fetched_at: Option<DateTime<Utc>>,
+    failed_at: Option<DateTime<Utc>>,
+    refreshing: bool,
+    /// Someone else keeps this up to date, see `refresh_in_background`
+    background: bool,
+}
+
+impl<T> Entry<T> {
+    fn store<E: Display>(&mut self, source: &str, result: Result<T, E>) {
+        match result {
+            Ok(value) => {
+                let now = clock::now();
+                self.value = Some(Rc::new(value));
+                self.fetched_at = Some(now);
+                self.failed_at = None;
+                count(source, |stats| stats.fetched_at = Some(now));
+                metrics::fetched(source, true);
+            }
+            Err(e) => {
+                log::warn!("Fetching {} failed: {}", source, e);
+                count(source, |stats| stats.errors += 1);
+                metrics::fetched(source, false);
+                self.failed_at = Some(clock::now());
+            }
+        }
+    }
+}
+
+/// One value from one source. Clones share it.
+#[derive(Clone)]
+pub struct Cache<T> {
+    source: String,
+    ttl: Ttl,
+    entry: Rc<RefCell<Entry<T>>>,
+}
+
+impl<T> std::fmt::Debug for Cache<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("source", &self.source)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: 'static> Cache<T> {
+    /// `ttl` is what the plugin thinks is right for `source`, plugins.toml gets the last word.
+    pub fn new(source: &str, ttl: Ttl, config: &HashMap<String, TtlConfig>) -> Self {
+        count(source, |_| {});
+        Self {
+            source: source.to_owned(),
+            ttl: ttl.configured(config.get(source)),
+            entry: Rc::new(RefCell::new(Entry {
+                value: None,
+                fetched_at: None,
+                failed_at: None,
+                refreshing: false,
+                background: false,
+            })),
+        }
+    }
+
+    /// Fetches right away and then every `refresh` from the `Ttl`, until the cache is dropped.
+    /// Nothing happens without a `refresh`. Needs to be called from within a `LocalSet`.
+    pub fn refresh_in_background<F, E>(&self, fetch: impl Fn() -> F + 'static)
+    where
+        F: Future<Output = Result<T, E>> + 'static,
+        E: Display,
+    {
+        let interval = match self.ttl.refresh.and_then(|refresh| refresh.to_std().ok()) {
+            Some(interval) => interval,
+            None => return,
+        };
+        self.entry.borrow_mut().background = true;
+        let entry: Weak<RefCell<Entry<T>>> = Rc::downgrade(&self.entry);
+        let source = self.source.clone();
+        tokio::task::spawn_local(async move {
+            loop {
+                let result = fetch().await;
+                match entry.upgrade() {
+                    Some(entry) => entry.borrow_mut().store(&source, result),
+                    None => break,
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    /// The value, calling `fetch` for a new one when needed. Only waits for that if what we have
+    /// is too old, stale values are refreshed in the background. None if there never was a
+    /// value.
+    pub async fn get<F, E>(&self, fetch: impl FnOnce() -> F) -> Option<Rc<T>>
+    where
+        F: Future<Output = Result<T, E>> + 'static,
+        E: Display,
+    {
+        let now = clock::now();
+        let (value, age) = {
+            let entry = self.entry.borrow();
+            if entry.background && entry.value.is_some() {
+                count(&self.source, |stats| stats.hits += 1);
+                return entry.value.clone();
+            }
+            if entry
+                .failed_at
+                .is_some_and(|failed_at| now - failed_at < self.ttl.error)
+            {
+                count(&self.source, |stats| stats.negative += 1);
+                return entry.value.clone();
+            }
+            (entry.value.clone(), entry.fetched_at.map(|at| now - at))
+        };
+        match (value, age) {
+            (Some(value), Some(age)) if age < self.ttl.fresh => {
+                count(&self.source, |stats| stats.hits += 1);
+                Some(value)
+            }
+            (Some(value), Some(age)) if age < self.ttl.fresh + self.ttl.stale => {
+                count(&self.source, |stats| stats.stale += 1);
+                if !self.entry.borrow().refreshing {
+                    self.entry.borrow_mut().refreshing = true;
+                    let fetching = fetch();
+                    let entry = self.entry.clone();
+                    let source = self.source.clone();
+                    tokio::task::spawn_local(async move {
+                        let result = fetching.await;
+                        let mut entry = entry.borrow_mut();
+                        entry.store(&source, result);
+                        entry.refreshing = false;
+                    });
+                }
+                Some(value)
+            }
+            _ => {
+                count(&self.source, |stats| stats.misses += 1);
+                let result = fetch().await;
+                let mut entry = self.entry.borrow_mut();
+                entry.store(&self.source, result);
+                entry.value.clone()
+            }
+        }
+    }
+}
+
+/// `!updated`, how old the data of each source is.
+#[derive(Default)]
+pub struct CachePlugin;
+
+impl CachePlugin {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+fn updated(stats: &BTreeMap<String, Stats>, source: Option<&str>) -> String {
+    let age = |stats: &Stats| match stats.fetched_at {
+        Some(fetched_at) => format!("{} ago", ago(clock::now() - fetched_at)),
+        None => String::from("never fetched"),
+    };
+    match source {
+        Some(source) => match stats.get(&source.to_lowercase()) {
+            Some(source_stats) => format!("{}: {}", source, age(source_stats)),
+            None => format!(
+                "There is no source {}. Try one of {}.",
+                source,
+                stats.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        },
+        None if stats.is_empty() => String::from("Nothing gets fetched."),
+        None => stats
+            .iter()
+            .map(|(source, stats)| format!("{}: {}", source, age(stats)))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+/// Short and rounded down, like 2h13m.
+fn ago(age: Duration) -> String {
+    let minutes = age.num_minutes();
+    if minutes < 1 {
+        String::from("<1m")
+    } else if minutes < 60 {
+        format!("{}m", minutes)
+    } else if minutes < 24 * 60 {
+        format!("{}h{:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}d{}h", minutes / (24 * 60), minutes / 60 % 24)
+    }
+}
+
+#[async_trait(?Send)]
+impl super::Plugin for CachePlugin {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "updated",
+            "How old the data from SOURCE is (or from all of them) that the other plugins answer with.",
+        )
+        .arg(Arg::word("SOURCE").optional())]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        outbound.reply(msg, &updated(&stats(), invocation.get("SOURCE")));
+    }
+}
+
+impl super::help::Help for CachePlugin {
+    fn name(&self) -> String {
+        String::from("cache")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn ttl() -> Ttl {
+        Ttl {
+            fresh: Duration::minutes(1),
+            stale: Duration::minutes(10),
+            error: Duration::minutes(5),
+            refresh: None,
+        }
+    }
+
+    #[test]
+    fn configured() {
+        let config = HashMap::from([(
+            String::from("test-configured"),
+            TtlConfig {
+                ttl_secs: Some(5),
+                ..Default::default()
+            },
+        )]);
+        let cache: Cache<u32> = Cache::new("test-configured", ttl(), &config);
+        assert_eq!(cache.ttl.fresh, Duration::seconds(5));
+        assert_eq!(cache.ttl.stale, ttl().stale);
+        let cache: Cache<u32> = Cache::new("test-other", ttl(), &config);
+        assert_eq!(cache.ttl, ttl());
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(ago(Duration::seconds(59)), "<1m");
+        assert_eq!(ago(Duration::minutes(5)), "5m");
+        assert_eq!(ago(Duration::minutes(133)), "2h13m");
+        assert_eq!(ago(Duration::hours(50)), "2d2h");
+
+        let now: DateTime<Utc> = "2026-06-20T18:00:00Z".parse().unwrap();
+        clock::freeze(now);
+        let stats = BTreeMap::from([
+            (
+                String::from("clubelo"),
+                Stats {
+                    fetched_at: Some(now - Duration::minutes(90)),
+                    ..Default::default()
+                },
+            ),
+            (String::from("games"), Stats::default()),
+        ]);
+        assert_eq!(
+            updated(&stats, None),
+            "clubelo: 1h30m ago, games: never fetched"
+        );
+        assert_eq!(updated(&stats, Some("ClubElo")), "ClubElo: 1h30m ago");
+        assert_eq!(
+            updated(&stats, Some("elo")),
+            "There is no source elo. Try one of clubelo, games."
+        );
+    }
+
+    #[tokio::test]
+    async fn background() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let ttl = Ttl {
+                    refresh: Some(Duration::hours(1)),
+                    ..ttl()
+                };
+                let cache: Cache<u32> = Cache::new("test-background", ttl, &HashMap::new());
+                let fetches = Rc::new(Cell::new(0));
+                let counted = fetches.clone();
+                cache.refresh_in_background(move || {
+                    let fetches = counted.clone();
+                    async move {
+                        fetches.set(fetches.get() + 1);
+                        Ok::<_, &str>(fetches.get())
+                    }
+                });
+                tokio::task::yield_now().await;
+                assert_eq!(fetches.get(), 1);
+                // Always the latest, never fetching on its own
+                let fetch = || async { Err("should not be called") };
+                assert_eq!(cache.get(fetch).await.as_deref(), Some(&1));
+                clock::freeze(clock::now() + Duration::days(2));
+                assert_eq!(cache.get(fetch).await.as_deref(), Some(&1));
+                assert_eq!(fetches.get(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn fresh_stale_and_failing() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let start: DateTime<Utc> = "2026-06-20T18:00:00Z".parse().unwrap();
+                clock::freeze(start);
+                let cache: Cache<u32> = Cache::new("test-cache", ttl(), &HashMap::new());
+                let fetches = Rc::new(Cell::new(0));
+                let fetch = |result: Result<u32, &'static str>| {
+                    let fetches = fetches.clone();
+                    move || async move {
+                        fetches.set(fetches.get() + 1);
+                        result
+                    }
+                };
+
+                // Nothing yet, so we wait for it
+                assert_eq!(cache.get(fetch(Ok(1))).await.as_deref(), Some(&1));
+                assert_eq!(cache.get(fetch(Ok(2))).await.as_deref(), Some(&1));
+                assert_eq!(fetches.get(), 1);
+
+                // Stale: the old value now, the new one once the background fetch is done
+                clock::freeze(start + Duration::minutes(2));
+                assert_eq!(cache.get(fetch(Ok(3))).await.as_deref(), Some(&1));
+                tokio::task::yield_now().await;
+                assert_eq!(cache.get(fetch(Ok(4))).await.as_deref(), Some(&3));
+                assert_eq!(fetches.get(), 2);
+
+                // Too old, and the fetch fails: keep the old value and leave the source alone
+                clock::freeze(start + Duration::hours(1));
+                assert_eq!(cache.get(fetch(Err("down"))).await.as_deref(), Some(&3));
+                assert_eq!(cache.get(fetch(Ok(5))).await.as_deref(), Some(&3));
+                assert_eq!(fetches.get(), 3);
+                clock::freeze(start + Duration::hours(2));
+                assert_eq!(cache.get(fetch(Ok(6))).await.as_deref(), Some(&6));
+
+                assert_eq!(
+                    stats()["test-cache"],
+                    Stats {
+                        hits: 2,
+                        stale: 1,
+                        negative: 1,
+                        misses: 3,
+                        errors: 1,
+                        fetched_at: Some(start + Duration::hours(2)),
+                    }
+                );
+            })
+            .await;
+    }
+}
@@ -1,8 +1,10 @@
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use async_trait::async_trait;
 use irc::client::prelude::*;
 use regex::Regex;
 use std::fmt;
 use std::str::FromStr;
-use unicode_segmentation::UnicodeSegmentation;
 
 pub struct CalcHandler {
     ctx: rink_core::Context,
@@ -16,44 +18,19 @@ impl CalcHandler {
         let mut ctx = rink_core::simple_context().expect("Could not create calculator core?");
         ctx.short_output = true;
         let shortcuts = vec![
-            CalcShortcut {
-                regex: Regex::new(r"!km +(\d.*)$").unwrap(),
-                target_unit: "kilometre".to_owned(),
-                default_unit: "miles".to_owned(),
-            },
-            CalcShortcut {
-                regex: Regex::new(r"!mi(?:le)? +(\d.*)$").unwrap(),
-                target_unit: "miles".to_owned(),
-                default_unit: "kilometer".to_owned(),
-            },
-            CalcShortcut {
-                regex: Regex::new(r"^!c +(-?\d.*)$").unwrap(),
-                target_unit: "celsius".to_owned(),
-                default_unit: "fahrenheit".to_owned(),
-            },
-            CalcShortcut {
-                regex: Regex::new(r"^!f +(-?\d.*)$").unwrap(),
-                target_unit: "fahrenheit".to_owned(),
-                default_unit: "celsius".to_owned(),
-            },
-            CalcShortcut {
-                regex: Regex::new(r"^!kg +(-?\d.*)$").unwrap(),
-                target_unit: "kilogram".to_owned(),
-                default_unit: "lbs".to_owned(),
-            },
-            CalcShortcut {
-                regex: Regex::new(r"^!(?:lbs?|pound) +(-?\d.*)$").unwrap(),
-                target_unit: "lbs".to_owned(),
-                default_unit: "kilogram".to_owned(),
-            },
+            CalcShortcut::new(&["km"], "kilometre", "miles"),
+            CalcShortcut::new(&["mi", "mile"], "miles", "kilometer"),
+            CalcShortcut::new(&["c"], "celsius", "fahrenheit"),
+            CalcShortcut::new(&["f"], "fahrenheit", "celsius"),
+            CalcShortcut::new(&["kg"], "kilogram", "lbs"),
+            CalcShortcut::new(&["lbs", "lb", "pound"], "lbs", "kilogram"),
         ];
 
-        let feet_to_cm_matcher = Regex::new(r"^!cm +(\d+)\D+([0-9.]+)").unwrap();
-        let cm_to_feet_matcher =
-            Regex::new(r"^!(?:f(?:ee|oo)?t|in(?:ch|ches)?) +([0-9.]+) *(?:cm)?$").unwrap();
+        let feet_to_cm_matcher = Regex::new(r"^(\d+)\D+([0-9.]+)").unwrap();
+        let cm_to_feet_matcher = Regex::new(r"^([0-9.]+) *(?:cm)?$").unwrap();
 
         // !grade <distance> <elevation>
-        let grade_matcher = Regex::new(r"^(?i)!grade +(?P<distance>[0-9.]+) *(?P<distanceunit>[a-z]+)? +(?P<elevation>[0-9.]+) *(?P<elevationunit>[a-z]+)?$").unwrap();
+        let grade_matcher = Regex::new(r"^(?i)(?P<distance>[0-9.]+) *(?P<distanceunit>[a-z]+)? +(?P<elevation>[0-9.]+) *(?P<elevationunit>[a-z]+)?$").unwrap();
 
         CalcHandler {
             ctx,
@@ -63,59 +40,46 @@ impl CalcHandler {
             grade_matcher,
         }
     }
-    fn match_calc(msg: &str) -> bool {
-        let first_six: String = msg.graphemes(true).take(6).collect();
-        first_six.eq_ignore_ascii_case("!calc ")
-    }
-
-    fn get_calc_input(msg: &str) -> String {
-        let input: String = msg.graphemes(true).skip(6).collect();
-        input.trim().to_owned()
-    }
 
     fn eval(&mut self, line: &str) -> Result<String, String> {
         rink_core::one_line(&mut self.ctx, line)
     }
 
-    /// Checks incoming message to see whether it uses a calculation shortcut. If so, return
+    /// Checks whether the command is a calculation shortcut. If so, return
     /// Some(stringtoevaluate). Otherwise None
-    fn handle_shortcut(&self, msg: &str) -> Option<String> {
-        for shortcut in &self.shortcuts {
-            // TODO Should this be part of a CalcShortcut method?
-            if let Some(captures) = shortcut.regex.captures(msg) {
-                if let Some(input) = captures.get(1) {
-                    let input = input.as_str().trim();
-                    if input
-                        .chars()
-                        .last()
-                        .expect("Cannot be empty because of regex match")
-                        .is_ascii_digit()
-                    {
-                        return Some(format!(
-                            "{} {} to {}",
-                            input, shortcut.default_unit, shortcut.target_unit
-                        ));
-                    } else {
-                        return Some(format!("{} to {}", input, shortcut.target_unit));
-                    }
-                }
-            }
+    fn handle_shortcut(&self, command: &str, input: &str) -> Option<String> {
+        let shortcut = self
+            .shortcuts
+            .iter()
+            .find(|shortcut| shortcut.names[0] == command)?;
+        let input = input.trim();
+        if !input
+            .trim_start_matches('-')
+            .starts_with(|c: char| c.is_ascii_digit())
+        {
+            return None;
+        }
+        if input
+            .chars()
+            .last()
+            .expect("Cannot be empty, starts with a digit")
+            .is_ascii_digit()
+        {
+            Some(format!(
+                "{} {} to {}",
+                input, shortcut.default_unit, shortcut.target_unit
+            ))
+        } else {
+            Some(format!("{} to {}", input, shortcut.target_unit))
         }
-        None
     }
 
-    /// Checks incoming message for a !pace calculation.
+    /// Handles a !pace calculation.
     /// The input is some sort of time representation.
     /// We provide a conversion of t/km to t/mile and vice versa.
-    fn handle_pace(&self, msg: &str) -> Option<String> {
-        let first_six: String = msg.graphemes(true).take(6).collect();
-        if !first_six.eq_ignore_ascii_case("!pace ") {
-            return None;
-        }
-        let input: String = msg.graphemes(true).skip(6).collect();
-        let input = input.trim();
+    fn handle_pace(&self, input: &str) -> Option<String> {
         // TODO: Log failure to parse
-        if let Ok(pace) = input.parse::<Pace>() {
+        if let Ok(pace) = input.trim().parse::<Pace>() {
             Some(format!(
                 "{orig}/km = {miles}/mile || {orig}/mile = {km}/km",
                 orig = pace,
@@ -127,8 +91,8 @@ impl CalcHandler {
         }
     }
 
-    fn handle_grade(&mut self, msg: &str) -> Option<String> {
-        if let Some(captures) = self.grade_matcher.captures(msg) {
+    fn handle_grade(&mut self, input: &str) -> Option<String> {
+        if let Some(captures) = self.grade_matcher.captures(input) {
             // Parsing input
             let distance: Result<f64, _> = captures.name("distance").unwrap().as_str().parse();
             let elevation: Result<f64, _> = captures.name("elevation").unwrap().as_str().parse();
@@ -198,8 +162,8 @@ impl CalcHandler {
         None
     }
 
-    fn handle_feet_to_cm(&self, msg: &str) -> Option<String> {
-        if let Some(captures) = self.feet_to_cm_matcher.captures(msg) {
+    fn handle_feet_to_cm(&self, input: &str) -> Option<String> {
+        if let Some(captures) = self.feet_to_cm_matcher.captures(input) {
             if let Some(feet) = captures.get(1) {
                 if let Some(inches) = captures.get(2) {
                     return Some(format!(
@@ -213,8 +177,8 @@ impl CalcHandler {
         None
     }
 
-    fn handle_cm_to_feet(&self, msg: &str) -> Option<String> {
-        if let Some(captures) = self.cm_to_feet_matcher.captures(msg) {
+    fn handle_cm_to_feet(&self, input: &str) -> Option<String> {
+        if let Some(captures) = self.cm_to_feet_matcher.captures(input) {
             if let Some(cm) = captures.get(1) {
                 if let Ok(cm) = cm.as_str().parse::<f64>() {
                     let feet = (cm * 0.032_808).floor();
@@ -232,47 +196,95 @@ impl Default for CalcHandler {
     }
 }
 
-impl super::MutableHandler for CalcHandler {
-    fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if CalcHandler::match_calc(message) {
-                match self.eval(&CalcHandler::get_calc_input(message)) {
-                    Ok(res) => client.send_privmsg(&channel, &res).unwrap(),
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        client
-                            .send_privmsg(&channel, "I had some trouble with that :(")
-                            .unwrap()
-                    }
-                }
-            }
+#[async_trait(?Send)]
+impl super::Plugin for CalcHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        let mut commands =
+            vec![CommandSpec::new("calc", "Performs given CALCULATION")
+                .arg(Arg::text("CALCULATION"))];
+        for shortcut in &self.shortcuts {
+            commands.push(shortcut.command());
+        }
+        commands.push(
+            CommandSpec::new("cm", "Convert feet and inches to centimetre")
+                .arg(Arg::text("FEET'INCHES")),
+        );
+        commands.push(
+            CommandSpec::new("ft", "Convert centimetre to feet and inches")
+                .alias("feet")
+                .alias("foot")
+                .alias("in")
+                .alias("inch")
+                .alias("inches")
+                .arg(Arg::text("CM")),
+        );
+        commands.push(
+            CommandSpec::new(
+                "pace",
+                "Converts pace per km to pace per mile and vice versa",
+            )
+            .arg(Arg::text("MM:SS")),
+        );
+        commands.push(
+            CommandSpec::new(
+                "grade",
+                "Calculates the grade of a climb. Units default to km and meter.",
+            )
+            .arg(Arg::text("DISTANCE ELEVATION")),
+        );
+        commands
+    }
 
-            // TODO Integrate with the above...
-            if let Some(ref to_eval) = self.handle_shortcut(message) {
-                match self.eval(to_eval) {
-                    Ok(result) => client.send_privmsg(&channel, &result).unwrap(),
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        client
-                            .send_privmsg(&channel, "I had some trouble with that :(")
-                            .unwrap()
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        match invocation.command.as_str() {
+            "calc" => match self.eval(invocation.get("CALCULATION").unwrap_or_default()) {
+                Ok(res) => outbound.reply(msg, &res),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    outbound.reply(msg, "I had some trouble with that :(")
+                }
+            },
+            "cm" => {
+                let input = invocation.get("FEET'INCHES").unwrap_or_default();
+                if let Some(ref to_eval) = self.handle_feet_to_cm(input) {
+                    match self.eval(to_eval) {
+                        Ok(result) => outbound.reply(msg, &result),
+                        Err(e) => eprintln!("{}", e),
                     }
                 }
             }
-            if let Some(ref to_eval) = self.handle_feet_to_cm(message) {
-                match self.eval(to_eval) {
-                    Ok(result) => client.send_privmsg(&channel, &result).unwrap(),
-                    Err(e) => eprintln!("{}", e),
+            "ft" => {
+                if let Some(ref cm_to_feet) =
+                    self.handle_cm_to_feet(invocation.get("CM").unwrap_or_default())
+                {
+                    outbound.reply(msg, cm_to_feet);
                 }
             }
-            if let Some(ref paceresult) = self.handle_pace(message) {
-                client.send_privmsg(&channel, paceresult).unwrap();
+            "pace" => {
+                if let Some(ref paceresult) =
+                    self.handle_pace(invocation.get("MM:SS").unwrap_or_default())
+                {
+                    outbound.reply(msg, paceresult);
+                }
             }
-            if let Some(ref cm_to_feet) = self.handle_cm_to_feet(message) {
-                client.send_privmsg(&channel, cm_to_feet).unwrap();
+            "grade" => {
+                if let Some(ref grade) =
+                    self.handle_grade(invocation.get("DISTANCE ELEVATION").unwrap_or_default())
+                {
+                    outbound.reply(msg, grade);
+                }
             }
-            if let Some(ref grade) = self.handle_grade(message) {
-                client.send_privmsg(&channel, grade).unwrap();
+            shortcut => {
+                let input = invocation.get("NUMBER").unwrap_or_default();
+                if let Some(ref to_eval) = self.handle_shortcut(shortcut, input) {
+                    match self.eval(to_eval) {
+                        Ok(result) => outbound.reply(msg, &result),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            outbound.reply(msg, "I had some trouble with that :(")
+                        }
+                    }
+                }
             }
         }
     }
@@ -285,27 +297,6 @@ impl super::help::Help for CalcHandler {
 
     fn help(&self) -> Vec<super::help::HelpEntry> {
         let result = vec![
-            super::help::HelpEntry::new("!calc CALCULATION", "Performs given CALCULATION"),
-            super::help::HelpEntry::new(
-                "!c NUMBER / !f NUMBER",
-                "Convert fahrenheit to celsius and vice versa",
-            ),
-            super::help::HelpEntry::new(
-                "!km NUMBER / !mi NUMBER",
-                "Convert miles to kilometre and vice versa",
-            ),
-            super::help::HelpEntry::new(
-                "!kg NUMBER / !lbs NUMBER",
-                "Convert pound to kilogram and vice versa",
-            ),
-            super::help::HelpEntry::new(
-                "!cm NUMBER'NUMBER / !ft NUMBER",
-                "Convert feet and inches to centimetre and vice versa",
-            ),
-            super::help::HelpEntry::new(
-                "!pace NUMBER:NUMBER",
-                "Converts pace per km to pace per mile and vice versa",
-            ),
             super::help::HelpEntry::new(
                 "!calc NUMBER UNIT to UNIT",
                 "Converts number. No spaces in UNIT. 'to UNIT' optional. See https://github.com/tiffany352/rink-rs/blob/master/core/definitions.units for all units.",
@@ -318,16 +309,34 @@ impl super::help::Help for CalcHandler {
 /// !km 26 or !c 100 or !mi 10000 metre. To do so, we make the following assumptions about these
 /// shortcuts:
 ///
-/// - The `regex` is used to match user input. First capture of it is the number (+ optional unit)
-/// input.
+/// - The `names` are the command and its aliases. The argument is the number (+ optional unit)
+///   input, which has to start with a number.
 /// - The `target_unit` is the second part inclusion in a `"{} to {}"` format string. The first
-/// parameter is the input.
+///   parameter is the input.
 /// - The `default_unit` is there in case the user did not provide a unit.
 struct CalcShortcut {
-    regex: Regex,
+    names: Vec<String>,
     target_unit: String,
     default_unit: String,
 }
+impl CalcShortcut {
+    fn new(names: &[&str], target_unit: &str, default_unit: &str) -> Self {
+        CalcShortcut {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            target_unit: target_unit.to_owned(),
+            default_unit: default_unit.to_owned(),
+        }
+    }
+
+    fn command(&self) -> CommandSpec {
+        let description = format!("Convert {} to {}", self.default_unit, self.target_unit);
+        let mut command = CommandSpec::new(&self.names[0], &description);
+        for alias in &self.names[1..] {
+            command = command.alias(alias);
+        }
+        command.arg(Arg::text("NUMBER"))
+    }
+}
 
 struct Pace {
     secs: u32,
@@ -364,7 +373,7 @@ impl FromStr for Pace {
             Err(_e) => {
                 // Split by non numbers and assume first and second are
                 // the numbers representing mins and seconds
-                let mut parts = s.split(|c: char| !c.is_digit(10)).take(2);
+                let mut parts = s.split(|c: char| !c.is_ascii_digit()).take(2);
                 // This cannot be the best way to do this...
                 // Can't use ? for my error type without rust nightly,
                 // which I am trying to avoid.
@@ -390,20 +399,32 @@ struct PaceParseError {}
 
 #[cfg(test)]
 mod tests {
+    use super::super::router::Router;
+    use super::super::Plugin;
     use super::*;
 
+    fn router(calc: &CalcHandler) -> Router {
+        let mut router = Router::new();
+        router.add(0, "calc", calc.commands());
+        router
+    }
+
     #[test]
     fn calc_matches() {
-        assert!(CalcHandler::match_calc("!calc 5+5"));
-        assert!(CalcHandler::match_calc("!CALC 5+5"));
-        assert!(CalcHandler::match_calc("!cAlc 5+5"));
-        assert!(!CalcHandler::match_calc("!colc 5+5"));
-        assert!(!CalcHandler::match_calc("!calca 5+5"));
+        let router = router(&CalcHandler::new());
+        for msg in ["!calc 5+5", "!CALC 5+5", "!cAlc 5+5"] {
+            let (_, invocation) = router.route(msg).unwrap();
+            assert_eq!(invocation.unwrap().command, "calc");
+        }
+        assert!(router.route("!colc 5+5").is_none());
+        assert!(router.route("!calca 5+5").is_none());
     }
 
     #[test]
     fn calc_input() {
-        assert_eq!(CalcHandler::get_calc_input("!calc 5+5"), "5+5");
+        let router = router(&CalcHandler::new());
+        let (_, invocation) = router.route("!calc 5+5").unwrap();
+        assert_eq!(invocation.unwrap().get("CALCULATION"), Some("5+5"));
     }
 
     #[test]
@@ -430,9 +451,14 @@ mod tests {
         let calc = CalcHandler::new();
 
         assert_eq!(
-            calc.handle_shortcut("!km 26"),
+            calc.handle_shortcut("km", "26"),
             Some("26 miles to kilometre".to_owned())
         );
+        assert_eq!(
+            calc.handle_shortcut("f", "-40"),
+            Some("-40 celsius to fahrenheit".to_owned())
+        );
+        assert_eq!(calc.handle_shortcut("mi", "far"), None);
     }
 
     #[test]
@@ -440,22 +466,23 @@ mod tests {
         let calc = CalcHandler::new();
 
         assert_eq!(
-            calc.handle_cm_to_feet("!feet 188"),
+            calc.handle_cm_to_feet("188"),
             Some("6 ft 2.016 in".to_owned())
         );
     }
 
     #[test]
     fn unicode_line() {
-        CalcHandler::match_calc("🤓🤓🤓🤓");
         let calc = CalcHandler::new();
+        assert!(router(&calc).route("🤓🤓🤓🤓").is_none());
         calc.handle_pace("🤓🤓🤓🤓");
+        calc.handle_shortcut("c", "🤓🤓🤓🤓");
     }
 
     #[test]
     fn pace_conversion() {
         let calc = CalcHandler::new();
-        let res = calc.handle_pace("!pace 5:00");
+        let res = calc.handle_pace("5:00");
         assert_eq!(
             res,
             Some("5:00/km = 8:02/mile || 5:00/mile = 3:06/km".to_owned())
@@ -479,6 +506,7 @@ mod tests {
         ];
         for (input, output) in input_output {
             println!("{}", input);
+            let input = input.strip_prefix("!grade ").unwrap();
             let res = calc.handle_grade(input);
             assert_eq!(res, Some(output.to_owned()));
         }
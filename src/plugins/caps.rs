@@ -0,0 +1,292 @@
+//! IRCv3 capabilities. While we register we ask the server which ones it has (`CAP LS 302`) and
+//! request the ones in `WANTED` that it offers. Those put tags on messages: when a line was really
+//! said (`server-time`, matters for lines a bouncer plays back), which account someone is logged in
+//! to (`account-tag`, `extended-join`) and which batch a line belongs to (`batch`).
+//!
+//! Plugins read those through `Context::new(msg)`, whether or not the server sent the tags. Without
+//! them the time is now and there is no account.
+//!
+//! Like `sasl`, nothing in here sends anything itself. When SASL is configured it is the one that
+//! ends negotiation, our request goes out before it starts authenticating.
+
+use super::clock;
+use chrono::prelude::{DateTime, Utc};
+use irc::client::prelude::*;
+use irc::proto::{CapSubCommand, Tag};
+use std::collections::BTreeSet;
+
+/// What we ask for, if the server has it.
+pub const WANTED: &[&str] = &[
+    "server-time",
+    "account-tag",
+    "extended-join",
+    "message-tags",
+    "batch",
+];
+
+/// Negotiation for one connection, make a new one for every connection.
+pub struct Caps {
+    /// Whether SASL ends negotiation instead of us
+    sasl: bool,
+    /// What the server offered so far, `CAP LS` can take several lines
+    offered: Vec<String>,
+    /// Requests we have not had an answer to
+    waiting: usize,
+    enabled: BTreeSet<String>,
+}
+
+impl Caps {
+    pub fn new(sasl: bool) -> Self {
+        Self {
+            sasl,
+            offered: vec![],
+            waiting: 0,
+            enabled: BTreeSet::new(),
+        }
+    }
+
+    /// To send before NICK and USER.
+    pub fn start(&mut self) -> Vec<Command> {
+        vec![Command::CAP(
+            None,
+            CapSubCommand::LS,
+            Some(String::from("302")),
+            None,
+        )]
+    }
+
+    /// Needs to see every incoming message.
+    pub fn handle(&mut self, msg: &Message) -> Vec<Command> {
+        let (subcommand, code, caps) = match msg.command {
+            Command::CAP(_, ref subcommand, ref code, ref caps) => (subcommand, code, caps),
+            _ => return vec![],
+        };
+        match subcommand {
+            // `CAP * LS * :some caps` means more are coming
+            CapSubCommand::LS if code.as_deref() == Some("*") && caps.is_some() => {
+                self.offered.extend(names(caps));
+                vec![]
+            }
+            CapSubCommand::LS => {
+                self.offered.extend(names(code).chain(names(caps)));
+                let mut commands = self.request(&self.offered.clone());
+                if self.waiting == 0 && !self.sasl {
+                    commands.push(cap_end());
+                }
+                commands
+            }
+            // Only with cap-notify, which 302 turns on
+            CapSubCommand::NEW => {
+                let offered: Vec<String> = names(code).chain(names(caps)).collect();
+                self.request(&offered)
+            }
+            CapSubCommand::DEL => {
+                for cap in names(code).chain(names(caps)) {
+                    self.enabled.remove(&cap);
+                }
+                vec![]
+            }
+            CapSubCommand::ACK | CapSubCommand::NAK => {
+                let ours: Vec<String> = names(code)
+                    .chain(names(caps))
+                    .filter(|cap| WANTED.contains(&cap.as_str()))
+                    .collect();
+                if ours.is_empty() {
+                    // The answer to SASL's request
+                    return vec![];
+                }
+                if *subcommand == CapSubCommand::ACK {
+                    log::info!("Enabled capabilities: {}", ours.join(", "));
+                    self.enabled.extend(ours);
+                } else {
+                    log::warn!("Server refused capabilities: {}", ours.join(", "));
+                }
+                self.waiting = self.waiting.saturating_sub(1);
+                if self.waiting == 0 && !self.sasl {
+                    vec![cap_end()]
+                } else {
+                    vec![]
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The ones the server agreed to.
+    pub fn enabled(&self) -> impl Iterator<Item = &str> {
+        self.enabled.iter().map(String::as_str)
+    }
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    /// A REQ for what we want out of `offered` and do not have yet.
+    fn request(&mut self, offered: &[String]) -> Vec<Command> {
+        let wanted: Vec<&str> = WANTED
+            .iter()
+            .copied()
+            .filter(|cap| offered.iter().any(|offer| offer == cap))
+            .filter(|cap| !self.enabled.contains(*cap))
+            .collect();
+        if wanted.is_empty() {
+            return vec![];
+        }
+        self.waiting += 1;
+        vec![Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            None,
+            Some(wanted.join(" ")),
+        )]
+    }
+}
+
+/// Capability names in a list, without values (`sasl=PLAIN,EXTERNAL`) or modifiers (`-batch`).
+fn names(list: &Option<String>) -> impl Iterator<Item = String> + '_ {
+    list.iter()
+        .flat_map(|list| list.split_whitespace())
+        .map(|cap| {
+            let cap = cap.trim_start_matches(['-', '~', '=']);
+            cap.split('=').next().unwrap_or(cap).to_lowercase()
+        })
+}
+
+fn cap_end() -> Command {
+    Command::CAP(None, CapSubCommand::END, None, None)
+}
+
+/// The value of a tag, None if the message does not have it (or it has no value).
+pub fn tag<'a>(msg: &'a Message, name: &str) -> Option<&'a str> {
+    msg.tags
+        .iter()
+        .flatten()
+        .find(|Tag(key, _)| key == name)
+        .and_then(|Tag(_, value)| value.as_deref())
+}
+
+/// What the tags say about a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    /// When it was said. Now, unless the server says otherwise.
+    pub time: DateTime<Utc>,
+    /// The account whoever sent it is logged in to.
+    pub account: Option<String>,
+    /// The batch it is part of, playback from a bouncer for example.
+    pub batch: Option<String>,
+}
+
+impl Context {
+    pub fn new(msg: &Message) -> Self {
+        let time = tag(msg, "time")
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(clock::now);
+        let account = match msg.command {
+            // extended-join: JOIN #channel account :Real Name
+            Command::JOIN(_, Some(ref account), Some(_)) => Some(account.as_str()),
+            _ => tag(msg, "account"),
+        }
+        // Not logged in
+        .filter(|account| *account != "*")
+        .map(str::to_owned);
+        Self {
+            time,
+            account,
+            batch: tag(msg, "batch").map(str::to_owned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn handle(caps: &mut Caps, line: &str) -> Vec<Command> {
+        let msg: Message = line.parse().unwrap();
+        caps.handle(&msg)
+    }
+
+    fn req(caps: &str) -> Command {
+        Command::CAP(None, CapSubCommand::REQ, None, Some(caps.to_owned()))
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut caps = Caps::new(false);
+        assert_eq!(caps.start().len(), 1);
+        assert!(handle(
+            &mut caps,
+            ":server CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL server-time"
+        )
+        .is_empty());
+        assert_eq!(
+            handle(&mut caps, ":server CAP * LS :account-tag batch away-notify"),
+            vec![req("server-time account-tag batch")]
+        );
+        assert_eq!(
+            handle(
+                &mut caps,
+                ":server CAP * ACK :server-time account-tag batch"
+            ),
+            vec![cap_end()]
+        );
+        assert_eq!(
+            caps.enabled().collect::<Vec<_>>(),
+            vec!["account-tag", "batch", "server-time"]
+        );
+        assert_eq!(
+            handle(&mut caps, ":server CAP butler NEW :extended-join"),
+            vec![req("extended-join")]
+        );
+        assert!(handle(&mut caps, ":server CAP butler DEL :batch").is_empty());
+        assert!(!caps.has("batch"));
+    }
+
+    #[test]
+    fn leaves_the_end_to_sasl() {
+        let mut caps = Caps::new(true);
+        assert_eq!(
+            handle(&mut caps, ":server CAP * LS :sasl message-tags"),
+            vec![req("message-tags")]
+        );
+        assert!(handle(&mut caps, ":server CAP * ACK :sasl").is_empty());
+        assert!(handle(&mut caps, ":server CAP * NAK :message-tags").is_empty());
+        assert_eq!(caps.enabled().count(), 0);
+
+        // Nothing to ask for
+        let mut caps = Caps::new(false);
+        assert_eq!(
+            handle(&mut caps, ":server CAP * LS :multi-prefix"),
+            vec![cap_end()]
+        );
+    }
+
+    #[test]
+    fn context() {
+        clock::freeze(Utc.with_ymd_and_hms(2026, 6, 20, 12, 0, 0).unwrap());
+        let msg: Message =
+            "@time=2026-06-19T21:04:05.123Z;account=wardaccount;batch=yXNAbvnRHTRBv :ward!w@host PRIVMSG #chan :hi"
+                .parse()
+                .unwrap();
+        let context = Context::new(&msg);
+        assert_eq!(
+            context.time,
+            Utc.with_ymd_and_hms(2026, 6, 19, 21, 4, 5).unwrap()
+                + chrono::Duration::milliseconds(123)
+        );
+        assert_eq!(context.account.as_deref(), Some("wardaccount"));
+        assert_eq!(context.batch.as_deref(), Some("yXNAbvnRHTRBv"));
+
+        let msg: Message = ":ward!w@host PRIVMSG #chan :hi".parse().unwrap();
+        let context = Context::new(&msg);
+        assert_eq!(context.time, clock::now());
+        assert_eq!(context.account, None);
+
+        let msg: Message = ":ward!w@host JOIN #chan wardaccount :Ward".parse().unwrap();
+        assert_eq!(Context::new(&msg).account.as_deref(), Some("wardaccount"));
+        let msg: Message = ":ward!w@host JOIN #chan * :Ward".parse().unwrap();
+        assert_eq!(Context::new(&msg).account, None);
+    }
+}
@@ -0,0 +1,190 @@
+//! What `--check-config` does. Reads bot.toml and plugins.toml the way the bot would, but instead
+//! of stopping at the first thing that is off it lists all of them. Keys that nothing reads are
+//! warnings, they are usually typos. Nothing gets connected or fetched.
+
+use super::config::{Config as PluginsConfig, PLUGINS};
+use irc::client::prelude::Config;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Checks both files. Their names go in front of every problem.
+pub fn check(bot_path: &str, plugins_path: &str) -> Report {
+    let mut report = Report::default();
+    match std::fs::read_to_string(bot_path) {
+        Ok(contents) => check_bot(&contents, &mut report.with_file(bot_path)),
+        Err(e) => report.errors.push(format!("{}: {}", bot_path, e)),
+    }
+    match std::fs::read_to_string(plugins_path) {
+        Ok(contents) => check_plugins(&contents, &mut report.with_file(plugins_path)),
+        Err(e) => report.errors.push(format!("{}: {}", plugins_path, e)),
+    }
+    report
+}
+
+/// The report, but everything added to it is about one file.
+struct FileReport<'a> {
+    report: &'a mut Report,
+    file: &'a str,
+}
+
+impl Report {
+    fn with_file<'a>(&'a mut self, file: &'a str) -> FileReport<'a> {
+        FileReport { report: self, file }
+    }
+}
+
+impl FileReport<'_> {
+    fn error(&mut self, problem: impl std::fmt::Display) {
+        self.report
+            .errors
+            .push(format!("{}: {}", self.file, problem));
+    }
+
+    fn warning(&mut self, problem: impl std::fmt::Display) {
+        self.report
+            .warnings
+            .push(format!("{}: {}", self.file, problem));
+    }
+}
+
+fn check_bot(contents: &str, report: &mut FileReport) {
+    // toml's errors name the key and the line, the irc crate's do not
+    let config: Config = match toml::from_str(contents) {
+        Ok(config) => config,
+        Err(e) => return report.error(e),
+    };
+    warn_unknown_keys(contents, &config, report);
+    if config.nickname().is_err() {
+        report.error("nickname is missing");
+    }
+    if config.server.is_none() {
+        report.error("server is missing");
+    }
+    if let Err(e) = super::sasl::SaslConfig::new(&config) {
+        report.error(e);
+    }
+}
+
+fn check_plugins(contents: &str, report: &mut FileReport) {
+    let config: PluginsConfig = match toml::from_str(contents) {
+        Ok(config) => config,
+        Err(e) => return report.error(e),
+    };
+    warn_unknown_keys(contents, &config, report);
+    let mut names: Vec<&String> = config.plugins.keys().collect();
+    names.sort();
+    for name in names {
+        if !PLUGINS.contains(&name.as_str()) {
+            report.warning(format!(
+                "there is no plugin called '{}', see [plugins.{}]",
+                name, name
+            ));
+        }
+    }
+    for problem in config.problems() {
+        report.error(problem);
+    }
+}
+
+/// Whatever is in the file but did not survive a round trip through the config struct was not
+/// read by anything.
+fn warn_unknown_keys<T: serde::Serialize>(contents: &str, config: &T, report: &mut FileReport) {
+    let (original, understood) = match (
+        toml::from_str::<toml::Value>(contents),
+        toml::Value::try_from(config),
+    ) {
+        (Ok(original), Ok(understood)) => (original, understood),
+        _ => return,
+    };
+    for key in unknown_keys(&original, &understood, "") {
+        report.warning(format!("unknown key '{}' is ignored", key));
+    }
+}
+
+fn unknown_keys(original: &toml::Value, understood: &toml::Value, path: &str) -> Vec<String> {
+    let (original, understood) = match (original.as_table(), understood.as_table()) {
+        (Some(original), Some(understood)) => (original, understood),
+        _ => return vec![],
+    };
+    let mut unknown = vec![];
+    for (key, value) in original {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        match understood.get(key) {
+            Some(understood_value) => {
+                unknown.extend(unknown_keys(value, understood_value, &key_path))
+            }
+            None => unknown.push(key_path),
+        }
+    }
+    unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_str(check: fn(&str, &mut FileReport), contents: &str) -> Report {
+        let mut report = Report::default();
+        check(contents, &mut report.with_file("test.toml"));
+        report
+    }
+
+    #[test]
+    fn sample_files() {
+        let report = check_str(check_bot, include_str!("../../bot.toml.sample"));
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.warnings.is_empty(), "{:?}", report);
+        let report = check_str(check_plugins, include_str!("../../plugins.toml.sample"));
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.warnings.is_empty(), "{:?}", report);
+    }
+
+    #[test]
+    fn field_errors() {
+        let report = check_str(check_plugins, "[plugins.strava]\nenabled = \"yes\"\n");
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("test.toml: "));
+        assert!(report.errors[0].contains("plugins.strava.enabled"));
+
+        let report = check_str(check_bot, "server = \"irc.example.org\"\n");
+        assert_eq!(report.errors, vec!["test.toml: nickname is missing"]);
+    }
+
+    #[test]
+    fn unknown_keys_warn() {
+        let report = check_str(
+            check_plugins,
+            r##"
+            colour = "blue"
+            [plugins.strava]
+            chanels = ["#running"]
+            [plugins.stravaa]
+            [ratelimit]
+            user_commands = 3
+            "##,
+        );
+        assert!(report.is_ok());
+        assert_eq!(
+            report.warnings,
+            vec![
+                "test.toml: unknown key 'colour' is ignored",
+                "test.toml: unknown key 'plugins.strava.chanels' is ignored",
+                "test.toml: there is no plugin called 'stravaa', see [plugins.stravaa]",
+            ]
+        );
+    }
+}
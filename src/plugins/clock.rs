@@ -0,0 +1,34 @@
+//! The time as far as plugins are concerned. Always the real time, unless a test stopped the
+//! clock with `freeze` so replies that mention the time stay the same from run to run.
+
+use chrono::{DateTime, Utc};
+use std::cell::Cell;
+
+thread_local! {
+    // Plugins all run on one thread, see `dispatch`
+    static FROZEN: Cell<Option<DateTime<Utc>>> = const { Cell::new(None) };
+}
+
+pub fn now() -> DateTime<Utc> {
+    FROZEN.with(|frozen| frozen.get()).unwrap_or_else(Utc::now)
+}
+
+/// From now on `now` gives `at` on this thread. For tests.
+pub fn freeze(at: DateTime<Utc>) {
+    FROZEN.with(|frozen| frozen.set(Some(at)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frozen() {
+        let before = now();
+        assert!(before <= Utc::now());
+        let at = "2026-06-20T18:00:00Z".parse().unwrap();
+        freeze(at);
+        assert_eq!(now(), at);
+        assert_eq!(now(), at);
+    }
+}
@@ -1,8 +1,48 @@
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug)]
+/// Every plugin main knows how to build, by the name used in the `[plugins]` sections.
+pub const PLUGINS: &[&str] = &[
+    "time",
+    "simple_reply",
+    "nickinternal",
+    "calc",
+    "seen",
+    "elo",
+    "league_ranking",
+    "strava",
+    "untappd",
+    "games",
+    "3rd",
+    "cache",
+    "admin",
+    "help",
+];
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
+    #[serde(default)]
+    pub plugins: HashMap<String, PluginConfig>,
+    #[serde(default)]
+    pub outbound: super::outbound::OutboundConfig,
+    #[serde(default)]
+    pub ratelimit: super::ratelimit::RateLimitConfig,
+    #[serde(default)]
+    pub quarantine: super::dispatch::QuarantineConfig,
+    #[serde(default)]
+    pub reconnect: super::connection::ReconnectConfig,
+    #[serde(default)]
+    pub storage: super::storage::StorageConfig,
+    #[serde(default)]
+    pub console: super::console::ConsoleConfig,
+    #[serde(default)]
+    pub http: super::http::HttpConfig,
+    #[serde(default)]
+    pub cache: HashMap<String, super::cache::TtlConfig>,
+    #[serde(default)]
+    pub metrics: super::metrics::MetricsConfig,
+    #[serde(default)]
     pub league_ranking: LeagueRankingConfig,
+    #[serde(default)]
     pub simple_reply: SimpleReplyConfig,
     pub strava: Option<StravaConfig>,
     pub alias: Option<HashMap<String, String>>,
@@ -11,9 +51,119 @@ pub struct Config {
 impl Config {
     /// Parses the plugins.toml file into configuration for our plugins.
     pub fn new() -> Self {
-        let contents =
-            std::fs::read_to_string("plugins.toml").expect("No 'plugins.toml' file found.");
-        toml::from_str(&contents).expect("Failed to parse 'plugins.toml'.")
+        Self::from_file("plugins.toml")
+            .unwrap_or_else(|e| panic!("Failed to load 'plugins.toml': {}", e))
+    }
+
+    /// Like `new`, but leaves it to the caller what to do when that fails.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Catches what parses fine but would not work.
+    pub fn validate(&self) -> Result<(), String> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    /// Everything `validate` complains about, one entry per problem, naming the field.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut aliases: Vec<&String> = self.alias.iter().flat_map(|a| a.keys()).collect();
+        aliases.sort();
+        for needle in aliases {
+            if let Err(e) = regex::Regex::new(needle) {
+                problems.push(format!("alias '{}' is not a valid regex: {}", needle, e));
+            }
+        }
+        for (section, leagues) in [
+            ("leagues", &self.league_ranking.leagues),
+            ("competitions", &self.league_ranking.competitions),
+        ] {
+            let mut names: Vec<&String> = leagues.keys().collect();
+            names.sort();
+            for name in names {
+                let url = &leagues[name].url;
+                if let Err(e) = reqwest::Url::parse(url) {
+                    problems.push(format!(
+                        "league_ranking.{}.{}.url '{}' is not a URL: {}",
+                        section, name, url, e
+                    ));
+                }
+            }
+        }
+        problems.extend(self.outbound.problems());
+        problems.extend(self.ratelimit.problems());
+        problems.extend(self.quarantine.problems());
+        problems
+    }
+
+    /// The plugins that have to be built again to pick up the new config. Changes to the
+    /// `[plugins]` settings themselves do not need that, see `Dispatcher::reconfigure`.
+    pub fn affected_plugins(&self, new: &Config) -> Vec<&'static str> {
+        let mut affected = vec![];
+        if self.simple_reply != new.simple_reply {
+            affected.push("simple_reply");
+        }
+        if self.league_ranking != new.league_ranking {
+            affected.push("league_ranking");
+        }
+        if self.strava != new.strava {
+            affected.push("strava");
+        }
+        if self.alias != new.alias {
+            affected.push("alias");
+        }
+        affected
+    }
+
+    /// The sections that changed but are only read at startup, so a reload does not apply them.
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.outbound != new.outbound {
+            changed.push("outbound");
+        }
+        if self.reconnect != new.reconnect {
+            changed.push("reconnect");
+        }
+        if self.storage != new.storage {
+            changed.push("storage");
+        }
+        if self.console != new.console {
+            changed.push("console");
+        }
+        if self.http != new.http {
+            changed.push("http");
+        }
+        if self.cache != new.cache {
+            changed.push("cache");
+        }
+        if self.metrics != new.metrics {
+            changed.push("metrics");
+        }
+        changed
+    }
+
+    /// Plugins that were off and are now on. They were never built, it takes a restart to do so.
+    pub fn newly_enabled(&self, new: &Config) -> Vec<&'static str> {
+        PLUGINS
+            .iter()
+            .copied()
+            .filter(|name| !self.plugin(name).enabled && new.plugin(name).enabled)
+            .collect()
+    }
+
+    /// Settings for the plugin with the given name (see `Help::name`). Plugins without a section
+    /// of their own are enabled everywhere.
+    pub fn plugin(&self, name: &str) -> PluginConfig {
+        self.plugins.get(name).cloned().unwrap_or_default()
     }
 }
 
@@ -25,29 +175,79 @@ impl Default for Config {
 
 // TODO How to keep the types for each plugin separate without creating circular dependencies?
 
-#[derive(Deserialize, Debug)]
+/// Decides whether a plugin gets built at all, and which channels it gets to see messages from.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PluginConfig {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// When given, the plugin only sees messages from these channels.
+    pub channels: Option<Vec<String>>,
+    /// The plugin never sees messages from these channels.
+    #[serde(default)]
+    pub exclude_channels: Vec<String>,
+    /// Where replies to channel messages go.
+    #[serde(default)]
+    pub reply: super::outbound::ReplyMode,
+    /// Only replies longer than this many lines get redirected, 0 redirects all of them.
+    #[serde(default)]
+    pub redirect_over_lines: usize,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            enabled: enabled_by_default(),
+            channels: None,
+            exclude_channels: vec![],
+            reply: Default::default(),
+            redirect_over_lines: 0,
+        }
+    }
+}
+
+impl PluginConfig {
+    /// Channel names are case insensitive on IRC, so are they here.
+    pub fn allows_channel(&self, channel: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|c| c.eq_ignore_ascii_case(channel));
+        if listed(&self.exclude_channels) {
+            return false;
+        }
+        match &self.channels {
+            Some(channels) => listed(channels),
+            None => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default)]
 pub struct SimpleReplyConfig {
     pub replies: HashMap<String, ReplyConfig>,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct ReplyConfig {
     pub triggers: Vec<String>,
     pub replies: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default)]
 pub struct LeagueRankingConfig {
     pub leagues: HashMap<String, LeagueConfig>,
     pub competitions: HashMap<String, LeagueConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct LeagueConfig {
     pub alias: Vec<String>,
     pub url: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct StravaConfig {
     pub cookies: String,
 }
@@ -57,3 +257,140 @@ pub struct StravaConfig {
 //     pub alias: Vec<String>,
 //     pub groups: HashMap<String, String>,
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::super::outbound::ReplyMode;
+    use super::*;
+
+    #[test]
+    fn parse_sample() {
+        let config: Config = toml::from_str(include_str!("../../plugins.toml.sample")).unwrap();
+        assert!(config.simple_reply.replies.contains_key("hello"));
+        assert_eq!(config.alias.as_ref().unwrap().len(), 4);
+        assert!(!config.plugin("untappd").enabled);
+        assert!(config.plugin("time").enabled);
+    }
+
+    #[test]
+    fn missing_sections() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.league_ranking.leagues.is_empty());
+        assert!(config.simple_reply.replies.is_empty());
+        assert!(config.plugin("games").enabled);
+    }
+
+    #[test]
+    fn affected_plugins() {
+        let old: Config = toml::from_str(include_str!("../../plugins.toml.sample")).unwrap();
+        let mut new: Config = toml::from_str(include_str!("../../plugins.toml.sample")).unwrap();
+        assert!(old.affected_plugins(&new).is_empty());
+        new.plugins.clear();
+        assert!(old.affected_plugins(&new).is_empty());
+        new.strava = Some(StravaConfig {
+            cookies: String::from("session=abc"),
+        });
+        new.alias.as_mut().unwrap().clear();
+        assert_eq!(old.affected_plugins(&new), vec!["strava", "alias"]);
+    }
+
+    #[test]
+    fn restart_needed() {
+        let old: Config = toml::from_str(include_str!("../../plugins.toml.sample")).unwrap();
+        let mut new: Config = toml::from_str(include_str!("../../plugins.toml.sample")).unwrap();
+        assert!(old.restart_needed(&new).is_empty());
+        assert!(old.newly_enabled(&new).is_empty());
+        new.outbound.burst = 10;
+        new.http.timeout_secs = 5;
+        new.plugins.remove("untappd");
+        assert_eq!(old.restart_needed(&new), vec!["outbound", "http"]);
+        assert_eq!(old.newly_enabled(&new), vec!["untappd"]);
+        assert!(new.newly_enabled(&old).is_empty());
+    }
+
+    #[test]
+    fn invalid_alias() {
+        let config: Config = toml::from_str(
+            r#"
+            [alias]
+            "^!(unclosed" = "!games"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn every_problem() {
+        let config: Config = toml::from_str(
+            r#"
+            [league_ranking.leagues.jpl]
+            alias = ["jpl"]
+            url = "not a url"
+            [league_ranking]
+            competitions = {}
+            [alias]
+            "^!(unclosed" = "!games"
+            "^!fine" = "!games"
+            "[" = "!calc"
+            "#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[2].starts_with("league_ranking.leagues.jpl.url"));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let config: Config = toml::from_str(
+            r#"
+            [outbound]
+            lines_per_second = 0
+            [ratelimit]
+            user_commands = 0
+            channel_commands = 0
+            [quarantine]
+            panics = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.problems(),
+            vec![
+                "outbound.lines_per_second is 0, it has to be more than 0",
+                "ratelimit.user_commands is 0, nobody could use any command",
+                "ratelimit.channel_commands is 0, nobody could use any command",
+                "quarantine.panics is 0, it has to be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn plugin_channels() {
+        let config: Config = toml::from_str(
+            r###"
+            [plugins.strava]
+            channels = ["##running"]
+            [plugins.games]
+            exclude_channels = ["#quiet"]
+            [plugins.calc]
+            enabled = false
+            [plugins.help]
+            reply = "notice"
+            "###,
+        )
+        .unwrap();
+        let strava = config.plugin("strava");
+        assert!(strava.enabled);
+        assert!(strava.allows_channel("##running"));
+        assert!(strava.allows_channel("##RUNNING"));
+        assert!(!strava.allows_channel("#quiet"));
+        let games = config.plugin("games");
+        assert!(games.allows_channel("##running"));
+        assert!(!games.allows_channel("#quiet"));
+        assert!(!config.plugin("calc").enabled);
+        assert_eq!(config.plugin("help").reply, ReplyMode::Notice);
+        assert_eq!(config.plugin("games").reply, ReplyMode::Channel);
+    }
+}
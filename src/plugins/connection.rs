@@ -0,0 +1,161 @@
+//! Bits that keep us connected. When the connection drops, main builds a whole new client after
+//! a delay that grows with every failed attempt. Plugins, the dispatcher and the outbound queue
+//! are not part of the connection, so they (and whatever they remember) carry on as if nothing
+//! happened.
+
+use irc::client::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// How long to wait before the first attempt to reconnect.
+    pub initial_delay_secs: u64,
+    /// The delay doubles with every failed attempt, up to this.
+    pub max_delay_secs: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_secs: 5,
+            max_delay_secs: 600,
+        }
+    }
+}
+
+/// Exponential backoff between connection attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        let initial = Duration::from_secs(config.initial_delay_secs);
+        Self {
+            initial,
+            max: Duration::from_secs(config.max_delay_secs).max(initial),
+            next: initial,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Call once we are properly connected again.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// The channels we are in, so we can get back in after a reconnect. The client only rejoins the
+/// channels from bot.toml by itself.
+#[derive(Default)]
+pub struct Channels {
+    /// By lowercased name
+    joined: HashMap<String, String>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Needs to see every incoming message, and what our nick is at that point.
+    pub fn observe(&mut self, own_nick: &str, msg: &Message) {
+        let from_us = msg
+            .source_nickname()
+            .is_some_and(|nick| nick.eq_ignore_ascii_case(own_nick));
+        match msg.command {
+            Command::JOIN(ref chanlist, _, _) if from_us => {
+                for channel in chanlist.split(',') {
+                    self.joined
+                        .insert(channel.to_lowercase(), channel.to_owned());
+                }
+            }
+            Command::PART(ref chanlist, _) if from_us => {
+                for channel in chanlist.split(',') {
+                    self.joined.remove(&channel.to_lowercase());
+                }
+            }
+            Command::KICK(ref channel, ref nick, _) if nick.eq_ignore_ascii_case(own_nick) => {
+                self.joined.remove(&channel.to_lowercase());
+            }
+            _ => {}
+        }
+    }
+
+    /// Channels we were in that the client will not rejoin by itself.
+    pub fn to_rejoin(&self, irc_config: &Config) -> Vec<String> {
+        let mut channels: Vec<String> = self
+            .joined
+            .iter()
+            .filter(|(lowercase, _)| {
+                !irc_config
+                    .channels()
+                    .iter()
+                    .any(|channel| channel.to_lowercase() == **lowercase)
+            })
+            .map(|(_, channel)| channel.clone())
+            .collect();
+        channels.sort();
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(&ReconnectConfig {
+            initial_delay_secs: 5,
+            max_delay_secs: 30,
+        });
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 30, 30]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay().as_secs(), 5);
+    }
+
+    #[test]
+    fn rejoin_channels() {
+        let irc_config = Config {
+            channels: vec![String::from("#Configured")],
+            ..Default::default()
+        };
+        let mut channels = Channels::new();
+        for line in [
+            ":butler!rusty@example.org JOIN #configured",
+            ":butler!rusty@example.org JOIN #extra,#other",
+            ":someone!else@example.org JOIN #elsewhere",
+            ":butler!rusty@example.org PART #other :bye",
+            ":op!op@example.org KICK #kicked butler :out",
+        ] {
+            let msg: Message = line.parse().unwrap();
+            channels.observe("butler", &msg);
+        }
+        let msg: Message = ":butler!rusty@example.org JOIN #kicked".parse().unwrap();
+        channels.observe("Butler", &msg);
+        assert_eq!(
+            channels.to_rejoin(&irc_config),
+            vec![String::from("#extra"), String::from("#kicked")]
+        );
+        let msg: Message = ":op!op@example.org KICK #kicked butler :out"
+            .parse()
+            .unwrap();
+        channels.observe("butler", &msg);
+        assert_eq!(
+            channels.to_rejoin(&irc_config),
+            vec![String::from("#extra")]
+        );
+    }
+}
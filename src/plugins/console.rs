@@ -0,0 +1,176 @@
+//! `--console`: try out plugins without an IRC server. Every line typed on stdin reaches the
+//! plugins as a PRIVMSG from `nick` in `channel` (see `[console]` in plugins.toml). Whatever the
+//! bot says back is printed, with the IRC bold, colours and such turned into ANSI escapes.
+
+use irc::client::prelude::*;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConsoleConfig {
+    /// Who we pretend to be.
+    pub nick: String,
+    /// Where we pretend to talk. Use the bot's nick to talk to it privately.
+    pub channel: String,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        Self {
+            nick: String::from("console"),
+            channel: String::from("#console"),
+        }
+    }
+}
+
+impl ConsoleConfig {
+    /// What a line typed on the console looks like to the plugins.
+    pub fn message(&self, line: &str) -> Message {
+        Message {
+            tags: None,
+            prefix: Some(Prefix::Nickname(
+                self.nick.clone(),
+                self.nick.clone(),
+                String::from("console"),
+            )),
+            command: Command::PRIVMSG(self.channel.clone(), line.to_owned()),
+        }
+    }
+}
+
+/// One line for the terminal, None for what is not worth showing.
+pub fn render(own_nick: &str, command: &Command) -> Option<String> {
+    match command {
+        Command::PRIVMSG(target, text) => {
+            Some(format!("[{}] <{}> {}", target, own_nick, to_ansi(text)))
+        }
+        Command::NOTICE(target, text) => {
+            Some(format!("[{}] -{}- {}", target, own_nick, to_ansi(text)))
+        }
+        // Admin commands
+        Command::JOIN(..) | Command::PART(..) | Command::QUIT(_) => Some(format!(
+            "*** {}",
+            Message::from(command.clone()).to_string().trim_end()
+        )),
+        _ => None,
+    }
+}
+
+/// Turns IRC formatting codes into ANSI escapes, ending with a reset if anything was turned on.
+pub fn to_ansi(text: &str) -> String {
+    let mut ansi = String::with_capacity(text.len());
+    let mut styled = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escape = match c {
+            '\x02' => String::from("\x1b[1m"),
+            '\x1d' => String::from("\x1b[3m"),
+            '\x1f' => String::from("\x1b[4m"),
+            '\x1e' => String::from("\x1b[9m"),
+            '\x16' => String::from("\x1b[7m"),
+            '\x0f' => String::from("\x1b[0m"),
+            '\x03' => {
+                let foreground = colour_number(&mut chars);
+                let background = match (foreground, chars.peek()) {
+                    (Some(_), Some(',')) => {
+                        // Only a comma followed by digits belongs to the colour
+                        let mut ahead = chars.clone();
+                        ahead.next();
+                        if ahead.peek().is_some_and(|c| c.is_ascii_digit()) {
+                            chars.next();
+                            colour_number(&mut chars)
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                match (foreground, background) {
+                    // A lone \x03 turns colours off
+                    (None, _) => String::from("\x1b[39;49m"),
+                    (Some(fg), None) => format!("\x1b[{}m", ansi_colour(fg, 30)),
+                    (Some(fg), Some(bg)) => {
+                        format!("\x1b[{};{}m", ansi_colour(fg, 30), ansi_colour(bg, 40))
+                    }
+                }
+            }
+            c => {
+                ansi.push(c);
+                continue;
+            }
+        };
+        styled = c != '\x0f';
+        ansi.push_str(&escape);
+    }
+    if styled {
+        ansi.push_str("\x1b[0m");
+    }
+    ansi
+}
+
+/// Up to two digits.
+fn colour_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u8> {
+    let mut number = None;
+    for _ in 0..2 {
+        match chars.peek().and_then(|c| c.to_digit(10)) {
+            Some(digit) => {
+                number = Some(number.unwrap_or(0) * 10 + digit as u8);
+                chars.next();
+            }
+            None => break,
+        }
+    }
+    number
+}
+
+/// The closest of the 16 ANSI colours. `base` is 30 for text, 40 for the background.
+fn ansi_colour(irc: u8, base: u8) -> u8 {
+    // White, black, navy, green, red, brown, purple, olive, yellow, light green, teal, cyan,
+    // blue, pink, gray, light gray
+    const ANSI: [u8; 16] = [67, 0, 4, 2, 1, 1, 5, 3, 63, 62, 6, 66, 64, 65, 60, 7];
+    // Anything past 15 is one of the extended colours, which we do not bother with
+    base + ANSI.get(irc as usize).copied().unwrap_or(9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::formatting::{IrcColour, IrcFormat};
+    use super::*;
+
+    #[test]
+    fn ansi() {
+        assert_eq!(to_ansi("plain"), "plain");
+        assert_eq!(
+            to_ansi(&format!("{}bold{} not", IrcFormat::Bold, IrcFormat::Normal)),
+            "\x1b[1mbold\x1b[0m not"
+        );
+        assert_eq!(
+            to_ansi(&format!(
+                "{}red{}",
+                IrcFormat::ForegroundColour(IrcColour::Red),
+                IrcFormat::Bold
+            )),
+            "\x1b[31mred\x1b[1m\x1b[0m"
+        );
+        assert_eq!(
+            to_ansi(&format!(
+                "{}x",
+                IrcFormat::BackgroundColour(IrcColour::White, IrcColour::Navy)
+            )),
+            "\x1b[97;44mx\x1b[0m"
+        );
+        // The comma is part of the text, not the colour
+        assert_eq!(to_ansi("\x034,x"), "\x1b[31m,x\x1b[0m");
+        assert_eq!(to_ansi("\x03 off"), "\x1b[39;49m off\x1b[0m");
+    }
+
+    #[test]
+    fn console_lines() {
+        let config = ConsoleConfig::default();
+        let msg = config.message("!calc 1+1");
+        assert_eq!(msg.source_nickname(), Some("console"));
+        assert_eq!(msg.response_target(), Some("#console"));
+        let reply = Command::PRIVMSG(String::from("#console"), String::from("2"));
+        assert_eq!(render("butler", &reply).unwrap(), "[#console] <butler> 2");
+        assert_eq!(render("butler", &Command::PING(String::new(), None)), None);
+    }
+}
@@ -0,0 +1,675 @@
+//! Hands incoming messages to the plugins. Every plugin gets its own task and its own queue of
+//! messages, so a plugin that is stuck waiting on clubelo or Wikipedia does not stop `!time` (or
+//! the connection itself) from being handled.
+//!
+//! Commands are looked up in the `router` first. Only the plugin owning the command gets it as an
+//! `Invocation`, all plugins still see the plain message. Commands over the `ratelimit` are not
+//! handed to anyone.
+//!
+//! Plugins are not required to be `Send`, so all these tasks live on a
+//! `tokio::task::LocalSet`. They are still run concurrently, just on one thread.
+//!
+//! A plugin that panics only loses the message it was working on. It is logged, with the message,
+//! and the plugin carries on with the next one. One that keeps panicking (see `[quarantine]` in
+//! plugins.toml) is quarantined: it gets no more messages until an admin enables it again.
+//!
+//! Jobs a plugin scheduled (see `scheduler`) go through the same queue, so they never run at the
+//! same time as the plugin handles a message. The same goes for them: skipped while disabled or
+//! quarantined, and a panic counts towards the quarantine.
+
+use super::config::{Config, PluginConfig};
+use super::help::{HelpEntry, HelpHandler};
+use super::metrics;
+use super::outbound::{reply_target, Outbound};
+use super::ratelimit::{RateLimiter, Verdict};
+use super::router::{Invocation, Router, UsageError};
+use super::scheduler::Scheduler;
+use super::Plugin;
+use futures::FutureExt;
+use irc::client::prelude::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuarantineConfig {
+    /// A plugin that panics this many times...
+    pub panics: usize,
+    /// ...within this many seconds is quarantined.
+    pub window_secs: u64,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            panics: 3,
+            window_secs: 600,
+        }
+    }
+}
+
+impl QuarantineConfig {
+    /// For `Config::problems`.
+    pub fn problems(&self) -> Vec<String> {
+        if self.panics == 0 {
+            vec![String::from(
+                "quarantine.panics is 0, it has to be at least 1",
+            )]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// How a plugin has been doing. Shared between the dispatcher and the plugin's task.
+#[derive(Debug, Default)]
+struct Health {
+    config: QuarantineConfig,
+    /// When it panicked, the ones in the window only
+    panics: VecDeque<Instant>,
+    quarantined: bool,
+    /// As in the plugin's config. Scheduled jobs are not dispatched, they check here.
+    enabled: bool,
+}
+
+impl Health {
+    /// Remembers a panic. True if that was one too many and the plugin is now quarantined.
+    fn panicked(&mut self, now: Instant) -> bool {
+        let window = Duration::from_secs(self.config.window_secs);
+        self.panics.push_back(now);
+        while let Some(&first) = self.panics.front() {
+            if now.duration_since(first) < window {
+                break;
+            }
+            self.panics.pop_front();
+        }
+        if !self.quarantined && self.panics.len() >= self.config.panics.max(1) {
+            self.quarantined = true;
+            return true;
+        }
+        false
+    }
+}
+
+struct Incoming {
+    msg: Rc<Message>,
+    invocation: Option<Invocation>,
+    /// Comes along with every message, so new reply settings apply right away
+    outbound: Outbound,
+}
+
+/// What goes in a plugin's queue.
+enum Job {
+    Handle(Incoming),
+    /// Says when everything before it is handled, see `Dispatcher::flush`
+    Flush(oneshot::Sender<()>),
+    /// One of the plugin's scheduled jobs, by name
+    Due(String),
+}
+
+struct Queue {
+    name: String,
+    config: PluginConfig,
+    /// Knows how this plugin wants to reply. Shared with the plugin's task, so its jobs reply
+    /// the way the config says now, not how it said when the plugin started.
+    outbound: Rc<RefCell<Outbound>>,
+    queue: mpsc::UnboundedSender<Job>,
+    health: Rc<RefCell<Health>>,
+    /// What the help plugin says about it
+    help: Vec<HelpEntry>,
+}
+
+pub struct Dispatcher {
+    outbound: Outbound,
+    queues: Vec<Queue>,
+    router: Router,
+    limiter: RateLimiter,
+    quarantine: QuarantineConfig,
+}
+
+impl Dispatcher {
+    pub fn new(outbound: Outbound, limiter: RateLimiter, quarantine: QuarantineConfig) -> Self {
+        Self {
+            outbound,
+            queues: vec![],
+            router: Router::new(),
+            limiter,
+            quarantine,
+        }
+    }
+
+    /// Starts a task for the plugin that handles messages in the order they arrive. Messages in
+    /// channels the config does not allow are not passed on.
+    ///
+    /// Has to be called from within a `LocalSet`.
+    pub fn add(&mut self, plugin: Box<dyn Plugin>, config: PluginConfig) {
+        let name = plugin.name();
+        self.router.add(self.queues.len(), &name, plugin.commands());
+        let help = HelpHandler::entries(&*plugin);
+        let outbound = Rc::new(RefCell::new(self.outbound.for_plugin(&config)));
+        let health = Rc::new(RefCell::new(Health {
+            config: self.quarantine.clone(),
+            enabled: config.enabled,
+            ..Default::default()
+        }));
+        self.queues.push(Queue {
+            name,
+            config,
+            queue: spawn(plugin, Rc::clone(&health), Rc::clone(&outbound)),
+            outbound,
+            health,
+            help,
+        });
+    }
+
+    /// Swaps a running plugin for a new one with the same name, e.g. after its config changed.
+    /// The old one still gets to finish what is already in its queue. False (and nothing
+    /// happens) if no plugin by that name is running.
+    pub fn replace(&mut self, plugin: Box<dyn Plugin>) -> bool {
+        let name = plugin.name();
+        let position = match self.queues.iter().position(|queue| queue.name == name) {
+            Some(position) => position,
+            None => return false,
+        };
+        self.router.replace(position, &name, plugin.commands());
+        let queue = &mut self.queues[position];
+        queue.help = HelpHandler::entries(&*plugin);
+        queue.queue = spawn(plugin, Rc::clone(&queue.health), Rc::clone(&queue.outbound));
+        true
+    }
+
+    /// A help plugin that knows about the plugins running now, to replace the one that was built
+    /// with the others.
+    pub fn help(&self) -> HelpHandler {
+        let mut help = HelpHandler::new();
+        for queue in &self.queues {
+            if queue.name != "help" {
+                help.add_entries(&queue.name, queue.help.clone());
+            }
+        }
+        help
+    }
+
+    /// Whether a plugin by that name was built and added.
+    pub fn has(&self, name: &str) -> bool {
+        self.queues.iter().any(|queue| queue.name == name)
+    }
+
+    /// False if there is no such plugin. Plugins that were not enabled at startup never got
+    /// built, so there is nothing to enable. Enabling also lifts a quarantine.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.queues.iter_mut().find(|queue| queue.name == name) {
+            Some(queue) => {
+                queue.config.enabled = enabled;
+                let mut health = queue.health.borrow_mut();
+                health.enabled = enabled;
+                if enabled {
+                    health.quarantined = false;
+                    health.panics.clear();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes over new settings for the plugins we have (channels, replies, enabled or not) and
+    /// new rate limits. Rate limits start over from zero.
+    pub fn reconfigure(&mut self, config: &Config) {
+        for queue in self.queues.iter_mut() {
+            queue.config = config.plugin(&queue.name);
+            *queue.outbound.borrow_mut() = self.outbound.for_plugin(&queue.config);
+            let mut health = queue.health.borrow_mut();
+            health.config = config.quarantine.clone();
+            health.enabled = queue.config.enabled;
+        }
+        self.limiter = RateLimiter::new(config.ratelimit.clone());
+        self.quarantine = config.quarantine.clone();
+    }
+
+    /// Queues the message for every plugin allowed to see it. Does not wait for any of them to
+    /// handle it.
+    pub fn dispatch(&mut self, msg: Message) {
+        metrics::received();
+        let channel = channel(&msg).map(|c| c.to_owned());
+        let allowed = |queue: &Queue| {
+            queue.config.enabled
+                && !queue.health.borrow().quarantined
+                && match channel {
+                    Some(ref channel) => queue.config.allows_channel(channel),
+                    None => true,
+                }
+        };
+        let routed = match self.route(&msg) {
+            Some((owner, result)) if allowed(&self.queues[owner]) => {
+                if self.rate_limited(&msg, owner, &result) {
+                    None
+                } else {
+                    Some((owner, result))
+                }
+            }
+            _ => None,
+        };
+        if let Some((owner, Err(ref usage_error))) = routed {
+            let owner = &self.queues[owner];
+            if allowed(owner) {
+                owner
+                    .outbound
+                    .borrow()
+                    .reply(&msg, &usage_error.to_string());
+            }
+        }
+        let msg = Rc::new(msg);
+        for (position, queue) in self.queues.iter().enumerate() {
+            if !allowed(queue) {
+                continue;
+            }
+            let invocation = match routed {
+                Some((owner, Ok(ref invocation))) if owner == position => Some(invocation.clone()),
+                _ => None,
+            };
+            let incoming = Incoming {
+                msg: Rc::clone(&msg),
+                invocation,
+                outbound: queue.outbound.borrow().clone(),
+            };
+            if queue.queue.send(Job::Handle(incoming)).is_err() {
+                log::error!("Plugin {} is no longer handling messages", queue.name);
+            }
+        }
+    }
+
+    /// Waits until every plugin has handled everything dispatched before.
+    pub async fn flush(&self) {
+        let mut done = vec![];
+        for queue in &self.queues {
+            let (flushed, flushed_rx) = oneshot::channel();
+            if queue.queue.send(Job::Flush(flushed)).is_ok() {
+                done.push(flushed_rx);
+            }
+        }
+        for flushed in done {
+            // An error only means the plugin's task is gone, nothing to wait for then
+            let _ = flushed.await;
+        }
+    }
+
+    fn route(&self, msg: &Message) -> Option<(usize, Result<Invocation, UsageError>)> {
+        match msg.command {
+            Command::PRIVMSG(_, ref text) => self.router.route(text),
+            _ => None,
+        }
+    }
+
+    /// Whether the command should be dropped. Tells the user to slow down if the limiter says so.
+    fn rate_limited(
+        &mut self,
+        msg: &Message,
+        owner: usize,
+        routed: &Result<Invocation, UsageError>,
+    ) -> bool {
+        let command = match routed {
+            Ok(invocation) => &invocation.command,
+            Err(usage_error) => usage_error.command(),
+        };
+        let (user, place) = match (user(msg), reply_target(msg)) {
+            (Some(user), Some(place)) => (user, place),
+            _ => return false,
+        };
+        match self.limiter.check(Instant::now(), user, place, command) {
+            Verdict::Allowed => false,
+            Verdict::Limited => true,
+            Verdict::Warn(wait) => {
+                let warning = format!("Slow down a bit, try again in {}s.", wait.as_secs().max(1));
+                self.queues[owner].outbound.borrow().reply(msg, &warning);
+                true
+            }
+        }
+    }
+}
+
+/// Starts the task that hands the plugin its messages, in the order they arrive, and lets the
+/// plugin schedule its jobs.
+fn spawn(
+    mut plugin: Box<dyn Plugin>,
+    health: Rc<RefCell<Health>>,
+    outbound: Rc<RefCell<Outbound>>,
+) -> mpsc::UnboundedSender<Job> {
+    let (queue, mut jobs) = mpsc::unbounded_channel::<Job>();
+    plugin.schedule(scheduler(queue.downgrade(), Rc::clone(&health)));
+    tokio::task::spawn_local(async move {
+        while let Some(job) = jobs.recv().await {
+            match job {
+                Job::Flush(flushed) => {
+                    let _ = flushed.send(());
+                }
+                // Messages queued before a quarantine started are dropped too
+                _ if health.borrow().quarantined => {}
+                Job::Handle(Incoming {
+                    msg,
+                    invocation,
+                    outbound,
+                }) => {
+                    let started = Instant::now();
+                    let handled = AssertUnwindSafe(async {
+                        if let Some(ref invocation) = invocation {
+                            plugin.command(&outbound, &msg, invocation).await;
+                        }
+                        plugin.handle(&outbound, &msg).await;
+                    })
+                    .catch_unwind()
+                    .await;
+                    let command = invocation.as_ref().map(|invocation| &*invocation.command);
+                    metrics::handled(&plugin.name(), command, started.elapsed());
+                    if let Err(panic) = handled {
+                        panicked(&plugin.name(), msg.to_string().trim_end(), &*panic, &health);
+                        if invocation.is_some() {
+                            outbound.reply(&msg, "Sorry, something went wrong there.");
+                        }
+                    }
+                }
+                Job::Due(job) => {
+                    let outbound = outbound.borrow().clone();
+                    let ran = AssertUnwindSafe(plugin.run_job(&outbound, &job))
+                        .catch_unwind()
+                        .await;
+                    if let Err(panic) = ran {
+                        panicked(&plugin.name(), &format!("job {}", job), &*panic, &health);
+                    }
+                }
+            }
+        }
+        log::debug!("Message queue for plugin {} closed", plugin.name());
+    });
+    queue
+}
+
+/// Puts the plugin's jobs in its queue when they are due. Only holds on to the queue weakly, so
+/// the jobs end once the plugin is replaced.
+fn scheduler(queue: mpsc::WeakUnboundedSender<Job>, health: Rc<RefCell<Health>>) -> Scheduler {
+    Scheduler::new(move |job| {
+        let queue = match queue.upgrade() {
+            Some(queue) => queue,
+            None => return false,
+        };
+        let health = health.borrow();
+        if !health.enabled || health.quarantined {
+            return true;
+        }
+        queue.send(Job::Due(job.to_owned())).is_ok()
+    })
+}
+
+fn panicked(name: &str, on: &str, panic: &(dyn std::any::Any + Send), health: &RefCell<Health>) {
+    log::error!(
+        "Plugin {} panicked on {}: {}",
+        name,
+        on,
+        panic_message(panic)
+    );
+    if health.borrow_mut().panicked(Instant::now()) {
+        log::error!(
+            "Plugin {} keeps panicking, it gets no more messages until enabled again",
+            name
+        );
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+/// Who sent the message, for rate limiting. The host, since changing nick is easy. Just the nick
+/// if we do not know the host.
+fn user(msg: &Message) -> Option<&str> {
+    match msg.prefix {
+        Some(Prefix::Nickname(_, _, ref host)) if !host.is_empty() => Some(host),
+        _ => msg.source_nickname(),
+    }
+}
+
+/// The channel a message happened in. None for private messages and things like QUIT or NICK that
+/// are not tied to one channel.
+pub fn channel(msg: &Message) -> Option<&str> {
+    let channel = match msg.command {
+        Command::PRIVMSG(ref target, _) | Command::NOTICE(ref target, _) => target,
+        Command::JOIN(ref chanlist, _, _) | Command::PART(ref chanlist, _) => chanlist,
+        Command::TOPIC(ref channel, _) | Command::KICK(ref channel, _, _) => channel,
+        _ => return None,
+    };
+    if channel.is_channel_name() {
+        Some(channel)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock;
+    use super::super::outbound::Recorder;
+    use super::super::ratelimit::RateLimitConfig;
+    use super::super::router::CommandSpec;
+    use super::*;
+    use async_trait::async_trait;
+
+    struct Panicky;
+
+    #[async_trait(?Send)]
+    impl Plugin for Panicky {
+        fn commands(&self) -> Vec<CommandSpec> {
+            vec![
+                CommandSpec::new("boom", "Panics"),
+                CommandSpec::new("ok", "Works"),
+            ]
+        }
+
+        async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+            match invocation.command.as_str() {
+                "boom" => panic!("boom"),
+                _ => outbound.reply(msg, "ok"),
+            }
+        }
+    }
+
+    impl super::super::help::Help for Panicky {
+        fn name(&self) -> String {
+            String::from("panicky")
+        }
+    }
+
+    struct Ticker;
+
+    #[async_trait(?Send)]
+    impl Plugin for Ticker {
+        fn schedule(&mut self, scheduler: Scheduler) {
+            scheduler.add(super::super::scheduler::Job::every(
+                "tick",
+                chrono::Duration::milliseconds(5),
+            ));
+        }
+
+        /// As if answering someone, so the plugin's reply settings apply.
+        async fn run_job(&mut self, outbound: &Outbound, job: &str) {
+            let asked: Message = ":ward!ward@host PRIVMSG #chan :!tick".parse().unwrap();
+            outbound.reply(&asked, job);
+        }
+    }
+
+    impl super::super::help::Help for Ticker {
+        fn name(&self) -> String {
+            String::from("ticker")
+        }
+    }
+
+    async fn say(dispatcher: &mut Dispatcher, recorder: &Recorder, text: &str) -> Vec<String> {
+        let msg = format!(":ward!ward@host PRIVMSG #chan :{}", text);
+        dispatcher.dispatch(msg.parse().unwrap());
+        dispatcher.flush().await;
+        recorder.take()
+    }
+
+    #[tokio::test]
+    async fn quarantine() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (outbound, recorder) = Outbound::recording();
+                let limiter = RateLimiter::new(RateLimitConfig {
+                    user_commands: 100,
+                    ..Default::default()
+                });
+                let mut dispatcher =
+                    Dispatcher::new(outbound, limiter, QuarantineConfig::default());
+                dispatcher.add(Box::new(Panicky), PluginConfig::default());
+
+                let sorry = vec!["PRIVMSG #chan Sorry, something went wrong there."];
+                assert_eq!(say(&mut dispatcher, &recorder, "!boom").await, sorry);
+                assert_eq!(
+                    say(&mut dispatcher, &recorder, "!ok").await,
+                    ["PRIVMSG #chan ok"]
+                );
+                assert_eq!(say(&mut dispatcher, &recorder, "!boom").await, sorry);
+                assert_eq!(say(&mut dispatcher, &recorder, "!boom").await, sorry);
+                // Third strike
+                assert!(say(&mut dispatcher, &recorder, "!ok").await.is_empty());
+
+                dispatcher.set_enabled("panicky", true);
+                assert_eq!(
+                    say(&mut dispatcher, &recorder, "!ok").await,
+                    ["PRIVMSG #chan ok"]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn rebuilt_help() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (outbound, recorder) = Outbound::recording();
+                let limiter = RateLimiter::new(RateLimitConfig::default());
+                let mut dispatcher =
+                    Dispatcher::new(outbound, limiter, QuarantineConfig::default());
+                dispatcher.add(Box::new(HelpHandler::new()), PluginConfig::default());
+                assert_eq!(
+                    say(&mut dispatcher, &recorder, "!help").await,
+                    ["PRIVMSG #chan Plugins: help"]
+                );
+
+                dispatcher.add(Box::new(Panicky), PluginConfig::default());
+                assert!(dispatcher.has("panicky"));
+                assert!(!dispatcher.has("ticker"));
+                let help = dispatcher.help();
+                assert!(dispatcher.replace(Box::new(help)));
+                assert_eq!(
+                    say(&mut dispatcher, &recorder, "!help").await,
+                    ["PRIVMSG #chan Plugins: help, panicky"]
+                );
+                assert_eq!(
+                    say(&mut dispatcher, &recorder, "!help panicky").await,
+                    ["PRIVMSG #chan Plugin panicky: !boom, !ok. Try !help panicky NUMBER"]
+                );
+            })
+            .await;
+    }
+
+    /// Moves both the clock and tokio's paused time `steps` ticks of the ticker along, gives
+    /// how many ticks were said meanwhile.
+    async fn ticks(dispatcher: &Dispatcher, recorder: &Recorder, steps: u32) -> usize {
+        let step = std::time::Duration::from_millis(5);
+        for _ in 0..steps {
+            clock::freeze(clock::now() + chrono::Duration::from_std(step).unwrap());
+            tokio::time::advance(step).await;
+            dispatcher.flush().await;
+        }
+        // The last one might have come in after that flush
+        tokio::task::yield_now().await;
+        dispatcher.flush().await;
+        let ticks = recorder.take();
+        assert!(ticks.iter().all(|tick| tick == "PRIVMSG #chan tick"));
+        ticks.len()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_jobs() {
+        clock::freeze("2026-06-20T18:00:00Z".parse().unwrap());
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (outbound, recorder) = Outbound::recording();
+                let limiter = RateLimiter::new(RateLimitConfig::default());
+                let mut dispatcher =
+                    Dispatcher::new(outbound, limiter, QuarantineConfig::default());
+                dispatcher.add(Box::new(Ticker), PluginConfig::default());
+                // Lets the job start counting
+                dispatcher.flush().await;
+                assert_eq!(ticks(&dispatcher, &recorder, 4).await, 4);
+
+                dispatcher.set_enabled("ticker", false);
+                assert_eq!(ticks(&dispatcher, &recorder, 4).await, 0);
+
+                // The new one has jobs of its own, the old one's stop
+                dispatcher.set_enabled("ticker", true);
+                dispatcher.replace(Box::new(Ticker));
+                dispatcher.flush().await;
+                assert_eq!(ticks(&dispatcher, &recorder, 3).await, 3);
+            })
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfigured_jobs() {
+        clock::freeze("2026-06-20T18:00:00Z".parse().unwrap());
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (outbound, recorder) = Outbound::recording();
+                let limiter = RateLimiter::new(RateLimitConfig::default());
+                let mut dispatcher =
+                    Dispatcher::new(outbound, limiter, QuarantineConfig::default());
+                dispatcher.add(Box::new(Ticker), PluginConfig::default());
+                dispatcher.flush().await;
+                assert_eq!(ticks(&dispatcher, &recorder, 1).await, 1);
+
+                let config: Config = toml::from_str(
+                    r#"
+                    [plugins.ticker]
+                    reply = "notice"
+                    "#,
+                )
+                .unwrap();
+                dispatcher.reconfigure(&config);
+                let step = std::time::Duration::from_millis(5);
+                clock::freeze(clock::now() + chrono::Duration::from_std(step).unwrap());
+                tokio::time::advance(step).await;
+                tokio::task::yield_now().await;
+                dispatcher.flush().await;
+                assert_eq!(recorder.take(), ["NOTICE ward tick"]);
+            })
+            .await;
+    }
+
+    #[test]
+    fn message_channel() {
+        let privmsg: Message = ":ward!ward@host PRIVMSG #chan :!time".parse().unwrap();
+        assert_eq!(channel(&privmsg), Some("#chan"));
+        let query: Message = ":ward!ward@host PRIVMSG butler :!time".parse().unwrap();
+        assert_eq!(channel(&query), None);
+        let join: Message = ":ward!ward@host JOIN #chan".parse().unwrap();
+        assert_eq!(channel(&join), Some("#chan"));
+        let quit: Message = ":ward!ward@host QUIT :bye".parse().unwrap();
+        assert_eq!(channel(&quit), None);
+    }
+}
@@ -1,107 +1,87 @@
+use super::cache::{Cache, Ttl, TtlConfig};
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use super::{clock, http};
 use async_trait::async_trait;
-use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
 use irc::client::prelude::*;
-use unicode_segmentation::UnicodeSegmentation;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 pub struct EloHandler {
-    ranking: Vec<EloEntry>,
-    cache_time: chrono::Duration,
-    last_update: DateTime<Utc>,
+    http: http::Http,
+    ranking: Rc<Vec<EloEntry>>,
+    cache: Cache<Vec<EloEntry>>,
 }
 
 impl EloHandler {
-    pub fn new() -> EloHandler {
+    pub fn new(http: &http::Http, cache_config: &HashMap<String, TtlConfig>) -> EloHandler {
+        let cache = Self::cache(cache_config);
+        let refresh_http = http.clone();
+        cache.refresh_in_background(move || Self::fetch(refresh_http.clone()));
         EloHandler {
-            ranking: vec![],
-            cache_time: chrono::Duration::hours(12),
-            last_update: Utc::now() - chrono::Duration::hours(13),
+            http: http.clone(),
+            ranking: Rc::new(vec![]),
+            cache,
         }
     }
 
-    fn is_cache_stale(&self) -> bool {
-        let now = Utc::now();
-        now - self.last_update > self.cache_time
+    fn cache(cache_config: &HashMap<String, TtlConfig>) -> Cache<Vec<EloEntry>> {
+        // clubelo updates once a day
+        let ttl = Ttl {
+            fresh: Duration::hours(12),
+            stale: Duration::hours(12),
+            error: Duration::minutes(10),
+            refresh: Some(Duration::hours(1)),
+        };
+        Cache::new("clubelo", ttl, cache_config)
     }
 
     /// Tries to update the rankings, leaves old one untouched if it fails.
     async fn update_rankings(&mut self) {
-        if let Some(csvtext) = Self::fetch().await {
-            let ranking = Self::parse(&csvtext);
-            if !ranking.is_empty() {
-                self.ranking = ranking;
-                self.last_update = Utc::now();
-            }
+        let http = self.http.clone();
+        if let Some(ranking) = self.cache.get(|| Self::fetch(http)).await {
+            self.ranking = ranking;
         }
     }
 
-    fn is_elo_trigger(msg: &str) -> bool {
-        "!elo".eq_ignore_ascii_case(msg.trim())
+    fn handle_elo_ranking(&self) -> String {
+        let ranks: Vec<String> = self
+            .ranking
+            .iter()
+            .take(15)
+            .map(|entry| entry.to_string())
+            .collect();
+        ranks.join("; ")
     }
 
-    fn handle_elo_ranking(&self, msg: &str) -> Option<String> {
-        if EloHandler::is_elo_trigger(msg) {
-            let ranks: Vec<String> = self
-                .ranking
-                .iter()
-                .take(15)
-                .map(|entry| entry.to_string())
-                .collect();
-            Some(ranks.join("; "))
-        } else {
-            None
-        }
+    fn handle_elo_nth(&self, query: &str) -> Option<String> {
+        let nth = query.parse::<usize>().ok()?;
+        self.ranking
+            .get(nth.checked_sub(1)?)
+            .map(|entry: &EloEntry| entry.to_string())
     }
 
-    fn handle_elo_nth(&self, msg: &str) -> Option<String> {
-        let exclamation = msg.graphemes(true).next();
-        if exclamation == Some("!") {
-            // Note: unicode_words removes '!'
-            let mut words = msg.unicode_words();
-            if let Some(trigger) = words.next() {
-                if let Some(nth) = words.next() {
-                    let cruft = words.next();
-                    if cruft.is_none() && trigger.eq_ignore_ascii_case("elo") {
-                        if let Ok(nth) = nth.parse::<usize>() {
-                            return self
-                                .ranking
-                                .get(nth - 1)
-                                .map(|entry: &EloEntry| entry.to_string());
-                        }
-                    }
-                }
-            }
-        }
-        None
-    }
-    fn handle_search(&self, msg: &str) -> Option<String> {
-        let exclamation = msg.graphemes(true).next();
-        if exclamation == Some("!") {
-            let mut words = msg.unicode_words();
-            if let Some(trigger) = words.next() {
-                if trigger.eq_ignore_ascii_case("elo") {
-                    let query: Vec<&str> = words.collect();
-                    let query = query.join(" ");
-                    let results = self.find_club(&query);
-                    if results.is_empty() {
-                        return Some("No club found for your query".to_owned());
-                    } else {
-                        let results: Vec<String> =
-                            results.iter().map(|entry| entry.to_string()).collect();
-                        return Some(results.join("; "));
-                    }
-                }
-            }
+    fn handle_search(&self, query: &str) -> String {
+        let results = self.find_club(query);
+        if results.is_empty() {
+            "No club found for your query".to_owned()
+        } else {
+            let results: Vec<String> = results.iter().map(|entry| entry.to_string()).collect();
+            results.join("; ")
         }
-        None
     }
 
     /// Fetch the current clubelo ranking from <http://api.clubelo.com/>
-    async fn fetch() -> Option<String> {
-        let now = Utc::now();
-        let url = now.format("http://api.clubelo.com/%Y-%m-%d").to_string();
-
-        let req = reqwest::get(&url).await.ok()?;
-        req.text().await.ok()
+    async fn fetch(http: http::Http) -> Result<Vec<EloEntry>, String> {
+        let path = clock::now().format("/%Y-%m-%d").to_string();
+        let url = http.url("clubelo", "http://api.clubelo.com", &path);
+        let csvtext = http.text(http.get(&url)).await.map_err(|e| e.to_string())?;
+        let ranking = Self::parse(&csvtext);
+        if ranking.is_empty() {
+            return Err(String::from("No clubs in the clubelo ranking"));
+        }
+        Ok(ranking)
     }
 
     /// Parse a string in csv format representing current clubelo ranking. The csv format follows
@@ -132,31 +112,27 @@ impl EloHandler {
     }
 }
 
-impl std::default::Default for EloHandler {
-    fn default() -> Self {
-        Self::new()
+#[async_trait(?Send)]
+impl super::Plugin for EloHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "elo",
+            "Show the top few teams ranked by clubelo. Or search for teams matching QUERY, or the team in the QUERYth place, and list their clubelo.",
+        )
+        .arg(Arg::text("QUERY").optional())]
     }
-}
 
-#[async_trait]
-impl super::AsyncMutableHandler for EloHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            // Only update when command is used
-            if message.starts_with("!elo") && self.is_cache_stale() {
-                self.update_rankings().await;
-            }
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        // Picks up what the cache refreshed in the background since the last command
+        self.update_rankings().await;
 
-            let reply = self
-                .handle_elo_ranking(message)
-                .or_else(|| self.handle_elo_nth(message))
-                .or_else(|| self.handle_search(message));
-            if let Some(reply) = reply {
-                client
-                    .send_privmsg(&channel, &format!("[ELO] {}", reply))
-                    .unwrap()
-            }
-        }
+        let reply = match invocation.get("QUERY") {
+            None => self.handle_elo_ranking(),
+            Some(query) => self
+                .handle_elo_nth(query)
+                .unwrap_or_else(|| self.handle_search(query)),
+        };
+        outbound.reply(msg, &format!("[ELO] {}", reply))
     }
 }
 
@@ -164,20 +140,6 @@ impl super::help::Help for EloHandler {
     fn name(&self) -> String {
         String::from("elo")
     }
-
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        vec![
-            super::help::HelpEntry::new("!elo", "Show the top few teams ranked by clubelo."),
-            super::help::HelpEntry::new(
-                "!elo QUERY",
-                "Search for teams matching QUERY and list their clubelo.",
-            ),
-            super::help::HelpEntry::new(
-                "!elo POSITION",
-                "Search for the team in POSITIONth place and list their clubelo.",
-            ),
-        ]
-    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -200,12 +162,12 @@ impl EloEntry {
         let _rank = parts.next(); //.unwrap().parse();
                                   // let rank = if rank.is_err() { 0 } else { rank.unwrap() };
         let rank = rank + 1;
-        let club = parts.next().unwrap().to_string();
-        let country = parts.next().unwrap().to_string();
-        let level = parts.next().unwrap().to_string();
-        let elo = parts.next().unwrap().parse().unwrap();
-        let from = parts.next().unwrap().to_string();
-        let to = parts.next().unwrap().to_string();
+        let club = parts.next()?.to_string();
+        let country = parts.next()?.to_string();
+        let level = parts.next()?.to_string();
+        let elo = parts.next()?.parse().ok()?;
+        let from = parts.next()?.to_string();
+        let to = parts.next()?.to_string();
         Some(EloEntry {
             rank,
             club,
@@ -233,21 +195,20 @@ impl std::fmt::Display for EloEntry {
 mod tests {
     use super::*;
 
-    fn read_to_string(path: &str) -> String {
-        use std::fs::File;
-        use std::io::Read;
-
-        let mut f = File::open(path).unwrap();
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer).unwrap();
-        buffer
+    /// Without the background refresh, that needs a LocalSet
+    fn with_ranking(ranking: Vec<EloEntry>) -> EloHandler {
+        EloHandler {
+            http: http::Http::new(&Default::default()).unwrap(),
+            ranking: Rc::new(ranking),
+            cache: EloHandler::cache(&HashMap::new()),
+        }
     }
 
     #[test]
     fn parse_ranking() {
         let text = include_str!("clubelo.ranking.20190910.csv");
-        let elo = EloHandler::parse(&text);
-        assert!(elo.len() > 0);
+        let elo = EloHandler::parse(text);
+        assert!(!elo.is_empty());
         let p612 = elo.get(611).expect("There should be a 612th place");
         assert_eq!(p612.club, "La Fiorita");
         assert!(elo.get(613).is_none());
@@ -255,11 +216,17 @@ mod tests {
         assert_eq!(p187.club, "Anderlecht");
     }
 
+    #[test]
+    fn broken_lines() {
+        assert!(EloEntry::parse("1,Liverpool,ENG", 0).is_none());
+        assert!(EloEntry::parse("1,Liverpool,ENG,1,lots,2019-09-01,2019-09-10", 0).is_none());
+    }
+
     #[test]
     fn get_top_10() {
         let text = include_str!("clubelo.ranking.20190910.csv");
-        let elo = EloHandler::parse(&text);
-        assert!(elo.len() > 0);
+        let elo = EloHandler::parse(text);
+        assert!(!elo.is_empty());
         let top10: Vec<&EloEntry> = elo.iter().take(10).collect();
         assert_eq!(top10.len(), 10);
         assert_eq!(top10[0].club, "Liverpool");
@@ -269,10 +236,9 @@ mod tests {
     #[test]
     fn find_exact_club() {
         let text = include_str!("clubelo.ranking.20190910.csv");
-        let elorank = EloHandler::parse(&text);
-        assert!(elorank.len() > 0);
-        let mut elo = EloHandler::new();
-        elo.ranking = elorank;
+        let elorank = EloHandler::parse(text);
+        assert!(!elorank.is_empty());
+        let elo = with_ranking(elorank);
         let results = elo.find_club("Anderlecht");
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].club, "Anderlecht");
@@ -282,10 +248,9 @@ mod tests {
     #[test]
     fn find_different_case_club() {
         let text = include_str!("clubelo.ranking.20190910.csv");
-        let elorank = EloHandler::parse(&text);
-        assert!(elorank.len() > 0);
-        let mut elo = EloHandler::new();
-        elo.ranking = elorank;
+        let elorank = EloHandler::parse(text);
+        assert!(!elorank.is_empty());
+        let elo = with_ranking(elorank);
         let results = elo.find_club("anderlecht");
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].club, "Anderlecht");
@@ -294,10 +259,9 @@ mod tests {
     #[test]
     fn find_many_clubs() {
         let text = include_str!("clubelo.ranking.20190910.csv");
-        let elorank = EloHandler::parse(&text);
-        assert!(elorank.len() > 0);
-        let mut elo = EloHandler::new();
-        elo.ranking = elorank;
+        let elorank = EloHandler::parse(text);
+        assert!(!elorank.is_empty());
+        let elo = with_ranking(elorank);
         let results = elo.find_club("man");
         assert_eq!(results.len(), 6);
         assert_eq!(results[0].club, "Man City");
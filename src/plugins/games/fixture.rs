@@ -0,0 +1,179 @@
+//! The games as the JSON `Http` records and replays. The football crate fetches and parses them
+//! itself and its types cannot be (de)serialized, so this mirrors what the plugin uses of them.
+
+use chrono::{DateTime, Utc};
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Games {
+    pub countries: Vec<Country>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Country {
+    pub name: String,
+    pub competitions: Vec<Competition>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Competition {
+    pub name: String,
+    pub games: Vec<Game>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Game {
+    pub home_team: String,
+    pub away_team: String,
+    pub home_score: Option<u32>,
+    pub away_score: Option<u32>,
+    #[serde(with = "rfc3339")]
+    pub start_time: DateTime<Utc>,
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ended,
+    Upcoming,
+    /// With the minute the game is in
+    Ongoing(String),
+    Postponed,
+    Cancelled,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Ended => write!(f, "ended"),
+            Status::Upcoming => write!(f, "upcoming"),
+            Status::Ongoing(minute) => write!(f, "{}", minute),
+            Status::Postponed => write!(f, "postponed"),
+            Status::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl From<&football::Football> for Games {
+    fn from(football: &football::Football) -> Self {
+        let countries = football
+            .countries
+            .iter()
+            .map(|country| Country {
+                name: country.name.clone(),
+                competitions: country
+                    .competitions
+                    .iter()
+                    .map(|competition| Competition {
+                        name: competition.name.clone(),
+                        games: competition.games.iter().map(Game::from).collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { countries }
+    }
+}
+
+impl From<&football::Game> for Game {
+    fn from(game: &football::Game) -> Self {
+        Self {
+            home_team: game.home_team.clone(),
+            away_team: game.away_team.clone(),
+            home_score: game.home_score.map(u32::from),
+            away_score: game.away_score.map(u32::from),
+            start_time: game.start_time,
+            status: match &game.status {
+                football::GameStatus::Ended => Status::Ended,
+                football::GameStatus::Upcoming => Status::Upcoming,
+                football::GameStatus::Ongoing(minute) => Status::Ongoing(minute.clone()),
+                football::GameStatus::Postponed => Status::Postponed,
+                football::GameStatus::Cancelled => Status::Cancelled,
+            },
+        }
+    }
+}
+
+impl From<Games> for football::Football {
+    /// Back to what the football crate would have given us.
+    fn from(games: Games) -> Self {
+        let countries = games
+            .countries
+            .into_iter()
+            .map(|country| football::Country {
+                name: country.name,
+                competitions: country
+                    .competitions
+                    .into_iter()
+                    .map(|competition| football::Competition {
+                        name: competition.name,
+                        games: competition.games.into_iter().map(Into::into).collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { countries }
+    }
+}
+
+impl From<Game> for football::Game {
+    fn from(game: Game) -> Self {
+        Self {
+            home_team: game.home_team,
+            away_team: game.away_team,
+            home_score: game.home_score.and_then(|score| score.try_into().ok()),
+            away_score: game.away_score.and_then(|score| score.try_into().ok()),
+            start_time: game.start_time,
+            status: match game.status {
+                Status::Ended => football::GameStatus::Ended,
+                Status::Upcoming => football::GameStatus::Upcoming,
+                Status::Ongoing(minute) => football::GameStatus::Ongoing(minute),
+                Status::Postponed => football::GameStatus::Postponed,
+                Status::Cancelled => football::GameStatus::Cancelled,
+            },
+        }
+    }
+}
+
+/// chrono is built without serde.
+mod rfc3339 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        time.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let json = r#"{"countries": [{"name": "Belgium", "competitions": [{"name": "Jupiler Pro League", "games": [
+            {"home_team": "Club Brugge", "away_team": "Anderlecht", "home_score": 2, "away_score": 1,
+             "start_time": "2026-06-20T16:00:00+00:00", "status": {"ongoing": "67'"}},
+            {"home_team": "Genk", "away_team": "Gent", "home_score": null, "away_score": null,
+             "start_time": "2026-06-20T18:30:00+00:00", "status": "upcoming"}
+        ]}]}]}"#;
+        let games: Games = serde_json::from_str(json).unwrap();
+        let football = football::Football::from(serde_json::from_str::<Games>(json).unwrap());
+        let game = &football.countries[0].competitions[0].games[0];
+        assert_eq!(game.home_score, Some(2));
+        assert!(matches!(&game.status, football::GameStatus::Ongoing(minute) if minute == "67'"));
+        assert_eq!(Games::from(&football), games);
+        let json = json.replace("2026-06-20T18:30:00+00:00", "half past six");
+        assert!(serde_json::from_str::<Games>(&json).is_err());
+    }
+}
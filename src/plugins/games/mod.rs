@@ -1,172 +1,222 @@
 use async_trait::async_trait;
-use chrono::prelude::*;
 use chrono::Duration;
 use football::*;
 use irc::client::prelude::*;
-use regex::Regex;
+use std::collections::HashMap;
+use std::rc::Rc;
+mod fixture;
 mod query;
 mod toirc;
-use super::send_privmsg;
+use super::bus::{Bus, GameUpdate};
+use super::cache::{Cache, Ttl, TtlConfig};
+use super::http::{Http, HttpError};
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use super::scheduler::{Job, Scheduler};
 use toirc::ToIrc;
 
 const MAX_NUMBER_OF_GAMES: usize = 20;
 
+/// Where `Http` records the games, and replays them from. The football crate knows where they
+/// really come from.
+const GAMES_BASE: &str = "https://games.football.invalid";
+
 pub struct GamesHandler {
-    games: Football,
-    cached_at: DateTime<Utc>,
-    cache_threshold: Duration,
-    query_matcher: Regex,
-    empty_query_matcher: Regex,
+    games: Rc<Football>,
+    cache: Cache<Football>,
+    http: Http,
     query_parser: query::Parser,
+    bus: Bus,
 }
 
 impl GamesHandler {
-    pub async fn new() -> Self {
-        let games = match get_all_games().await {
-            Ok(games) => games,
-            Err(e) => {
-                eprintln!(
-                    "Error while getting games, returning empty instead. Error: {}",
-                    e
-                );
-                Default::default()
-            }
+    /// The games get fetched in the background, starting right away. Publishes a
+    /// `bus::GameUpdate` for every change it sees.
+    pub fn new(http: &Http, cache_config: &HashMap<String, TtlConfig>, bus: &Bus) -> Self {
+        let ttl = Ttl {
+            fresh: Duration::minutes(2),
+            stale: Duration::minutes(10),
+            error: Duration::minutes(1),
+            refresh: Some(Duration::minutes(2)),
         };
-        let cached_at = Utc::now();
-        let cache_threshold = Duration::minutes(2);
-        let query_matcher = Regex::new(r"^!games? +(.+)$").unwrap();
-        let empty_query_matcher = Regex::new(r"^!games? *$").unwrap();
+        let cache = Cache::new("games", ttl, cache_config);
+        let refresh_http = http.clone();
+        cache.refresh_in_background(move || Self::fetch(refresh_http.clone()));
         Self {
-            games,
-            cached_at,
-            cache_threshold,
-            query_matcher,
-            empty_query_matcher,
+            games: Default::default(),
+            cache,
+            http: http.clone(),
             query_parser: query::Parser::new(),
+            bus: bus.clone(),
         }
     }
 
-    /// If incoming message looks like it should be a query, get the query from it. Otherwise None.
-    fn get_query(&self, msg: &str) -> Option<String> {
-        if let Some(captures) = self.query_matcher.captures(msg) {
-            captures
-                .get(1)
-                .map(|input| input.as_str().trim().to_string())
+    /// From the football crate, unless `Http` takes over (replaying, say).
+    async fn fetch(http: Http) -> Result<Football, HttpError> {
+        let url = http.url("games", GAMES_BASE, "/games.json");
+        if http.takes_over("games") {
+            let games: fixture::Games = http.json(http.get(&url)).await?;
+            Ok(games.into())
         } else {
-            None
+            let games = get_all_games().await?;
+            http.record(&url, &fixture::Games::from(&games));
+            Ok(games)
         }
     }
 
-    /// An empty query is just a "!game" or "!games" command
-    fn is_empty_query(&self, msg: &str) -> bool {
-        self.empty_query_matcher.is_match(msg)
-    }
-
-    /// Update the list of games if cache is older than a certain threshold.
-    ///
-    /// TODO: Should/can this be async? Kick off an update while still using current stored
-    /// results. Or perhaps if updating takes longer than x seconds, use old data.
+    /// Update the list of games if the cache says so. Keeps the old list if that fails.
     async fn update(&mut self) {
-        let now = Utc::now();
-        if now - self.cached_at > self.cache_threshold {
-            println!("Starting football games update...");
-            match get_all_games().await {
-                Ok(new_games) => {
-                    println!("Got football games update.");
-                    self.games = new_games;
-                    self.cached_at = now;
+        let http = self.http.clone();
+        if let Some(games) = self.cache.get(|| Self::fetch(http)).await {
+            if !Rc::ptr_eq(&games, &self.games) {
+                for update in changes(&self.games, &games) {
+                    self.bus.publish(update);
                 }
-                Err(e) => eprintln!("Failed to update football games. {}", e),
             }
+            self.games = games;
         }
     }
 }
 
-#[async_trait]
-impl super::AsyncMutableHandler for GamesHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            // TODO: Replace these "shortshortcuts" with a proper alias plugin
-            let query = if message.eq_ignore_ascii_case("!epl") {
-                self.get_query("!game --country England --competition Premier League")
-            } else if message.eq_ignore_ascii_case("!wc") {
-                self.get_query("!game --country World Cup 2026 @bytime")
-            } else if message.eq_ignore_ascii_case("!genk") {
-                self.get_query("!game genk")
-            } else if message.eq_ignore_ascii_case("!cl") {
-                self.get_query("!game --country Champions League")
+/// The games in both `old` and `new` whose score or status changed, as they are in `new`.
+fn changes(old: &Football, new: &Football) -> Vec<GameUpdate> {
+    let mut before = HashMap::new();
+    for (country, competition, game) in all_games(old) {
+        let game = fixture::Game::from(game);
+        let key = (
+            country,
+            competition,
+            game.home_team.clone(),
+            game.away_team.clone(),
+            game.start_time,
+        );
+        before.insert(key, game);
+    }
+    all_games(new)
+        .filter_map(|(country, competition, game)| {
+            let game = fixture::Game::from(game);
+            let key = (
+                country,
+                competition,
+                game.home_team.clone(),
+                game.away_team.clone(),
+                game.start_time,
+            );
+            match before.get(&key) {
+                Some(previous) if *previous != game => Some(GameUpdate {
+                    country: country.to_owned(),
+                    competition: competition.to_owned(),
+                    home_team: game.home_team,
+                    away_team: game.away_team,
+                    home_score: game.home_score,
+                    away_score: game.away_score,
+                    status: game.status.to_string(),
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Every game, with the names of its country and competition.
+fn all_games(football: &Football) -> impl Iterator<Item = (&str, &str, &Game)> {
+    football.countries.iter().flat_map(|country| {
+        country.competitions.iter().flat_map(move |competition| {
+            competition
+                .games
+                .iter()
+                .map(move |game| (country.name.as_str(), competition.name.as_str(), game))
+        })
+    })
+}
+
+#[async_trait(?Send)]
+impl super::Plugin for GamesHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "games",
+            "Without QUERY, list countries for which there is information today. Otherwise search for games matching QUERY. Optionally combine with @modifiers or shortcuts",
+        )
+        .alias("game")
+        .arg(Arg::text("QUERY").optional())]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        if let Some(query) = invocation.get("QUERY") {
+            println!("Handling !games query: '{}'", query);
+            self.update().await;
+            let query = self.query_parser.from_message(query);
+            println!("Query parsed as: {:?}", query);
+            let filtered = self.games.query(&query.just_query_string());
+            let filtered = if let Some(country_name) = query.country {
+                filtered.country(&country_name)
             } else {
-                // Otherwise check for regular !game query
-                self.get_query(message)
+                filtered
             };
-            if let Some(query) = query {
-                println!("Handling !games query: '{}'", query);
-                self.update().await;
-                let query = self.query_parser.from_message(&query);
-                println!("Query parsed as: {:?}", query);
-                let filtered = self.games.query(&query.just_query_string());
-                let filtered = if let Some(country_name) = query.country {
-                    filtered.country(&country_name)
-                } else {
-                    filtered
-                };
-                let filtered = if let Some(competition_name) = query.competition {
-                    filtered.competition(&competition_name)
-                } else {
-                    filtered
-                };
-                let filtered = match query.time {
-                    query::QueryTime::SlidingWindow => filtered.sliding_window(10, 16),
-                    query::QueryTime::Today => filtered.today(),
-                    query::QueryTime::Yesterday => filtered.yesterday(),
-                    query::QueryTime::Tomorrow => filtered.tomorrow(),
-                    query::QueryTime::Finished => filtered.ended(),
-                    query::QueryTime::Live => filtered.live(),
-                    query::QueryTime::Upcoming => filtered.upcoming(),
-                };
-
-                let result = if filtered.countries.is_empty() {
-                    String::from("Your !games query returned no results.")
-                } else if query.display_order == query::DisplayOrder::Time {
-                    filtered.to_irc_ordered_by(toirc::DisplayOrder::Time)
-                } else {
-                    filtered.to_irc()
-                };
-
-                println!("{}", result);
-
-                let total_games: usize = filtered.number_of_games();
-                if total_games > MAX_NUMBER_OF_GAMES {
-                    let too_many_games_msg = format!(
-                        "Too many games ({}). Showing first {}.",
-                        total_games, MAX_NUMBER_OF_GAMES
-                    );
-                    send_privmsg(client, &channel, &too_many_games_msg);
-                }
+            let filtered = if let Some(competition_name) = query.competition {
+                filtered.competition(&competition_name)
+            } else {
+                filtered
+            };
+            let filtered = match query.time {
+                query::QueryTime::SlidingWindow => filtered.sliding_window(10, 16),
+                query::QueryTime::Today => filtered.today(),
+                query::QueryTime::Yesterday => filtered.yesterday(),
+                query::QueryTime::Tomorrow => filtered.tomorrow(),
+                query::QueryTime::Finished => filtered.ended(),
+                query::QueryTime::Live => filtered.live(),
+                query::QueryTime::Upcoming => filtered.upcoming(),
+            };
+
+            let result = if filtered.countries.is_empty() {
+                String::from("Your !games query returned no results.")
+            } else if query.display_order == query::DisplayOrder::Time {
+                filtered.to_irc_ordered_by(toirc::DisplayOrder::Time)
+            } else {
+                filtered.to_irc()
+            };
+
+            println!("{}", result);
 
-                send_privmsg(client, &channel, &result);
-            } else if self.is_empty_query(message) {
-                println!("Handling empty !games");
-                self.update().await;
-                let mut result = String::new();
-                let todays_games = self.games.sliding_window(10, 16);
-                if todays_games.countries.is_empty() {
-                    result.push_str("I've got nothing today. Go outside and enjoy the weather.");
-                } else {
-                    result.push_str("Check out some places: ");
-                    let mut country_names =
-                        todays_games.countries.iter().map(|country| &country.name);
-                    result.push_str(country_names.next().unwrap());
-                    for country_name in country_names {
-                        result.push_str(", ");
-                        result.push_str(country_name);
-                    }
+            let total_games: usize = filtered.number_of_games();
+            if total_games > MAX_NUMBER_OF_GAMES {
+                let too_many_games_msg = format!(
+                    "Too many games ({}). Showing first {}.",
+                    total_games, MAX_NUMBER_OF_GAMES
+                );
+                outbound.reply(msg, &too_many_games_msg);
+            }
+
+            outbound.reply(msg, &result);
+        } else {
+            println!("Handling empty !games");
+            self.update().await;
+            let mut result = String::new();
+            let todays_games = self.games.sliding_window(10, 16);
+            if todays_games.countries.is_empty() {
+                result.push_str("I've got nothing today. Go outside and enjoy the weather.");
+            } else {
+                result.push_str("Check out some places: ");
+                let mut country_names = todays_games.countries.iter().map(|country| &country.name);
+                result.push_str(country_names.next().unwrap());
+                for country_name in country_names {
+                    result.push_str(", ");
+                    result.push_str(country_name);
                 }
-                println!("{}", result);
-                client.send_privmsg(&channel, &result).unwrap();
             }
+            println!("{}", result);
+            outbound.reply(msg, &result);
         }
     }
+
+    /// Keeps looking when nobody asks, so the `bus::GameUpdate`s go out as things happen.
+    fn schedule(&mut self, scheduler: Scheduler) {
+        scheduler.add(Job::every("updates", Duration::minutes(2)));
+    }
+
+    async fn run_job(&mut self, _outbound: &Outbound, _job: &str) {
+        self.update().await;
+    }
 }
 
 impl super::help::Help for GamesHandler {
@@ -176,14 +226,6 @@ impl super::help::Help for GamesHandler {
 
     fn help(&self) -> Vec<super::help::HelpEntry> {
         let result = vec![
-            super::help::HelpEntry::new(
-                "!games",
-                "List countries for which there is information today.",
-            ),
-            super::help::HelpEntry::new(
-                "!games QUERY",
-                "Search for games matching query. Optionally combine with @modifiers or shortcuts",
-            ),
             super::help::HelpEntry::new("!games @yday", "Match yesterday's games."),
             super::help::HelpEntry::new("!games @today", "Match today's games."),
             super::help::HelpEntry::new("!games @tomorrow", "Match tomorrow's games."),
@@ -194,3 +236,49 @@ impl super::help::Help for GamesHandler {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn games(json: &str) -> Football {
+        serde_json::from_str::<fixture::Games>(json).unwrap().into()
+    }
+
+    #[test]
+    fn game_changes() {
+        let old = games(
+            r#"{"countries": [{"name": "Belgium", "competitions": [{"name": "Jupiler Pro League", "games": [
+            {"home_team": "Club Brugge", "away_team": "Anderlecht", "home_score": 1, "away_score": 0,
+             "start_time": "2026-06-20T16:00:00+00:00", "status": {"ongoing": "67'"}},
+            {"home_team": "Genk", "away_team": "Gent", "home_score": null, "away_score": null,
+             "start_time": "2026-06-20T18:30:00+00:00", "status": "upcoming"}
+        ]}]}]}"#,
+        );
+        let new = games(
+            r#"{"countries": [{"name": "Belgium", "competitions": [{"name": "Jupiler Pro League", "games": [
+            {"home_team": "Club Brugge", "away_team": "Anderlecht", "home_score": 2, "away_score": 0,
+             "start_time": "2026-06-20T16:00:00+00:00", "status": "ended"},
+            {"home_team": "Genk", "away_team": "Gent", "home_score": null, "away_score": null,
+             "start_time": "2026-06-20T18:30:00+00:00", "status": "upcoming"},
+            {"home_team": "Antwerp", "away_team": "Standard", "home_score": null, "away_score": null,
+             "start_time": "2026-06-21T18:30:00+00:00", "status": "upcoming"}
+        ]}]}]}"#,
+        );
+        assert_eq!(
+            changes(&old, &new),
+            vec![GameUpdate {
+                country: String::from("Belgium"),
+                competition: String::from("Jupiler Pro League"),
+                home_team: String::from("Club Brugge"),
+                away_team: String::from("Anderlecht"),
+                home_score: Some(2),
+                away_score: Some(0),
+                status: String::from("ended"),
+            }]
+        );
+        assert!(changes(&new, &new).is_empty());
+        // The first fetch is not news
+        assert!(changes(&Football::default(), &new).is_empty());
+    }
+}
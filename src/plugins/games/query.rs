@@ -151,6 +151,7 @@ impl Parser {
         Self { shortcuts }
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_message(&self, msg: &str) -> Query {
         let msg_parts = msg.split(' ');
 
@@ -1,3 +1,4 @@
+use super::super::clock;
 use chrono::prelude::*;
 
 /// Often I want the Display of a struct to be different from the way I want it to look on IRC.
@@ -18,7 +19,7 @@ impl ToIrc for football::Game {
                 away = self.away_team
             ),
             football::GameStatus::Upcoming => {
-                let now = Utc::now().date_naive();
+                let now = clock::now().date_naive();
                 if self.start_time.date_naive().ordinal() == now.ordinal() {
                     format!(
                         "({}) {} - {}",
@@ -65,22 +66,17 @@ impl ToIrc for football::Football {
     fn to_irc_ordered_by(&self, order: DisplayOrder) -> String {
         // Create tuples since a Game does not know its competition / country.
         // Once we have tuples, we can do sorting
-        let mut all_games: Vec<_> = self
-            .countries
-            .iter()
-            .map(|country| {
-                country
-                    .competitions
-                    .iter()
-                    .map(|competition| {
+        let mut all_games: Vec<_> =
+            self.countries
+                .iter()
+                .flat_map(|country| {
+                    country.competitions.iter().flat_map(|competition| {
                         competition.games.iter().map(|game| {
                             (game, competition.name.to_string(), country.name.to_string())
                         })
                     })
-                    .flatten()
-            })
-            .flatten()
-            .collect();
+                })
+                .collect();
 
         // TODO Does this handle the entire to_irc case? If so, remove the code duplication.
         // Just gotta think about the max number of games to show.
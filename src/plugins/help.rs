@@ -2,19 +2,26 @@
 //! might have been better. Future work! Could add plugin version and such in that case without
 //! making things weird. The plugin "name" already feels a little out of place right now.
 
-use super::send_privmsg;
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use super::Plugin;
+use async_trait::async_trait;
 use irc::client::prelude::*;
 use std::collections::HashMap;
 
 /// Handlers of plugins will want to implement this trait in order to be used by this plugin.
 pub trait Help {
-    fn help(&self) -> Vec<HelpEntry>;
+    /// Help on top of what the plugin's commands already describe, e.g. special syntax.
+    fn help(&self) -> Vec<HelpEntry> {
+        vec![]
+    }
     fn name(&self) -> String;
     // TODO
     // fn version(&self) -> String;
     // (Or a special type for version?)
 }
 
+#[derive(Clone)]
 pub struct HelpEntry {
     command: String,
     description: String,
@@ -30,33 +37,51 @@ impl HelpEntry {
 }
 
 pub struct HelpHandler {
-    regex_match: regex::Regex,
     data: HashMap<String, Vec<HelpEntry>>,
 }
 
 impl HelpHandler {
     pub fn new() -> Self {
         let mut res = Self {
-            regex_match: regex::Regex::new(r"(?i)^!help(?: (\w+)(?: (\d+))?)?").unwrap(),
             data: HashMap::new(),
         };
         // Due to borrow checker, need to explicitly add our own help
-        res.data.insert(res.name(), res.help());
+        res.data.insert(res.name(), HelpHandler::entries(&res));
         res
     }
 
     pub fn add_help<T>(&mut self, entry: &T)
     where
-        T: Help,
+        T: Plugin + ?Sized,
     {
-        self.data.insert(entry.name(), entry.help());
+        self.data.insert(entry.name(), HelpHandler::entries(entry));
+    }
+
+    /// Like `add_help`, for a plugin that is not at hand any more (see `Dispatcher::help`).
+    pub fn add_entries(&mut self, name: &str, entries: Vec<HelpEntry>) {
+        self.data.insert(name.to_owned(), entries);
+    }
+
+    /// The plugin's commands first, then whatever extra help it has.
+    pub fn entries<T>(plugin: &T) -> Vec<HelpEntry>
+    where
+        T: Plugin + ?Sized,
+    {
+        plugin
+            .commands()
+            .iter()
+            .map(|command| HelpEntry::new(&command.usage(), command.description()))
+            .chain(plugin.help())
+            .collect()
     }
 
     fn plugins(&self) -> Vec<&String> {
-        self.data.keys().collect()
+        let mut plugins: Vec<&String> = self.data.keys().collect();
+        plugins.sort();
+        plugins
     }
 
-    fn commands(&self, plugin_name: &str) -> Vec<&String> {
+    fn plugin_commands(&self, plugin_name: &str) -> Vec<&String> {
         self.data
             .get(plugin_name)
             .map_or_else(Vec::new, |help_entries| {
@@ -87,54 +112,60 @@ impl HelpHandler {
     }
 }
 
-impl super::Handler for HelpHandler {
-    fn handle(&self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if let Some(captures) = self.regex_match.captures(message) {
-                if let Some(position) = captures.get(2) {
-                    // !help plugin_name position
-                    if let Ok(position) = position.as_str().parse() {
-                        // 2nd capture does not exist without the 1st
-                        let plugin_name = captures.get(1).unwrap().as_str();
-                        if let Some(help_entry) = self.help_entry(plugin_name, position) {
-                            // Found help for request
-                            let result = format!(
-                                "Command \"{command}\" in {plugin_name}: {description}",
-                                command = help_entry.command,
-                                plugin_name = plugin_name,
-                                description = help_entry.description
-                            );
-                            send_privmsg(client, channel, &result);
-                        } else {
-                            // No help entry found (e.g., out of bounds)
-                            let result = format!(
-                                "No help found at position {} for {}",
-                                position, plugin_name
-                            );
-                            send_privmsg(client, channel, &result);
-                        }
-                    }
-                } else if let Some(plugin_name) = captures.get(1) {
-                    // !help plugin_name
-                    let plugin_name = plugin_name.as_str();
-                    let commands = self.commands(plugin_name);
-                    if !commands.is_empty() {
-                        let result = format!(
-                            "Plugin {plugin_name}: {commands}. Try !help {plugin_name} NUMBER",
-                            plugin_name = plugin_name,
-                            commands = HelpHandler::join_vec(commands)
-                        );
-                        send_privmsg(client, channel, &result);
-                    } else {
-                        let result = format!("No help found for {}", plugin_name);
-                        send_privmsg(client, channel, &result);
-                    }
+#[async_trait(?Send)]
+impl super::Plugin for HelpHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "help",
+            "Shows a list of plugins, the commands of PLUGIN, or the INDEXth command of PLUGIN. Zero-based.",
+        )
+        .arg(Arg::word("PLUGIN").optional())
+        .arg(Arg::number("INDEX").optional())]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        match (invocation.get("PLUGIN"), invocation.number("INDEX")) {
+            (Some(plugin_name), Some(position)) => {
+                // !help plugin_name position
+                let help_entry = usize::try_from(position)
+                    .ok()
+                    .and_then(|position| self.help_entry(plugin_name, position));
+                if let Some(help_entry) = help_entry {
+                    // Found help for request
+                    let result = format!(
+                        "Command \"{command}\" in {plugin_name}: {description}",
+                        command = help_entry.command,
+                        plugin_name = plugin_name,
+                        description = help_entry.description
+                    );
+                    outbound.reply(msg, &result);
+                } else {
+                    // No help entry found (e.g., out of bounds)
+                    let result =
+                        format!("No help found at position {} for {}", position, plugin_name);
+                    outbound.reply(msg, &result);
+                }
+            }
+            (Some(plugin_name), None) => {
+                // !help plugin_name
+                let commands = self.plugin_commands(plugin_name);
+                if !commands.is_empty() {
+                    let result = format!(
+                        "Plugin {plugin_name}: {commands}. Try !help {plugin_name} NUMBER",
+                        plugin_name = plugin_name,
+                        commands = HelpHandler::join_vec(commands)
+                    );
+                    outbound.reply(msg, &result);
                 } else {
-                    // !help
-                    let result = format!("Plugins: {}", HelpHandler::join_vec(self.plugins()));
-                    send_privmsg(client, channel, &result);
+                    let result = format!("No help found for {}", plugin_name);
+                    outbound.reply(msg, &result);
                 }
             }
+            _ => {
+                // !help
+                let result = format!("Plugins: {}", HelpHandler::join_vec(self.plugins()));
+                outbound.reply(msg, &result);
+            }
         }
     }
 }
@@ -149,47 +180,34 @@ impl Help for HelpHandler {
     fn name(&self) -> String {
         String::from("help")
     }
-
-    fn help(&self) -> Vec<HelpEntry> {
-        let result = vec![
-            HelpEntry {
-                command: String::from("!help"),
-                description: String::from("Shows a list of plugins for which some help exists"),
-            },
-            HelpEntry {
-                command: String::from("!help PLUGINNAME"),
-                description: String::from("Shows a list of commands for the given plugin"),
-            },
-            HelpEntry {
-                command: String::from("!help PLUGINNAME INDEX"),
-                description: String::from(
-                    "Shows the INDEXth command for the given plugin. Zero-based.",
-                ),
-            },
-        ];
-        result
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::router::Router;
     use super::*;
 
     #[test]
     fn match_help() {
         let help_handler = HelpHandler::new();
-        let _m1 = help_handler.regex_match.captures("!help").unwrap();
-        let _m1 = help_handler.regex_match.captures("!HELP").unwrap();
-        let m2 = help_handler
-            .regex_match
-            .captures("!help a_plugin_name")
-            .unwrap();
-        m2.get(1).unwrap();
-        let m3 = help_handler
-            .regex_match
-            .captures("!help a_plugin_name 3")
-            .unwrap();
-        m3.get(1).unwrap();
-        m3.get(2).unwrap();
+        let mut router = Router::new();
+        router.add(0, "help", help_handler.commands());
+        let (_, m1) = router.route("!help").unwrap();
+        assert_eq!(m1.unwrap().get("PLUGIN"), None);
+        let (_, m1) = router.route("!HELP").unwrap();
+        assert!(m1.is_ok());
+        let (_, m2) = router.route("!help a_plugin_name").unwrap();
+        assert_eq!(m2.unwrap().get("PLUGIN"), Some("a_plugin_name"));
+        let (_, m3) = router.route("!help a_plugin_name 3").unwrap();
+        let m3 = m3.unwrap();
+        assert_eq!(m3.get("PLUGIN"), Some("a_plugin_name"));
+        assert_eq!(m3.number("INDEX"), Some(3));
+    }
+
+    #[test]
+    fn commands_become_help() {
+        let help_handler = HelpHandler::new();
+        let entry = help_handler.help_entry("help", 0).unwrap();
+        assert_eq!(entry.command, "!help [PLUGIN] [INDEX]");
     }
 }
@@ -0,0 +1,390 @@
+//! Every plugin fetches through here. One client for all of them, set up from `[http]` in
+//! plugins.toml: user agent, timeout, and where each source lives (`base_urls`, handy to point a
+//! plugin at a local copy of some API).
+//!
+//! With `mode = "record"` everything fetched is also written to the `fixtures` directory. With
+//! `mode = "replay"` nothing goes out, requests are answered from that directory instead, so the
+//! bot can run offline. The directory has an `index.toml` mapping URLs to the files with their
+//! body. Paths in there are relative to the directory, so fixtures can be files that live
+//! elsewhere in the repo too (see tests/replay/fixtures).
+//!
+//! The games and league_ranking plugins get their data through the football crate, which does its
+//! own fetching and parsing. What it comes up with is recorded as JSON of our own (see `record`),
+//! and replay mode, or a `base_urls` entry for games or league_ranking, answers from that format
+//! instead of asking the crate (see `takes_over`).
+
+use reqwest::{ClientBuilder, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Query parameters that do not end up in fixtures, nor decide which fixture is used.
+const SECRET_PARAMETERS: &[&str] = &["client_id", "client_secret", "access_token"];
+
+const INDEX: &str = "index.toml";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub user_agent: String,
+    /// Requests that take longer than this fail.
+    pub timeout_secs: u64,
+    pub mode: HttpMode,
+    /// Where record mode writes and replay mode reads.
+    pub fixtures: String,
+    /// Replaces the start of the URLs of a source, by source name (clubelo, games,
+    /// league_ranking, strava, untappd, wikipedia).
+    pub base_urls: HashMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: format!(
+                "rusty-butler/{} (+https://github.com/ward/rusty-butler)",
+                env!("CARGO_PKG_VERSION")
+            ),
+            timeout_secs: 30,
+            mode: HttpMode::default(),
+            fixtures: String::from("fixtures"),
+            base_urls: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMode {
+    /// Just fetch
+    #[default]
+    Live,
+    /// Fetch and save what came back
+    Record,
+    /// Never fetch, only answer from what was saved
+    Replay,
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Request(reqwest::Error),
+    Json(serde_json::Error),
+    /// Replaying, and there is no fixture for this URL
+    NoFixture(String),
+    /// Replaying, and the fixtures could not be read
+    Fixtures(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Request(e) => write!(f, "HTTP request failed: {}", e),
+            HttpError::Json(e) => write!(f, "Unexpected JSON: {}", e),
+            HttpError::NoFixture(url) => write!(f, "No fixture for {}", url),
+            HttpError::Fixtures(e) => write!(f, "Could not read fixtures: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(e: reqwest::Error) -> Self {
+        HttpError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for HttpError {
+    fn from(e: serde_json::Error) -> Self {
+        HttpError::Json(e)
+    }
+}
+
+/// Cheap to clone, every clone uses the same connection pool.
+#[derive(Clone, Debug)]
+pub struct Http {
+    client: reqwest::Client,
+    config: Rc<HttpConfig>,
+}
+
+impl Http {
+    pub fn new(config: &HttpConfig) -> Result<Self, HttpError> {
+        let config = Rc::new(config.clone());
+        let client = Self::builder(&config).build()?;
+        Ok(Self { client, config })
+    }
+
+    fn builder(config: &HttpConfig) -> ClientBuilder {
+        reqwest::Client::builder()
+            .user_agent(config.user_agent.clone())
+            .timeout(Duration::from_secs(config.timeout_secs))
+    }
+
+    /// For plugins that need a client of their own (cookies, say). Send its requests through
+    /// `text` or `json` all the same.
+    pub fn client_builder(&self) -> ClientBuilder {
+        Self::builder(&self.config)
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// `path` on `source`, which lives at `default_base` unless plugins.toml says otherwise.
+    pub fn url(&self, source: &str, default_base: &str, path: &str) -> String {
+        let base = self
+            .config
+            .base_urls
+            .get(source)
+            .map(String::as_str)
+            .unwrap_or(default_base);
+        format!("{}{}", base.trim_end_matches('/'), path)
+    }
+
+    /// Sends the request, gives the body.
+    pub async fn text(&self, request: RequestBuilder) -> Result<String, HttpError> {
+        let (client, request) = request.build_split();
+        let request = request?;
+        let key = fixture_key(request.url());
+        let fixtures = Path::new(&self.config.fixtures);
+        if self.config.mode == HttpMode::Replay {
+            return load(fixtures, &key);
+        }
+        // An error page is not what anyone asked for, nor worth replaying
+        let body = client
+            .execute(request)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if self.config.mode == HttpMode::Record {
+            if let Err(e) = save(fixtures, &key, &body) {
+                log::warn!("Failed to record {}: {}", key, e);
+            }
+        }
+        Ok(body)
+    }
+
+    /// Sends the request, parses the body.
+    pub async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, HttpError> {
+        Ok(serde_json::from_str(&self.text(request).await?)?)
+    }
+
+    /// Whether `source` should be fetched from `url` like everything else, rather than by the
+    /// crate that normally gets it. True when replaying, or when `base_urls` has the source.
+    pub fn takes_over(&self, source: &str) -> bool {
+        self.config.mode == HttpMode::Replay || self.config.base_urls.contains_key(source)
+    }
+
+    /// When recording, saves `value` as the JSON `url` answers with. For what a crate fetched on
+    /// its own, so `json` can replay it later.
+    pub fn record<T: Serialize>(&self, url: &str, value: &T) {
+        if self.config.mode != HttpMode::Record {
+            return;
+        }
+        let key = match Url::parse(url) {
+            Ok(url) => fixture_key(&url),
+            Err(e) => return log::warn!("Failed to record {}: {}", url, e),
+        };
+        let saved = serde_json::to_string_pretty(value)
+            .map_err(|e| e.to_string())
+            .and_then(|body| save(Path::new(&self.config.fixtures), &key, &body));
+        if let Err(e) = saved {
+            log::warn!("Failed to record {}: {}", key, e);
+        }
+    }
+}
+
+/// The URL without anything secret in it.
+fn fixture_key(url: &Url) -> String {
+    let mut url = url.clone();
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !SECRET_PARAMETERS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    url.to_string()
+}
+
+/// Something that works as a file name on every system, and still says what it is. Ends in a
+/// hash of the whole key, as URLs that only differ in what gets cut off or replaced by `_` would
+/// otherwise end up in the same file.
+fn file_name(key: &str) -> String {
+    let readable = key.split_once("://").map(|(_, rest)| rest).unwrap_or(key);
+    let name: String = readable
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(100)
+        .collect();
+    format!("{}_{:016x}", name.trim_end_matches('_'), fnv1a(key))
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is sure to give the same names on every build.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn read_index(fixtures: &Path) -> Result<BTreeMap<String, String>, String> {
+    let index = fixtures.join(INDEX);
+    let contents = match std::fs::read_to_string(&index) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("{}: {}", index.display(), e)),
+    };
+    toml::from_str(&contents).map_err(|e| format!("{}: {}", index.display(), e))
+}
+
+fn save(fixtures: &Path, key: &str, body: &str) -> Result<(), String> {
+    let mut index = read_index(fixtures)?;
+    let name = index.get(key).cloned().unwrap_or_else(|| file_name(key));
+    std::fs::create_dir_all(fixtures).map_err(|e| e.to_string())?;
+    std::fs::write(fixtures.join(&name), body).map_err(|e| e.to_string())?;
+    index.insert(key.to_owned(), name);
+    let index = toml::to_string(&index).map_err(|e| e.to_string())?;
+    std::fs::write(fixtures.join(INDEX), index).map_err(|e| e.to_string())
+}
+
+fn load(fixtures: &Path, key: &str) -> Result<String, HttpError> {
+    let index = read_index(fixtures).map_err(HttpError::Fixtures)?;
+    let name = index
+        .get(key)
+        .ok_or_else(|| HttpError::NoFixture(key.to_owned()))?;
+    let path = fixtures.join(name);
+    std::fs::read_to_string(&path)
+        .map_err(|e| HttpError::Fixtures(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let url: Url = "https://api.example.org/v4/search?client_id=a&q=orval&client_secret=b"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            fixture_key(&url),
+            "https://api.example.org/v4/search?q=orval"
+        );
+        let url: Url = "http://api.example.org/2026-06-20?client_id=a"
+            .parse()
+            .unwrap();
+        assert_eq!(fixture_key(&url), "http://api.example.org/2026-06-20");
+        assert_eq!(
+            file_name("https://api.example.org/v4/search?q=orval"),
+            "api.example.org_v4_search_q_orval_8f23808e0b3c14cf"
+        );
+        // Same up to where the name is cut off
+        let long = format!("https://example.org/{}", "a".repeat(150));
+        assert_ne!(
+            file_name(&format!("{}1", long)),
+            file_name(&format!("{}2", long))
+        );
+        assert_ne!(
+            file_name("https://example.org/a?b"),
+            file_name("https://example.org/a/b")
+        );
+    }
+
+    #[tokio::test]
+    async fn record_then_replay() {
+        let fixtures = std::env::temp_dir().join(format!("butler-http-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&fixtures);
+        save(&fixtures, "https://example.org/data", "[1, 2]").unwrap();
+        save(&fixtures, "https://example.org/data", "[1, 2, 3]").unwrap();
+
+        let http = Http::new(&HttpConfig {
+            mode: HttpMode::Replay,
+            fixtures: fixtures.to_string_lossy().into_owned(),
+            base_urls: HashMap::from([(
+                String::from("example"),
+                String::from("https://example.org/"),
+            )]),
+            ..Default::default()
+        })
+        .unwrap();
+        let url = http.url("example", "https://elsewhere.example.org", "/data");
+        assert_eq!(url, "https://example.org/data");
+        let numbers: Vec<u32> = http
+            .json(http.get(&url).query(&[("client_secret", "x")]))
+            .await
+            .unwrap();
+        assert_eq!(numbers, vec![1, 2, 3]);
+        assert!(matches!(
+            http.text(http.get("https://example.org/other")).await,
+            Err(HttpError::NoFixture(_))
+        ));
+        std::fs::remove_dir_all(&fixtures).unwrap();
+    }
+
+    #[tokio::test]
+    async fn error_status() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/missing", listener.local_addr().unwrap());
+        let fixtures = std::env::temp_dir().join(format!("butler-status-{}", std::process::id()));
+        let http = Http::new(&HttpConfig {
+            mode: HttpMode::Record,
+            fixtures: fixtures.to_string_lossy().into_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let serve = async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 1024]).await.unwrap();
+            let body = "Not here";
+            let response = format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        };
+        let (result, _) = tokio::join!(http.text(http.get(&url)), serve);
+        assert!(matches!(result, Err(HttpError::Request(_))));
+        assert!(!fixtures.exists());
+    }
+
+    #[test]
+    fn record_for_a_crate() {
+        let fixtures = std::env::temp_dir().join(format!("butler-crate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&fixtures);
+        let http = Http::new(&HttpConfig {
+            mode: HttpMode::Record,
+            fixtures: fixtures.to_string_lossy().into_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!http.takes_over("games"));
+        http.record("https://games.example.org/games.json", &vec![1, 2]);
+        assert_eq!(
+            load(&fixtures, "https://games.example.org/games.json").unwrap(),
+            "[\n  1,\n  2\n]"
+        );
+        let http = Http::new(&HttpConfig {
+            base_urls: HashMap::from([(String::from("games"), String::from("http://localhost"))]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(http.takes_over("games"));
+        assert!(!http.takes_over("league_ranking"));
+        std::fs::remove_dir_all(&fixtures).unwrap();
+    }
+}
@@ -1,17 +1,33 @@
+use super::bus::{Bus, Seen, UserActivity};
+use super::caps::Context;
+use super::dispatch::channel;
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
+use super::storage::{Storage, StorageError};
+use async_trait::async_trait;
 use chrono::prelude::{DateTime, Utc};
 use irc::client::prelude::*;
-use regex::Regex;
+use rusqlite::params;
+use std::cell::{Ref, RefCell};
 use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 
-// TODO: Think up a way to make this info accessible for other plugins
-// TODO: Want to store this on disk and read it at start up in case of crashes
-//       However, serde does not readily handle DateTime and Command :(
+/// Events are kept in memory and written through to storage, so they survive restarts. The
+/// command is stored as the IRC line it came from.
+const MIGRATIONS: &[&str] = &["CREATE TABLE seen (
+    nick TEXT PRIMARY KEY,
+    at TEXT NOT NULL,
+    what TEXT NOT NULL
+);"];
+
+/// Everything we saw, by nick. Shared with the other plugins as the `bus::Seen` service.
+type Events = Rc<RefCell<HashMap<String, LastSeenEvent>>>;
 
-#[derive(Debug)]
 pub struct LastSeenHandler {
-    events: HashMap<String, LastSeenEvent>,
-    seen_matcher: Regex,
+    events: Events,
+    storage: Storage,
+    bus: Bus,
 }
 #[derive(Debug)]
 struct LastSeenEvent {
@@ -24,19 +40,70 @@ impl fmt::Display for LastSeenEvent {
         // TODO: Debug format of self.what gives too much info
         write!(
             f,
-            "Last seen at {when} doing {what}",
-            when = self.when.format("%Y-%m-%d %H:%M:%S %Z").to_string(),
-            what = format!("{:?}", self.what),
+            "Last seen at {when} doing {what:?}",
+            when = self.when.format("%Y-%m-%d %H:%M:%S %Z"),
+            what = self.what,
         )
     }
 }
 impl LastSeenHandler {
-    pub fn new() -> LastSeenHandler {
-        let seen_matcher = Regex::new(r"^!(?:last)?seen +([^ ]+) *$").unwrap();
+    /// Publishes a `bus::UserActivity` for everything it logs.
+    pub fn new(storage: &Storage, bus: &Bus) -> LastSeenHandler {
+        let events = match LastSeenHandler::load(storage) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Failed to load last seen events, starting empty. {}", e);
+                HashMap::new()
+            }
+        };
+        let events = Rc::new(RefCell::new(events));
+        bus.provide::<dyn Seen>(Rc::new(SeenService(Rc::clone(&events))));
         LastSeenHandler {
-            events: HashMap::new(),
-            seen_matcher,
+            events,
+            storage: storage.clone(),
+            bus: bus.clone(),
+        }
+    }
+
+    fn load(storage: &Storage) -> Result<HashMap<String, LastSeenEvent>, StorageError> {
+        storage.migrate("seen", MIGRATIONS)?;
+        let mut statement = storage
+            .connection()
+            .prepare("SELECT nick, at, what FROM seen")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, DateTime<Utc>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+        let mut events = HashMap::new();
+        for row in rows {
+            let (nick, when, line) = row?;
+            match line.parse::<Message>() {
+                Ok(parsed) => {
+                    events.insert(
+                        nick,
+                        LastSeenEvent {
+                            when,
+                            what: parsed.command,
+                        },
+                    );
+                }
+                Err(e) => eprintln!("Skipping stored event for {}. {}", nick, e),
+            }
         }
+        Ok(events)
+    }
+
+    fn save(&self, nick: &str, event: &LastSeenEvent) -> Result<(), StorageError> {
+        let line = Message::from(event.what.clone()).to_string();
+        self.storage.connection().execute(
+            "INSERT INTO seen (nick, at, what) VALUES (?1, ?2, ?3)
+            ON CONFLICT (nick) DO UPDATE SET at = excluded.at, what = excluded.what",
+            params![nick, event.when, line.trim_end()],
+        )?;
+        Ok(())
     }
 
     /// Given an IRC Message, considers whether it should be logged for the user that triggered
@@ -55,12 +122,29 @@ impl LastSeenHandler {
                         || nick.eq_ignore_ascii_case("freenode-connect"))
                     {
                         let nick = nick.to_owned();
-                        let command = msg.command.clone();
+                        let when = Context::new(msg).time;
+                        // Played back lines can be older than what we already know
+                        if self
+                            .events
+                            .borrow()
+                            .get(&nick)
+                            .is_some_and(|event| event.when > when)
+                        {
+                            return;
+                        }
                         let event = LastSeenEvent {
-                            when: Utc::now(),
-                            what: command,
+                            when,
+                            what: msg.command.clone(),
                         };
-                        self.events.insert(nick, event);
+                        if let Err(e) = self.save(&nick, &event) {
+                            eprintln!("Failed to store last seen event. {}", e);
+                        }
+                        self.events.borrow_mut().insert(nick.clone(), event);
+                        self.bus.publish(UserActivity {
+                            nick,
+                            channel: channel(msg).map(str::to_owned),
+                            at: when,
+                        });
                     }
                 }
             }
@@ -68,30 +152,39 @@ impl LastSeenHandler {
         }
     }
 
-    fn find_event<'a>(&'a self, nick: &str) -> Option<&'a LastSeenEvent> {
-        self.events.get(nick)
+    fn find_event(&self, nick: &str) -> Option<Ref<'_, LastSeenEvent>> {
+        Ref::filter_map(self.events.borrow(), |events| events.get(nick)).ok()
     }
+}
+
+struct SeenService(Events);
 
-    fn seen_trigger(&self, msg: &str) -> Option<String> {
-        self.seen_matcher
-            .captures(msg)
-            .map(|capture| capture.get(1).unwrap().as_str().to_string())
+impl Seen for SeenService {
+    fn last_seen(&self, nick: &str) -> Option<DateTime<Utc>> {
+        self.0.borrow().get(nick).map(|event| event.when)
     }
 }
-impl super::MutableHandler for LastSeenHandler {
-    fn handle(&mut self, client: &Client, msg: &Message) {
-        // "!(last)seen nick" command
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if let Some(nick) = self.seen_trigger(message) {
-                if let Some(event) = self.find_event(&nick) {
-                    client.send_privmsg(&channel, &event.to_string()).unwrap();
-                } else {
-                    client
-                        .send_privmsg(&channel, format!("I got nothing for '{}'.", nick))
-                        .unwrap();
-                }
-            }
+
+#[async_trait(?Send)]
+impl super::Plugin for LastSeenHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new("seen", "Check what I saw NICK most recently do.")
+                .alias("lastseen")
+                .arg(Arg::word("NICK")),
+        ]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        let nick = invocation.get("NICK").unwrap_or_default();
+        if let Some(event) = self.find_event(nick) {
+            outbound.reply(msg, &event.to_string());
+        } else {
+            outbound.reply(msg, &format!("I got nothing for '{}'.", nick));
         }
+    }
+
+    async fn handle(&mut self, _outbound: &Outbound, msg: &Message) {
         self.log(msg);
     }
 }
@@ -100,45 +193,123 @@ impl super::help::Help for LastSeenHandler {
     fn name(&self) -> String {
         String::from("seen")
     }
-
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        let result = vec![super::help::HelpEntry::new(
-            "!seen NICK",
-            "Check what I saw NICK most recently do.",
-        )];
-        result
-    }
-}
-
-impl Default for LastSeenHandler {
-    fn default() -> Self {
-        Self::new()
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::router::Router;
+    use super::super::Plugin;
     use super::*;
 
+    fn seen_trigger(router: &Router, msg: &str) -> Option<String> {
+        let (_, invocation) = router.route(msg)?;
+        invocation.ok()?.get("NICK").map(|nick| nick.to_owned())
+    }
+
     #[test]
     fn match_nick() {
-        let last_seen_handler = LastSeenHandler::new();
-        assert_eq!(
-            "ward",
-            last_seen_handler.seen_trigger("!seen ward").unwrap()
-        );
-        assert_eq!(
-            "ward",
-            last_seen_handler.seen_trigger("!seen ward ").unwrap()
-        );
+        let last_seen_handler = LastSeenHandler::new(&Storage::in_memory().unwrap(), &Bus::new());
+        let mut router = Router::new();
+        router.add(0, "seen", last_seen_handler.commands());
+        assert_eq!("ward", seen_trigger(&router, "!seen ward").unwrap());
+        assert_eq!("ward", seen_trigger(&router, "!seen ward ").unwrap());
+        assert_eq!("ward", seen_trigger(&router, "!lastseen ward").unwrap());
+        assert_eq!("ward", seen_trigger(&router, "!lastseen ward ").unwrap());
+        assert_eq!(None, seen_trigger(&router, "!lastseen "));
+    }
+
+    #[test]
+    fn survives_restart() {
+        let storage = Storage::in_memory().unwrap();
+        let mut last_seen_handler = LastSeenHandler::new(&storage, &Bus::new());
+        let msg: Message = ":ward!ward@example.org PRIVMSG #chan :hello there"
+            .parse()
+            .unwrap();
+        last_seen_handler.log(&msg);
+        let restarted = LastSeenHandler::new(&storage, &Bus::new());
+        let event = restarted.find_event("ward").unwrap();
+        assert_eq!(event.what, msg.command);
+        assert!(restarted.find_event("butler").is_none());
+    }
+
+    #[test]
+    fn shared_on_the_bus() {
+        let bus = Bus::new();
+        let activity = Rc::new(RefCell::new(vec![]));
+        {
+            let activity = Rc::clone(&activity);
+            bus.subscribe(move |event: &UserActivity| activity.borrow_mut().push(event.clone()));
+        }
+        let mut last_seen_handler = LastSeenHandler::new(&Storage::in_memory().unwrap(), &bus);
+        let msg: Message = "@time=2026-06-19T21:04:05.000Z :ward!ward@example.org PART #chan"
+            .parse()
+            .unwrap();
+        last_seen_handler.log(&msg);
+
+        let at = "2026-06-19T21:04:05Z".parse().unwrap();
         assert_eq!(
-            "ward",
-            last_seen_handler.seen_trigger("!lastseen ward").unwrap()
+            *activity.borrow(),
+            vec![UserActivity {
+                nick: String::from("ward"),
+                channel: Some(String::from("#chan")),
+                at,
+            }]
         );
+        let seen = bus.service::<dyn Seen>().unwrap();
+        assert_eq!(seen.last_seen("ward"), Some(at));
+        assert_eq!(seen.last_seen("nobody"), None);
+    }
+
+    #[test]
+    fn server_time() {
+        let mut last_seen_handler =
+            LastSeenHandler::new(&Storage::in_memory().unwrap(), &Bus::new());
+        let line = |time: &str, text: &str| -> Message {
+            format!(
+                "@time={} :ward!ward@example.org PRIVMSG #chan :{}",
+                time, text
+            )
+            .parse()
+            .unwrap()
+        };
+        last_seen_handler.log(&line("2026-06-19T21:04:05.000Z", "later"));
+        // Played back after the fact
+        last_seen_handler.log(&line("2026-06-19T20:00:00.000Z", "earlier"));
+        let event = last_seen_handler.find_event("ward").unwrap();
+        assert_eq!(event.when.to_rfc3339(), "2026-06-19T21:04:05+00:00");
         assert_eq!(
-            "ward",
-            last_seen_handler.seen_trigger("!lastseen ward ").unwrap()
+            event.what,
+            Command::PRIVMSG(String::from("#chan"), String::from("later"))
         );
-        assert_eq!(None, last_seen_handler.seen_trigger("!lastseen "));
+    }
+
+    #[tokio::test]
+    async fn seen_command() {
+        let (outbound, recorder) = Outbound::recording();
+        let mut last_seen_handler =
+            LastSeenHandler::new(&Storage::in_memory().unwrap(), &Bus::new());
+        let mut router = Router::new();
+        router.add(0, "seen", last_seen_handler.commands());
+        let said: Message = ":ward!ward@example.org PRIVMSG #chan :hello there"
+            .parse()
+            .unwrap();
+        last_seen_handler.handle(&outbound, &said).await;
+        assert!(recorder.take().is_empty());
+
+        for line in [
+            ":someone!else@example.org PRIVMSG #chan :!seen ward",
+            ":someone!else@example.org PRIVMSG #chan :!seen nobody",
+        ] {
+            let msg: Message = line.parse().unwrap();
+            let (_, invocation) = router.route(line.rsplit(" :").next().unwrap()).unwrap();
+            last_seen_handler
+                .command(&outbound, &msg, &invocation.unwrap())
+                .await;
+        }
+        let replies = recorder.take();
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].starts_with("PRIVMSG #chan Last seen at "));
+        assert!(replies[0].ends_with("doing PRIVMSG(\"#chan\", \"hello there\")"));
+        assert_eq!(replies[1], "PRIVMSG #chan I got nothing for 'nobody'.");
     }
 }
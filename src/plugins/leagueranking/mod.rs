@@ -1,35 +1,200 @@
-use super::send_privmsg;
+use super::cache::{Cache, Ttl, TtlConfig};
+use super::http::{Http, HttpError};
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
 use async_trait::async_trait;
+use chrono::Duration;
 use irc::client::prelude::*;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use football::ranking::beebs::CachedLeagues;
 
+/// Where `Http` records the rankings, and replays them from. The football crate fetches them from
+/// the configured URLs.
+const RANKING_BASE: &str = "https://league-ranking.football.invalid";
+
 #[derive(Debug)]
 pub struct LeagueRankingHandler {
-    competitions: HashMap<String, CachedLeagues>,
-    leagues: HashMap<String, CachedLeagues>,
+    competitions: HashMap<String, Ranking>,
+    leagues: HashMap<String, Ranking>,
     aliases: HashMap<String, String>,
 }
 
-impl LeagueRankingHandler {
-    pub fn new() -> Self {
-        // TODO: Move this config creation (i.e., parsing) out of here so it can be done once for
-        // all plugins
-        let config = super::config::Config::new();
+/// Where one league or competition comes from, and what we last got from there.
+#[derive(Debug)]
+struct Ranking {
+    url: String,
+    /// What `Http` records it as
+    fixture_url: String,
+    http: Http,
+    cache: Cache<Standings>,
+}
+
+/// What we got for a league or competition. Each group is a ranking of its own, a league has just
+/// the one.
+#[derive(Debug)]
+enum Standings {
+    /// By the football crate
+    Fetched(CachedLeagues),
+    /// By `Http`, as it was recorded
+    Recorded(Recorded),
+}
 
+/// The rankings as the JSON `Http` records and replays, the lines the football crate shows for
+/// every group.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Recorded {
+    groups: Vec<Vec<String>>,
+}
+
+impl From<&CachedLeagues> for Recorded {
+    fn from(leagues: &CachedLeagues) -> Self {
+        let groups = (0..)
+            .map_while(|group| leagues.get(group))
+            .map(|league| lines(&league.get_ranking()))
+            .collect();
+        Self { groups }
+    }
+}
+
+fn lines<T: ToString>(entries: &[T]) -> Vec<String> {
+    entries.iter().map(ToString::to_string).collect()
+}
+
+impl Standings {
+    /// The part of the group's ranking around `team` (a name or a position), the top without one.
+    fn around(&self, group: usize, team: Option<&str>) -> Option<Vec<String>> {
+        match self {
+            Standings::Fetched(leagues) => {
+                let league = leagues.get(group)?;
+                let ranking = if let Some(who) = team {
+                    if let Ok(who) = who.parse::<usize>() {
+                        league.get_ranking_around(if who > 1 { who - 1 } else { who })
+                    } else {
+                        let pos = league.find_team_position(who);
+                        league.get_ranking_around(pos.into())
+                    }
+                } else {
+                    league.get_ranking_around(1)
+                };
+                Some(lines(&ranking))
+            }
+            Standings::Recorded(recorded) => {
+                let lines = recorded.groups.get(group)?;
+                let position = match team {
+                    None => 1,
+                    Some(who) => who.parse().unwrap_or_else(|_| {
+                        let who = who.to_lowercase();
+                        lines
+                            .iter()
+                            .position(|line| line.to_lowercase().contains(&who))
+                            .map_or(1, |index| index + 1)
+                    }),
+                };
+                // Six lines, the team in the middle where there is room for that
+                let start = position
+                    .saturating_sub(3)
+                    .min(lines.len().saturating_sub(6));
+                Some(lines.iter().skip(start).take(6).cloned().collect())
+            }
+        }
+    }
+
+    /// The whole ranking of the group.
+    fn group(&self, group: usize) -> Option<Vec<String>> {
+        match self {
+            Standings::Fetched(leagues) => Some(lines(&leagues.get(group)?.get_ranking())),
+            Standings::Recorded(recorded) => recorded.groups.get(group).cloned(),
+        }
+    }
+}
+
+impl Ranking {
+    /// `kind` and `name` only decide the fixture it is recorded as.
+    fn new(
+        kind: &str,
+        name: &str,
+        url: &str,
+        http: &Http,
+        cache_config: &HashMap<String, TtlConfig>,
+    ) -> Self {
+        let ttl = Ttl {
+            fresh: Duration::minutes(5),
+            stale: Duration::minutes(30),
+            error: Duration::minutes(1),
+            refresh: Some(Duration::minutes(10)),
+        };
+        let ranking = Self {
+            url: url.to_owned(),
+            fixture_url: http.url(
+                "league_ranking",
+                RANKING_BASE,
+                &format!("/{}/{}.json", kind, name),
+            ),
+            http: http.clone(),
+            cache: Cache::new("league_ranking", ttl, cache_config),
+        };
+        let (url, fixture_url, http) = (
+            ranking.url.clone(),
+            ranking.fixture_url.clone(),
+            ranking.http.clone(),
+        );
+        ranking.cache.refresh_in_background(move || {
+            Self::fetch(url.clone(), fixture_url.clone(), http.clone())
+        });
+        ranking
+    }
+
+    /// From the football crate, unless `Http` takes over (replaying, say).
+    async fn fetch(url: String, fixture_url: String, http: Http) -> Result<Standings, HttpError> {
+        if http.takes_over("league_ranking") {
+            return Ok(Standings::Recorded(
+                http.json(http.get(&fixture_url)).await?,
+            ));
+        }
+        let mut leagues = CachedLeagues::empty(&url);
+        leagues.update().await?;
+        http.record(&fixture_url, &Recorded::from(&leagues));
+        Ok(Standings::Fetched(leagues))
+    }
+
+    async fn get(&self) -> Option<Rc<Standings>> {
+        let (url, fixture_url, http) = (
+            self.url.clone(),
+            self.fixture_url.clone(),
+            self.http.clone(),
+        );
+        self.cache.get(|| Self::fetch(url, fixture_url, http)).await
+    }
+}
+
+impl LeagueRankingHandler {
+    pub fn new(config: &super::config::Config, http: &Http) -> Self {
         let mut competitions = HashMap::new();
         let mut leagues = HashMap::new();
         let mut aliases = HashMap::new();
 
         for (name, league_config) in config.league_ranking.leagues.iter() {
-            leagues.insert(name.clone(), CachedLeagues::empty(&league_config.url));
+            leagues.insert(
+                name.clone(),
+                Ranking::new("leagues", name, &league_config.url, http, &config.cache),
+            );
             for alias in &league_config.alias {
                 aliases.insert(alias.clone(), name.clone());
             }
         }
         for (name, competition_config) in config.league_ranking.competitions.iter() {
-            competitions.insert(name.clone(), CachedLeagues::empty(&competition_config.url));
+            competitions.insert(
+                name.clone(),
+                Ranking::new(
+                    "competitions",
+                    name,
+                    &competition_config.url,
+                    http,
+                    &config.cache,
+                ),
+            );
             for alias in &competition_config.alias {
                 aliases.insert(alias.clone(), name.clone());
             }
@@ -54,87 +219,48 @@ impl LeagueRankingHandler {
     }
 }
 
-#[async_trait]
-impl super::AsyncMutableHandler for LeagueRankingHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            let mut message_parts = message.split(' ');
-            let rank_command = message_parts.next();
-            if rank_command.is_none() {
-                eprintln!(
-                    "Tried to split message_parts and no first part found?? {}",
-                    message
-                );
-                return;
-            }
-            let rank_command = rank_command.expect("Unreachable due to previous check");
-            if !rank_command.eq_ignore_ascii_case("!rank") {
-                return;
+#[async_trait(?Send)]
+impl super::Plugin for LeagueRankingHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "rank",
+            "List top 6 ranking for given league, or the teams around TEAM (a name or a position)",
+        )
+        .arg(Arg::word("LEAGUE"))
+        .arg(Arg::word("TEAM").optional())]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        let league_name = self.resolve_alias(invocation.get("LEAGUE").unwrap_or_default());
+        if let Some(league) = self.leagues.get(&league_name) {
+            let league = match league.get().await {
+                Some(league) => league,
+                None => return,
+            };
+            // In a regular league, there is only one
+            if let Some(ranking) = league.around(0, invocation.get("TEAM")) {
+                outbound.reply(msg, &format!("[{}] {}", league_name, ranking.join("; ")));
             }
-            if let Some(league_name) = message_parts.next() {
-                let league_name = self.resolve_alias(league_name);
-                if let Some(league) = self.leagues.get_mut(&league_name) {
-                    // This is why we need mut
-                    if let Err(e) = league.update().await {
-                        eprintln!("Failed to update group ranking: {}", e);
-                    }
+        } else if let Some(competition) = self.competitions.get(&league_name) {
+            if let Some(group) = invocation.get("TEAM") {
+                let group_name = group.to_lowercase();
+                let group_number = group_name_to_number(&group_name);
+                println!("{} - {}", group_name, group_number);
 
-                    // In a regular league, there is only one
-                    if let Some(league) = league.get(0) {
-                        let ranking = if let Some(who) = message_parts.next() {
-                            if let Ok(who) = who.parse::<usize>() {
-                                league.get_ranking_around(if who > 1 { who - 1 } else { who })
-                            } else {
-                                let pos = league.find_team_position(who);
-                                league.get_ranking_around(pos.into())
-                            }
-                        } else {
-                            league.get_ranking_around(1)
-                        };
-
-                        let ranking_txt = ranking
-                            .iter()
-                            .map(|rank_entry| rank_entry.to_string())
-                            .collect::<Vec<String>>()
-                            .join("; ");
-                        send_privmsg(
-                            client,
-                            &channel,
-                            &format!("[{}] {}", league_name, ranking_txt),
-                        );
-                    }
-                } else if let Some(competition) = self.competitions.get_mut(&league_name) {
-                    if let Some(group) = message_parts.next() {
-                        let group_name = group.to_lowercase();
-                        let group_number = group_name_to_number(&group_name);
-                        println!("{} - {}", group_name, group_number);
-
-                        // This is why we need mut
-                        if let Err(e) = competition.update().await {
-                            eprintln!("Failed to update group ranking: {}", e);
-                        }
-
-                        if let Some(group) = competition.get(group_number) {
-                            let ranking_txt = group
-                                .get_ranking()
-                                .iter()
-                                .map(|rank_entry| rank_entry.to_string())
-                                .collect::<Vec<String>>()
-                                .join("; ");
-                            send_privmsg(
-                                client,
-                                &channel,
-                                &format!("[{}][{}] {}", league_name, group_name, ranking_txt),
-                            );
-                        } else {
-                            send_privmsg(client, &channel, "Not a valid group");
-                        }
-                    } else {
-                        send_privmsg(client, &channel, "You need to give a group too");
-                    }
+                let competition = match competition.get().await {
+                    Some(competition) => competition,
+                    None => return,
+                };
+                if let Some(ranking) = competition.group(group_number) {
+                    outbound.reply(
+                        msg,
+                        &format!("[{}][{}] {}", league_name, group_name, ranking.join("; ")),
+                    );
+                } else {
+                    outbound.reply(msg, "Not a valid group");
                 }
             } else {
-                // Perhaps a listing of available leagues? Might be too long.
+                outbound.reply(msg, "You need to give a group too");
             }
         }
     }
@@ -200,23 +326,10 @@ impl super::help::Help for LeagueRankingHandler {
         String::from("league_ranking")
     }
     fn help(&self) -> Vec<super::help::HelpEntry> {
-        vec![
-            super::help::HelpEntry::new("!rank LEAGUE", "List top 6 ranking for given league"),
-            super::help::HelpEntry::new(
-                "!rank LEAGUE POSITION",
-                "List teams around the position for given league",
-            ),
-            super::help::HelpEntry::new(
-                "!rank COMPETITION GROUP",
-                "List ranking for the group in given competition",
-            ),
-        ]
-    }
-}
-
-impl Default for LeagueRankingHandler {
-    fn default() -> Self {
-        Self::new()
+        vec![super::help::HelpEntry::new(
+            "!rank COMPETITION GROUP",
+            "List ranking for the group in given competition",
+        )]
     }
 }
 
@@ -226,7 +339,7 @@ mod tests {
     fn test_group_name_converter() {
         let letters = vec!["a", "b", "f", "b2", "c4"];
         let group_numbers = vec![0, 1, 5, 5, 11];
-        for (letter, number) in letters.into_iter().zip(group_numbers.into_iter()) {
+        for (letter, number) in letters.into_iter().zip(group_numbers) {
             assert_eq!(super::group_name_to_number(letter), number);
         }
     }
@@ -0,0 +1,372 @@
+//! Numbers for Prometheus. Off unless `[metrics]` in plugins.toml has a `listen` address, then
+//! `http://<listen>/metrics` has:
+//!
+//! - `butler_messages_received_total` and `butler_messages_sent_total`
+//! - `butler_commands_total`, by plugin and command
+//! - `butler_handler_seconds`, a histogram of how long each plugin took per message
+//! - `butler_fetches_total`, by source and whether it worked. The football data shows up as the
+//!   games and league_ranking sources.
+//! - `butler_cache_lookups_total` and `butler_cache_age_seconds`, from `cache::stats`
+//! - `butler_reconnects_total`
+//!
+//! Only meant for a scraper on the same machine or network, there is nothing else on that port.
+//! Changing `listen` takes a restart.
+
+use super::cache;
+use super::clock;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Upper bounds of the `butler_handler_seconds` buckets.
+const BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0];
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, like `127.0.0.1:9184`.
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    /// Per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    /// Slower than the last bucket
+    over: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        match BUCKETS.iter().position(|bound| seconds <= *bound) {
+            Some(bucket) => self.counts[bucket] += 1,
+            None => self.over += 1,
+        }
+        self.sum += seconds;
+    }
+}
+
+struct Metrics {
+    received: u64,
+    sent: u64,
+    reconnects: u64,
+    /// By plugin and command
+    commands: BTreeMap<(String, String), u64>,
+    /// By plugin
+    handlers: BTreeMap<String, Histogram>,
+    /// By source and whether it worked
+    fetches: BTreeMap<(String, bool), u64>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            received: 0,
+            sent: 0,
+            reconnects: 0,
+            commands: BTreeMap::new(),
+            handlers: BTreeMap::new(),
+            fetches: BTreeMap::new(),
+        }
+    }
+}
+
+fn update(f: impl FnOnce(&mut Metrics)) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        f(&mut metrics);
+    }
+}
+
+pub fn received() {
+    update(|metrics| metrics.received += 1);
+}
+
+pub fn sent() {
+    update(|metrics| metrics.sent += 1);
+}
+
+pub fn reconnected() {
+    update(|metrics| metrics.reconnects += 1);
+}
+
+/// A plugin handled a message, and the command in it if it was one of its own.
+pub fn handled(plugin: &str, command: Option<&str>, took: Duration) {
+    update(|metrics| {
+        if let Some(command) = command {
+            *metrics
+                .commands
+                .entry((plugin.to_owned(), command.to_owned()))
+                .or_default() += 1;
+        }
+        metrics
+            .handlers
+            .entry(plugin.to_owned())
+            .or_default()
+            .observe(took.as_secs_f64());
+    });
+}
+
+pub fn fetched(source: &str, ok: bool) {
+    update(|metrics| *metrics.fetches.entry((source.to_owned(), ok)).or_default() += 1);
+}
+
+/// Everything in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(_) => return out,
+    };
+    let mut counter = |name: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+    counter(
+        "butler_messages_received_total",
+        "Messages from the server.",
+        vec![(String::new(), metrics.received)],
+    );
+    counter(
+        "butler_messages_sent_total",
+        "Messages sent to the server.",
+        vec![(String::new(), metrics.sent)],
+    );
+    counter(
+        "butler_reconnects_total",
+        "Times the connection was lost and set up again.",
+        vec![(String::new(), metrics.reconnects)],
+    );
+    counter(
+        "butler_commands_total",
+        "Commands handled, by plugin.",
+        metrics
+            .commands
+            .iter()
+            .map(|((plugin, command), count)| {
+                (labels(&[("plugin", plugin), ("command", command)]), *count)
+            })
+            .collect(),
+    );
+    counter(
+        "butler_fetches_total",
+        "Fetches from upstream, by source.",
+        metrics
+            .fetches
+            .iter()
+            .map(|((source, ok), count)| {
+                let result = if *ok { "ok" } else { "error" };
+                (labels(&[("source", source), ("result", result)]), *count)
+            })
+            .collect(),
+    );
+    let stats = cache::stats();
+    counter(
+        "butler_cache_lookups_total",
+        "Cache lookups, by source and how they were answered.",
+        stats
+            .iter()
+            .flat_map(|(source, stats)| {
+                [
+                    ("hit", stats.hits),
+                    ("stale", stats.stale),
+                    ("negative", stats.negative),
+                    ("miss", stats.misses),
+                ]
+                .map(|(result, count)| (labels(&[("source", source), ("result", result)]), count))
+            })
+            .collect(),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP butler_cache_age_seconds How old the cached data of a source is.\n\
+         # TYPE butler_cache_age_seconds gauge"
+    );
+    let now = clock::now();
+    for (source, stats) in &stats {
+        if let Some(fetched_at) = stats.fetched_at {
+            let age = (now - fetched_at).num_seconds().max(0);
+            let _ = writeln!(
+                out,
+                "butler_cache_age_seconds{} {}",
+                labels(&[("source", source)]),
+                age
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP butler_handler_seconds How long a plugin took to handle a message.\n\
+         # TYPE butler_handler_seconds histogram"
+    );
+    for (plugin, histogram) in &metrics.handlers {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let le = bound.to_string();
+            let _ = writeln!(
+                out,
+                "butler_handler_seconds_bucket{} {}",
+                labels(&[("plugin", plugin), ("le", &le)]),
+                cumulative
+            );
+        }
+        let total = cumulative + histogram.over;
+        let _ = writeln!(
+            out,
+            "butler_handler_seconds_bucket{} {}",
+            labels(&[("plugin", plugin), ("le", "+Inf")]),
+            total
+        );
+        let plugin = labels(&[("plugin", plugin)]);
+        let _ = writeln!(
+            out,
+            "butler_handler_seconds_sum{} {}",
+            plugin, histogram.sum
+        );
+        let _ = writeln!(out, "butler_handler_seconds_count{} {}", plugin, total);
+    }
+    out
+}
+
+/// `{name="value",...}`, escaped.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', "\\\"")
+                .replace('\n', r"\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Serves `/metrics` on `listen` until the bot stops. Logs and gives up if it cannot listen there.
+pub fn serve(listen: &str) {
+    let listen = listen.to_owned();
+    tokio::spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(&listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Cannot serve metrics on {}: {}", listen, e);
+                return;
+            }
+        };
+        log::info!("Serving metrics on http://{}/metrics", listen);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream));
+                }
+                Err(e) => log::warn!("Metrics connection failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Just enough HTTP to answer a scraper.
+async fn respond(mut stream: tokio::net::TcpStream) {
+    let mut request = vec![0; 4096];
+    let read = match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut request)).await {
+        Ok(Ok(read)) => read,
+        _ => return,
+    };
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path.split('?').next() {
+        Some("/metrics") => ("200 OK", render()),
+        _ => ("404 Not Found", String::from("Only /metrics here.\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        log::debug!("Failed to send metrics: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.003);
+        histogram.observe(60.0);
+        assert_eq!(histogram.counts[1], 2);
+        assert_eq!(histogram.over, 1);
+        assert!((histogram.sum - 60.006).abs() < 1e-9);
+    }
+
+    #[test]
+    fn labels_escaped() {
+        assert_eq!(
+            labels(&[("plugin", "time"), ("command", "a\"b\\c")]),
+            r#"{plugin="time",command="a\"b\\c"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let get = |path: &'static str| async move {
+            let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+            client
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let (response, _) = tokio::join!(get("/metrics"), async {
+            respond(listener.accept().await.unwrap().0).await
+        });
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE butler_reconnects_total counter"));
+        let (response, _) = tokio::join!(get("/"), async {
+            respond(listener.accept().await.unwrap().0).await
+        });
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn text_format() {
+        // Other tests count too, so only look for what this one adds
+        received();
+        handled("metrics-test", Some("ping"), Duration::from_millis(20));
+        handled("metrics-test", None, Duration::from_secs(40));
+        fetched("metrics-test", false);
+        let text = render();
+        for line in [
+            "# TYPE butler_messages_received_total counter",
+            r#"butler_commands_total{plugin="metrics-test",command="ping"} 1"#,
+            r#"butler_fetches_total{source="metrics-test",result="error"} 1"#,
+            r#"butler_handler_seconds_bucket{plugin="metrics-test",le="0.01"} 0"#,
+            r#"butler_handler_seconds_bucket{plugin="metrics-test",le="0.05"} 1"#,
+            r#"butler_handler_seconds_bucket{plugin="metrics-test",le="30"} 1"#,
+            r#"butler_handler_seconds_bucket{plugin="metrics-test",le="+Inf"} 2"#,
+            r#"butler_handler_seconds_count{plugin="metrics-test"} 2"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+    }
+}
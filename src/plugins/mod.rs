@@ -1,16 +1,127 @@
 use async_trait::async_trait;
 use irc::client::prelude::*;
-use unicode_segmentation::UnicodeSegmentation;
 
-pub trait Handler: help::Help {
-    fn handle(&self, client: &Client, msg: &Message);
-}
-pub trait MutableHandler: help::Help {
-    fn handle(&mut self, client: &Client, msg: &Message);
+/// Every plugin implements this. Plugins do not share a lock: the dispatcher gives each one its
+/// own task, so a plugin waiting on some upstream only delays its own replies. Whatever a plugin
+/// sends goes through the `outbound::Outbound` queue.
+#[async_trait(?Send)]
+pub trait Plugin: help::Help {
+    /// The `!commands` this plugin answers to, see `router`. These also end up in `!help`.
+    fn commands(&self) -> Vec<router::CommandSpec> {
+        vec![]
+    }
+
+    /// Called when someone used one of our commands. Gets called before `handle` sees the same
+    /// message.
+    async fn command(
+        &mut self,
+        _outbound: &outbound::Outbound,
+        _msg: &Message,
+        _invocation: &router::Invocation,
+    ) {
+    }
+
+    /// Called for every message the plugin is allowed to see, commands or not. For the plugins
+    /// that want to keep track of things or have triggers that are not commands.
+    async fn handle(&mut self, _outbound: &outbound::Outbound, _msg: &Message) {}
+
+    /// Called once when the plugin starts, with where to add its jobs, see `scheduler`.
+    fn schedule(&mut self, _scheduler: scheduler::Scheduler) {}
+
+    /// Called when one of the jobs the plugin added is due.
+    async fn run_job(&mut self, _outbound: &outbound::Outbound, _job: &str) {}
 }
-#[async_trait]
-pub trait AsyncMutableHandler: help::Help {
-    async fn handle(&mut self, client: &Client, msg: &Message);
+
+/// Builds the plugins plugins.toml enables. Some of these start fetching in the background, so
+/// this has to run within a `LocalSet`. Plugins talk to each other over `bus`. Admin commands that
+/// need main to do something go out through `admin_requests`.
+pub async fn build(
+    irc_config: &Config,
+    plugin_config: &config::Config,
+    storage: &storage::Storage,
+    http: &http::Http,
+    bus: &bus::Bus,
+    admin_requests: tokio::sync::mpsc::UnboundedSender<admin::AdminRequest>,
+) -> Vec<Box<dyn Plugin>> {
+    let mut help_handler = help::HelpHandler::new();
+    let mut plugins: Vec<Box<dyn Plugin>> = vec![];
+    if plugin_config.plugin("time").enabled {
+        let time_handler = time::TimeHandler::new();
+        help_handler.add_help(&time_handler);
+        plugins.push(Box::new(time_handler));
+    }
+    if plugin_config.plugin("simple_reply").enabled {
+        let simple_reply_handler = simple_reply::SimpleReplyHandler::new(plugin_config);
+        help_handler.add_help(&simple_reply_handler);
+        plugins.push(Box::new(simple_reply_handler));
+    }
+    if plugin_config.plugin("nickinternal").enabled {
+        let nickname_handler = nickname::NicknameHandler::new(irc_config);
+        help_handler.add_help(&nickname_handler);
+        plugins.push(Box::new(nickname_handler));
+    }
+    if plugin_config.plugin("calc").enabled {
+        let calc_handler = calc::CalcHandler::new();
+        help_handler.add_help(&calc_handler);
+        plugins.push(Box::new(calc_handler));
+    }
+    if plugin_config.plugin("seen").enabled {
+        let last_seen_handler = lastseen::LastSeenHandler::new(storage, bus);
+        help_handler.add_help(&last_seen_handler);
+        plugins.push(Box::new(last_seen_handler));
+    }
+    if plugin_config.plugin("elo").enabled {
+        let elo_handler = elo::EloHandler::new(http, &plugin_config.cache);
+        help_handler.add_help(&elo_handler);
+        plugins.push(Box::new(elo_handler));
+    }
+    if plugin_config.plugin("league_ranking").enabled {
+        let ranking_handler = leagueranking::LeagueRankingHandler::new(plugin_config, http);
+        help_handler.add_help(&ranking_handler);
+        plugins.push(Box::new(ranking_handler));
+    }
+    if plugin_config.plugin("strava").enabled {
+        let strava_handler = strava::StravaHandler::new(plugin_config, storage, http, bus);
+        help_handler.add_help(&strava_handler);
+        plugins.push(Box::new(strava_handler));
+    }
+    if plugin_config.plugin("untappd").enabled {
+        match untappd::UntappdHandler::new(irc_config, http) {
+            Some(untappd_handler) => {
+                help_handler.add_help(&untappd_handler);
+                plugins.push(Box::new(untappd_handler));
+            }
+            None => {
+                log::warn!("No untappd credentials in bot.toml, not loading the untappd plugin")
+            }
+        }
+    }
+    if plugin_config.plugin("games").enabled {
+        let games_handler = games::GamesHandler::new(http, &plugin_config.cache, bus);
+        help_handler.add_help(&games_handler);
+        plugins.push(Box::new(games_handler));
+    }
+    if plugin_config.plugin("3rd").enabled {
+        let third_place_handler =
+            thirdplace::ThirdPlaceHandler::new(http, &plugin_config.cache).await;
+        help_handler.add_help(&third_place_handler);
+        plugins.push(Box::new(third_place_handler));
+    }
+    if plugin_config.plugin("cache").enabled {
+        let cache_plugin = cache::CachePlugin::new();
+        help_handler.add_help(&cache_plugin);
+        plugins.push(Box::new(cache_plugin));
+    }
+    if plugin_config.plugin("admin").enabled {
+        let admin_plugin = admin::AdminPlugin::new(irc_config, storage, admin_requests);
+        help_handler.add_help(&admin_plugin);
+        plugins.push(Box::new(admin_plugin));
+    }
+    // help_handler last, it needs to have seen all the others
+    if plugin_config.plugin("help").enabled {
+        plugins.push(Box::new(help_handler));
+    }
+    plugins
 }
 
 pub fn print_msg(msg: &Message) {
@@ -20,39 +131,42 @@ pub fn print_msg(msg: &Message) {
     }
 }
 
-fn print_sent_privmsg(msg: &str) {
-    println!("SENT: {}", msg);
-}
-
-/// Sends a message to a given target. If the message is longer than a certain length, the message
-/// is split up (unicode safe) and individual messages are sent separately.
-///
-/// TODO: Delay between messages and/or maximum number of messages. (Global delay eventually?)
-///
-/// TODO: Length is currently hardcoded, ideally this bases itself on what the IRC server can
-/// handle.
-fn send_privmsg(client: &irc::client::Client, target: &str, message: &str) {
-    // If there is no need to split up, just send immediately
-    if message.len() < 400 {
-        match client.send_privmsg(target, message) {
-            Ok(_) => print_sent_privmsg(message),
-            Err(e) => eprintln!("Error sending message {}. {}", message, e),
-        }
-    } else {
-        // Otherwise, split at safe points and loop over
-        let message: Vec<_> = message.graphemes(true).collect();
-        for chunk in message.chunks(400) {
-            let to_send: String = chunk.concat();
-            match client.send_privmsg(target, &to_send) {
-                Ok(_) => print_sent_privmsg(&to_send),
-                Err(e) => eprintln!("Error sending message {}. {}", &to_send, e),
-            }
-        }
-    }
-}
+pub mod admin;
 
 pub mod alias;
 
+pub mod bus;
+
+pub mod cache;
+
+pub mod caps;
+
+pub mod check;
+
+pub mod clock;
+
+pub mod connection;
+
+pub mod console;
+
+pub mod dispatch;
+
+pub mod http;
+
+pub mod metrics;
+
+pub mod outbound;
+
+pub mod ratelimit;
+
+pub mod router;
+
+pub mod sasl;
+
+pub mod scheduler;
+
+pub mod storage;
+
 pub mod config;
 
 pub mod simple_reply;
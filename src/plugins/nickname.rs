@@ -1,11 +1,16 @@
+use super::outbound::Outbound;
+use super::scheduler::{Job, Scheduler};
+use async_trait::async_trait;
 use irc::client::prelude::*;
-use std::time::{Duration, Instant};
+
+/// How often we try to get our nick back, if someone else has it.
+const RETAKE_MINUTES: i64 = 5;
 
 pub struct NicknameHandler {
     nick: Option<String>,
+    /// The nick we are currently using, as far as we can tell from the server's messages.
+    current_nick: Option<String>,
     nickserv_password: Option<String>,
-    last_attempt: Instant,
-    waiting_time: Duration,
 }
 
 impl NicknameHandler {
@@ -14,27 +19,33 @@ impl NicknameHandler {
         let nickserv_password = config.nick_password.as_ref().cloned();
         NicknameHandler {
             nick,
+            current_nick: None,
             nickserv_password,
-            last_attempt: Instant::now(),
-            waiting_time: Duration::new(5 * 60, 0),
         }
     }
-    fn is_it_time(&self) -> bool {
-        let now = Instant::now();
-        let diff = now - self.last_attempt;
-        diff > self.waiting_time
-    }
-    fn reset_time(&mut self) {
-        self.last_attempt = Instant::now();
+    /// The server tells us our nick in the welcome message and whenever it changes.
+    fn track_nick(&mut self, msg: &Message) {
+        match msg.command {
+            Command::Response(Response::RPL_WELCOME, ref args) => {
+                self.current_nick = args.first().cloned();
+            }
+            Command::NICK(ref new_nick)
+                if self.current_nick.is_some()
+                    && msg.source_nickname() == self.current_nick.as_deref() =>
+            {
+                self.current_nick = Some(new_nick.to_owned());
+            }
+            _ => {}
+        }
     }
-    fn retake_nick(&self, client: &Client) {
+    fn retake_nick(&self, outbound: &Outbound) {
         if let Some(ref nick) = self.nick {
-            if client.current_nickname() != nick {
-                client.send(Command::NICK(nick.to_string())).unwrap();
+            if self.current_nick.as_ref() != Some(nick) {
+                outbound.send(Command::NICK(nick.to_string()));
             }
         }
     }
-    fn handle_nickserv(&self, client: &Client, msg: &Message) {
+    fn handle_nickserv(&self, outbound: &Outbound, msg: &Message) {
         // NOTE The irc library we use already has some logic surrounding logging in. See fn
         // `ClientState::send_nick_password(&self)`. That function gets called automatically at the
         // end of the MOTD (or when the notice is sent that there is no MOTD). Effectively, this
@@ -44,25 +55,32 @@ impl NicknameHandler {
         if let Some(ref pass) = self.nickserv_password {
             if let Command::NICKSERV(ref text) = msg.command {
                 if text.contains(&"This nickname is registered.".to_owned()) {
-                    client
-                        .send(Command::NICKSERV(vec![
-                            "IDENTIFY".to_string(),
-                            pass.to_owned(),
-                        ]))
-                        .unwrap();
+                    outbound.send(Command::NICKSERV(vec![
+                        "IDENTIFY".to_string(),
+                        pass.to_owned(),
+                    ]));
                 }
             }
         }
     }
 }
 
-impl super::MutableHandler for NicknameHandler {
-    fn handle(&mut self, client: &Client, msg: &Message) {
-        if self.is_it_time() {
-            self.reset_time();
-            self.retake_nick(client);
-        }
-        self.handle_nickserv(client, msg);
+#[async_trait(?Send)]
+impl super::Plugin for NicknameHandler {
+    async fn handle(&mut self, outbound: &Outbound, msg: &Message) {
+        self.track_nick(msg);
+        self.handle_nickserv(outbound, msg);
+    }
+
+    fn schedule(&mut self, scheduler: Scheduler) {
+        scheduler.add(Job::every(
+            "retake_nick",
+            chrono::Duration::minutes(RETAKE_MINUTES),
+        ));
+    }
+
+    async fn run_job(&mut self, outbound: &Outbound, _job: &str) {
+        self.retake_nick(outbound);
     }
 }
 
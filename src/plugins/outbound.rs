@@ -0,0 +1,685 @@
+//! Everything plugins say goes through here. Lines are queued and sent out at a steady pace (a
+//! token bucket, so a few lines can go out at once), and long messages are split so every line
+//! fits the 512 bytes IRC allows. That limit counts the `:nick!user@host` prefix the server adds
+//! when it passes our message on, so we keep track of what our own prefix looks like.
+//!
+//! The queue outlives the connection. After a reconnect it is pointed at the new client, in
+//! between anything plugins say is dropped. With `--console` it is pointed at the console instead
+//! of a client.
+//!
+//! Replies in a channel say what they answer. When the server lets us tag messages (see `caps`)
+//! they carry `+draft/reply` with the id of the message that triggered them, so clients can thread
+//! them. Otherwise they start with `reply_prefix`, "nick: " by default.
+//!
+//! Plugins only ever see an `Outbound`, never the client. For tests, `Outbound::recording` gives
+//! one that sends straight to a `Recorder`, so a test can hand a plugin a message and check exactly
+//! what it answered.
+
+use super::caps;
+use super::config::PluginConfig;
+use super::metrics;
+use irc::client::prelude::*;
+use irc::proto::Tag;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Including the trailing CRLF.
+const MAX_LINE_BYTES: usize = 512;
+/// Until we have seen our own host, assume the longest one a server would give us.
+const MAX_HOST_BYTES: usize = 63;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// How many lines can go out back to back.
+    pub burst: u32,
+    /// How quickly we get to send lines again once the burst is used up.
+    pub lines_per_second: f64,
+    /// A single reply never gets more lines than this, the rest is dropped.
+    pub max_lines_per_reply: usize,
+    /// Goes in front of replies in a channel when the server does not do message tags. `{nick}`
+    /// is whoever we are answering. Empty to leave replies as they are.
+    pub reply_prefix: String,
+}
+
+/// Where a plugin's replies to something said in a channel go. Private messages always get a
+/// private reply.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyMode {
+    /// Back in the channel
+    #[default]
+    Channel,
+    /// A private message to whoever asked
+    Private,
+    /// A notice to whoever asked
+    Notice,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            lines_per_second: 0.5,
+            max_lines_per_reply: 5,
+            reply_prefix: String::from("{nick}: "),
+        }
+    }
+}
+
+impl OutboundConfig {
+    /// For `Config::problems`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.burst == 0 {
+            problems.push(String::from("outbound.burst is 0, it has to be at least 1"));
+        }
+        if !valid_rate(self.lines_per_second) {
+            problems.push(format!(
+                "outbound.lines_per_second is {}, it has to be more than 0",
+                self.lines_per_second
+            ));
+        }
+        problems
+    }
+}
+
+fn valid_rate(per_second: f64) -> bool {
+    per_second.is_finite() && per_second > 0.0
+}
+
+/// How the server sees us. The server puts `:nick!user@host ` in front of every line we send.
+#[derive(Debug, Clone)]
+struct Identity {
+    nick: String,
+    user: String,
+    host: Option<String>,
+}
+
+impl Identity {
+    fn prefix_len(&self) -> usize {
+        let host_len = self.host.as_ref().map_or(MAX_HOST_BYTES, |host| host.len());
+        // :nick!user@host followed by a space
+        1 + self.nick.len() + 1 + self.user.len() + 1 + host_len + 1
+    }
+
+    /// How many bytes of text fit in one `COMMAND target :text` line.
+    fn budget(&self, command: &str, target: &str) -> usize {
+        let overhead = self.prefix_len() + command.len() + 1 + target.len() + 2 + 2;
+        MAX_LINE_BYTES.saturating_sub(overhead)
+    }
+}
+
+/// Where to reply to a message: the channel it was said in, or whoever sent it if it was sent to us
+/// directly.
+pub fn reply_target(msg: &Message) -> Option<&str> {
+    msg.response_target()
+}
+
+/// Takes the commands we send. The queue is one, and so is whatever the queue feeds: the client,
+/// or the console.
+pub trait Outbox {
+    fn send(&self, message: Message) -> Result<(), String>;
+
+    /// Whether lines have to go out at the pace set in `[outbound]`. Only servers mind a flood.
+    fn paced(&self) -> bool {
+        true
+    }
+}
+
+impl Outbox for Sender {
+    fn send(&self, message: Message) -> Result<(), String> {
+        let text = match message.command {
+            Command::PRIVMSG(_, ref text) | Command::NOTICE(_, ref text) => Some(text.clone()),
+            _ => None,
+        };
+        Sender::send(self, message).map_err(|e| e.to_string())?;
+        if let Some(text) = text {
+            println!("SENT: {}", text);
+        }
+        Ok(())
+    }
+}
+
+/// For `--console`, which reads from the other end.
+impl Outbox for mpsc::UnboundedSender<Message> {
+    fn send(&self, message: Message) -> Result<(), String> {
+        mpsc::UnboundedSender::send(self, message).map_err(|_| String::from("Console is gone"))
+    }
+
+    fn paced(&self) -> bool {
+        false
+    }
+}
+
+/// What `Outbound` sends to normally, the task in `Outbound::run` takes it from there.
+struct Queue(mpsc::UnboundedSender<Message>);
+
+impl Outbox for Queue {
+    fn send(&self, message: Message) -> Result<(), String> {
+        self.0
+            .send(message)
+            .map_err(|_| String::from("Outbound queue is gone, cannot send anything anymore"))
+    }
+}
+
+/// Keeps everything sent to it, for tests. Clones share what they keep.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    sent: Rc<RefCell<Vec<Message>>>,
+}
+
+impl Recorder {
+    /// Everything sent since the last call, as `COMMAND target text`. Tags, if any, go in front
+    /// as `@name=value`.
+    pub fn take(&self) -> Vec<String> {
+        self.sent
+            .borrow_mut()
+            .drain(..)
+            .map(|message| {
+                let tags: String = message
+                    .tags
+                    .iter()
+                    .flatten()
+                    .map(|Tag(name, value)| match value {
+                        Some(value) => format!("@{}={} ", name, value),
+                        None => format!("@{} ", name),
+                    })
+                    .collect();
+                let command = match message.command {
+                    Command::PRIVMSG(target, text) => format!("PRIVMSG {} {}", target, text),
+                    Command::NOTICE(target, text) => format!("NOTICE {} {}", target, text),
+                    other => String::from(Message::from(other).to_string().trim_end()),
+                };
+                tags + &command
+            })
+            .collect()
+    }
+}
+
+impl Outbox for Recorder {
+    fn send(&self, message: Message) -> Result<(), String> {
+        self.sent.borrow_mut().push(message);
+        Ok(())
+    }
+}
+
+/// Where the queue sends to, if anywhere.
+type Connection = Option<Arc<dyn Outbox + Send + Sync>>;
+
+/// Cheap to clone, every clone feeds the same queue. Each plugin gets its own clone that knows
+/// where that plugin's replies should go.
+#[derive(Clone)]
+pub struct Outbound {
+    queue: Rc<dyn Outbox>,
+    /// The client we are currently sending through, if we are connected
+    connection: Rc<watch::Sender<Connection>>,
+    identity: Rc<RefCell<Identity>>,
+    /// Whether the current connection lets us tag messages
+    message_tags: Rc<Cell<bool>>,
+    max_lines_per_reply: usize,
+    reply_prefix: String,
+    reply_mode: ReplyMode,
+    redirect_over_lines: usize,
+}
+
+impl Outbound {
+    /// Starts the task that does the actual sending. Nothing goes out until `connect`.
+    pub fn new(irc_config: &Config, config: &OutboundConfig) -> Self {
+        let (queue, incoming) = mpsc::unbounded_channel();
+        let (connection, sender) = watch::channel(None);
+        let bucket = TokenBucket::new(config.burst, config.lines_per_second, Instant::now());
+        tokio::spawn(Outbound::run(sender, incoming, bucket));
+        let identity = Identity {
+            nick: irc_config.nickname().unwrap_or_default().to_owned(),
+            user: irc_config.username().to_owned(),
+            host: None,
+        };
+        Self {
+            queue: Rc::new(Queue(queue)),
+            connection: Rc::new(connection),
+            identity: Rc::new(RefCell::new(identity)),
+            message_tags: Rc::new(Cell::new(false)),
+            max_lines_per_reply: config.max_lines_per_reply,
+            reply_prefix: config.reply_prefix.clone(),
+            reply_mode: ReplyMode::Channel,
+            redirect_over_lines: 0,
+        }
+    }
+
+    /// Sends straight to the returned `Recorder`, without queue or pacing. As the bot
+    /// `butler!rusty@example.org`, without a `reply_prefix`. For tests.
+    pub fn recording() -> (Self, Recorder) {
+        let recorder = Recorder::default();
+        let config = OutboundConfig::default();
+        let identity = Identity {
+            nick: String::from("butler"),
+            user: String::from("rusty"),
+            host: Some(String::from("example.org")),
+        };
+        let outbound = Self {
+            queue: Rc::new(recorder.clone()),
+            connection: Rc::new(watch::channel(None).0),
+            identity: Rc::new(RefCell::new(identity)),
+            message_tags: Rc::new(Cell::new(false)),
+            max_lines_per_reply: config.max_lines_per_reply,
+            reply_prefix: String::new(),
+            reply_mode: ReplyMode::Channel,
+            redirect_over_lines: 0,
+        };
+        (outbound, recorder)
+    }
+
+    /// The same queue, but replying the way the plugin is configured to.
+    pub fn for_plugin(&self, config: &PluginConfig) -> Self {
+        Self {
+            reply_mode: config.reply,
+            redirect_over_lines: config.redirect_over_lines,
+            ..self.clone()
+        }
+    }
+
+    /// Start sending through a (new) client, or the console.
+    pub fn connect(&self, outbox: impl Outbox + Send + Sync + 'static) {
+        self.connection.send_replace(Some(Arc::new(outbox)));
+    }
+
+    /// The connection is gone, drop what comes in until the next `connect`.
+    pub fn disconnect(&self) {
+        self.connection.send_replace(None);
+        self.message_tags.set(false);
+    }
+
+    /// Whether the server agreed to `message-tags`, see `caps`.
+    pub fn set_message_tags(&self, enabled: bool) {
+        self.message_tags.set(enabled);
+    }
+
+    async fn run(
+        connection: watch::Receiver<Connection>,
+        mut incoming: mpsc::UnboundedReceiver<Message>,
+        mut bucket: TokenBucket,
+    ) {
+        while let Some(message) = incoming.recv().await {
+            let outbox = match *connection.borrow() {
+                Some(ref outbox) => outbox.clone(),
+                None => {
+                    log::warn!("Not connected, dropping {:?}", message.command);
+                    continue;
+                }
+            };
+            if outbox.paced() {
+                while let Some(wait) = bucket.take(Instant::now()) {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+            match outbox.send(message) {
+                Ok(()) => metrics::sent(),
+                Err(e) => eprintln!("Error sending message. {}", e),
+            }
+        }
+        log::debug!("Outbound queue closed");
+    }
+
+    /// Keeps track of our nick and host, so we know how long our prefix is. Needs to see every
+    /// incoming message.
+    pub fn observe(&self, msg: &Message) {
+        let mut identity = self.identity.borrow_mut();
+        match msg.command {
+            Command::Response(Response::RPL_WELCOME, ref args) => {
+                if let Some(nick) = args.first() {
+                    identity.nick = nick.to_owned();
+                }
+            }
+            Command::NICK(ref new_nick) if msg.source_nickname() == Some(&identity.nick) => {
+                identity.nick = new_nick.to_owned();
+            }
+            Command::JOIN(_, _, _) => {
+                if let Some(Prefix::Nickname(ref nick, ref user, ref host)) = msg.prefix {
+                    if *nick == identity.nick {
+                        identity.user = user.to_owned();
+                        identity.host = Some(host.to_owned());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Replies to the given message, see `reply_target`. Replies to channel messages can be
+    /// redirected to the person asking, depending on the plugin's settings.
+    pub fn reply(&self, msg: &Message, message: &str) {
+        let target = match reply_target(msg) {
+            Some(target) => target,
+            None => {
+                log::debug!("Nobody to reply to for {:?}", msg.command);
+                return;
+            }
+        };
+        let (tags, prefix) = if target.is_channel_name() {
+            self.threading(msg)
+        } else {
+            (None, String::new())
+        };
+        let lines = self.lines("PRIVMSG", target, &(prefix + message));
+        let redirect = self.reply_mode != ReplyMode::Channel
+            && target.is_channel_name()
+            && lines.len() > self.redirect_over_lines;
+        match msg.source_nickname() {
+            Some(nick) if redirect && self.reply_mode == ReplyMode::Notice => {
+                for line in self.lines("NOTICE", nick, message) {
+                    self.send(Command::NOTICE(nick.to_owned(), line));
+                }
+            }
+            Some(nick) if redirect => self.privmsg(nick, message),
+            _ => {
+                for line in lines {
+                    self.send_message(Message {
+                        tags: tags.clone(),
+                        prefix: None,
+                        command: Command::PRIVMSG(target.to_owned(), line),
+                    });
+                }
+            }
+        }
+    }
+
+    /// What marks a reply to `msg` as one: a tag pointing at it, or failing that, a prefix for the
+    /// text.
+    fn threading(&self, msg: &Message) -> (Option<Vec<Tag>>, String) {
+        if self.message_tags.get() {
+            if let Some(msgid) = caps::tag(msg, "msgid") {
+                let reply = Tag(String::from("+draft/reply"), Some(msgid.to_owned()));
+                return (Some(vec![reply]), String::new());
+            }
+        }
+        let prefix = match msg.source_nickname() {
+            Some(nick) => self.reply_prefix.replace("{nick}", nick),
+            None => String::new(),
+        };
+        (None, prefix)
+    }
+
+    /// Queues a message, split over as many lines as needed (up to the configured maximum).
+    pub fn privmsg(&self, target: &str, message: &str) {
+        for line in self.lines("PRIVMSG", target, message) {
+            self.send(Command::PRIVMSG(target.to_owned(), line));
+        }
+    }
+
+    fn lines(&self, command: &str, target: &str, message: &str) -> Vec<String> {
+        let budget = self.identity.borrow().budget(command, target);
+        let mut lines = split_message(message, budget);
+        if lines.len() > self.max_lines_per_reply {
+            log::debug!(
+                "Dropping {} lines of a reply to {}",
+                lines.len() - self.max_lines_per_reply,
+                target
+            );
+            lines.truncate(self.max_lines_per_reply);
+        }
+        lines
+    }
+
+    /// Queues any other command. Not split up, so make sure it fits.
+    pub fn send(&self, command: Command) {
+        self.send_message(Message::from(command));
+    }
+
+    fn send_message(&self, message: Message) {
+        if let Err(e) = self.queue.send(message) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+/// Splits the message into lines of at most `budget` bytes. Prefers to split on spaces, otherwise
+/// splits between graphemes. Newlines always start a new line.
+fn split_message(message: &str, budget: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for mut rest in message.lines() {
+        while rest.len() > budget {
+            // Longest run of whole graphemes that fits
+            let mut fits = 0;
+            for (idx, grapheme) in rest.grapheme_indices(true) {
+                if idx + grapheme.len() > budget {
+                    break;
+                }
+                fits = idx + grapheme.len();
+            }
+            if fits == 0 {
+                // Not even one grapheme fits, nothing sensible left to do
+                break;
+            }
+            let cut = match rest[..fits].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => fits,
+            };
+            lines.push(rest[..cut].to_owned());
+            rest = rest[cut..].trim_start_matches(' ');
+        }
+        if !rest.is_empty() {
+            lines.push(rest.to_owned());
+        }
+    }
+    lines
+}
+
+/// Starts out full. Every line takes a token, tokens come back at a steady rate.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: u32, per_second: f64, now: Instant) -> Self {
+        let capacity = f64::from(burst.max(1));
+        // `Config::problems` keeps these out, but 0 would mean waiting forever for the next line
+        let per_second = if valid_rate(per_second) {
+            per_second
+        } else {
+            OutboundConfig::default().lines_per_second
+        };
+        Self {
+            capacity,
+            tokens: capacity,
+            per_second,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token if there is one. Otherwise, how long until there is.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_message_untouched() {
+        assert_eq!(split_message("hello there", 100), vec!["hello there"]);
+        assert!(split_message("", 100).is_empty());
+    }
+
+    #[test]
+    fn split_on_words() {
+        assert_eq!(
+            split_message("one two three four", 9),
+            vec!["one two", "three", "four"]
+        );
+    }
+
+    #[test]
+    fn split_long_words_and_unicode() {
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        // Each flag is 8 bytes, and should never be cut in half
+        assert_eq!(split_message("🇧🇪🇧🇪🇧🇪", 10), vec!["🇧🇪", "🇧🇪", "🇧🇪"]);
+    }
+
+    #[test]
+    fn split_on_newlines() {
+        assert_eq!(split_message("one\ntwo", 100), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn line_budget() {
+        let identity = Identity {
+            nick: String::from("butler"),
+            user: String::from("rusty"),
+            host: Some(String::from("example.org")),
+        };
+        // ":butler!rusty@example.org PRIVMSG #chan :" + CRLF
+        assert_eq!(identity.budget("PRIVMSG", "#chan"), 512 - 26 - 15 - 2);
+        let unknown_host = Identity {
+            host: None,
+            ..identity
+        };
+        assert!(unknown_host.budget("PRIVMSG", "#chan") < 512 - 26 - 15 - 2);
+    }
+
+    fn test_outbound(reply_mode: ReplyMode) -> (Outbound, Recorder) {
+        let (outbound, recorder) = Outbound::recording();
+        let config = PluginConfig {
+            reply: reply_mode,
+            redirect_over_lines: 1,
+            ..Default::default()
+        };
+        (outbound.for_plugin(&config), recorder)
+    }
+
+    #[test]
+    fn reply_to_channel_and_query() {
+        let (outbound, recorder) = test_outbound(ReplyMode::Channel);
+        let in_channel: Message = ":ward!ward@host PRIVMSG #chan :!time".parse().unwrap();
+        outbound.reply(&in_channel, "now");
+        let in_query: Message = ":ward!ward@host PRIVMSG butler :!time".parse().unwrap();
+        outbound.reply(&in_query, "now");
+        assert_eq!(
+            recorder.take(),
+            vec!["PRIVMSG #chan now", "PRIVMSG ward now"]
+        );
+    }
+
+    #[test]
+    fn threaded_replies() {
+        let (mut outbound, recorder) = Outbound::recording();
+        outbound.reply_prefix = String::from("{nick}: ");
+        let tagged: Message = "@msgid=abc123 :ward!ward@host PRIVMSG #chan :!elo"
+            .parse()
+            .unwrap();
+        let untagged: Message = ":ward!ward@host PRIVMSG #chan :!elo".parse().unwrap();
+        let in_query: Message = ":ward!ward@host PRIVMSG butler :!elo".parse().unwrap();
+
+        outbound.reply(&tagged, "one\ntwo");
+        outbound.reply(&in_query, "three");
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "PRIVMSG #chan ward: one",
+                "PRIVMSG #chan two",
+                "PRIVMSG ward three"
+            ]
+        );
+
+        outbound.set_message_tags(true);
+        outbound.reply(&tagged, "one\ntwo");
+        outbound.reply(&untagged, "three");
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "@+draft/reply=abc123 PRIVMSG #chan one",
+                "@+draft/reply=abc123 PRIVMSG #chan two",
+                "PRIVMSG #chan ward: three"
+            ]
+        );
+        outbound.disconnect();
+        outbound.reply(&tagged, "four");
+        assert_eq!(recorder.take(), vec!["PRIVMSG #chan ward: four"]);
+    }
+
+    #[test]
+    fn redirect_long_replies() {
+        let (outbound, recorder) = test_outbound(ReplyMode::Notice);
+        let msg: Message = ":ward!ward@host PRIVMSG #chan :!games".parse().unwrap();
+        outbound.reply(&msg, "short");
+        outbound.reply(&msg, "long\nreply");
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "PRIVMSG #chan short",
+                "NOTICE ward long",
+                "NOTICE ward reply"
+            ]
+        );
+        let (outbound, recorder) = test_outbound(ReplyMode::Private);
+        outbound.reply(&msg, "long\nreply");
+        assert_eq!(
+            recorder.take(),
+            vec!["PRIVMSG ward long", "PRIVMSG ward reply"]
+        );
+    }
+
+    #[test]
+    fn long_reply_split_and_capped() {
+        let (outbound, recorder) = Outbound::recording();
+        let msg: Message = ":ward!ward@host PRIVMSG #chan :!games".parse().unwrap();
+        outbound.reply(&msg, &"word ".repeat(200));
+        let budget = 512 - 26 - 15 - 2;
+        let sent = recorder.take();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].len() <= "PRIVMSG #chan ".len() + budget);
+        assert!(sent[0].ends_with("word"));
+        outbound.reply(&msg, &"line\n".repeat(10));
+        assert_eq!(recorder.take().len(), 5);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 0.5, start);
+        assert_eq!(bucket.take(start), None);
+        assert_eq!(bucket.take(start), None);
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(2)));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(bucket.take(later), None);
+        assert!(bucket.take(later).is_some());
+    }
+
+    #[test]
+    fn no_pace() {
+        let start = Instant::now();
+        for per_second in [0.0, -1.0, f64::NAN] {
+            let mut bucket = TokenBucket::new(0, per_second, start);
+            assert_eq!(bucket.take(start), None);
+            assert_eq!(bucket.take(start), Some(Duration::from_secs(2)));
+        }
+        let config = OutboundConfig {
+            burst: 0,
+            lines_per_second: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(config.problems().len(), 2);
+        assert!(OutboundConfig::default().problems().is_empty());
+    }
+}
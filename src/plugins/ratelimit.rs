@@ -0,0 +1,325 @@
+//! Keeps people from hammering commands, and through those commands the sites our plugins fetch
+//! from. Every user and every channel gets a budget of commands per time window, and commands
+//! can have a cooldown on top of that. Whoever goes over gets ignored, and (if so configured) told
+//! once to slow down.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Commands one user can use per `window_secs`
+    pub user_commands: usize,
+    /// Commands that can be used in one channel per `window_secs`
+    pub channel_commands: usize,
+    pub window_secs: u64,
+    /// Seconds before a command can be used again in the same channel, by command name (no `!`)
+    pub cooldowns: HashMap<String, u64>,
+    pub response: LimitResponse,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            user_commands: 5,
+            channel_commands: 15,
+            window_secs: 60,
+            cooldowns: HashMap::new(),
+            response: LimitResponse::Warn,
+        }
+    }
+}
+
+/// What to do when someone goes over their budget.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitResponse {
+    /// Just ignore them
+    Silent,
+    /// Tell them to slow down, once, then ignore them
+    Warn,
+}
+
+impl RateLimitConfig {
+    /// For `Config::problems`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, max) in [
+            ("user_commands", self.user_commands),
+            ("channel_commands", self.channel_commands),
+        ] {
+            if max == 0 {
+                problems.push(format!(
+                    "ratelimit.{} is 0, nobody could use any command",
+                    name
+                ));
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// Ignore the command
+    Limited,
+    /// Ignore the command, but tell them to slow down. They can try again after the duration.
+    Warn(Duration),
+}
+
+/// Timestamps of recent uses, oldest first.
+#[derive(Default)]
+struct Budget {
+    uses: VecDeque<Instant>,
+}
+
+impl Budget {
+    fn forget_before(&mut self, start: Instant) {
+        while self.uses.front().is_some_and(|&used| used < start) {
+            self.uses.pop_front();
+        }
+    }
+
+    /// None if there is room for one more use, otherwise how long until there is.
+    fn wait(&self, now: Instant, max: usize, window: Duration) -> Option<Duration> {
+        if self.uses.len() < max {
+            None
+        } else if max == 0 {
+            // `Config::problems` does not let this through, but never any room is what it means
+            Some(window)
+        } else {
+            // Full, so there is an oldest use. Room again once that one drops out of the window.
+            let oldest = self.uses[self.uses.len() - max];
+            Some(window.saturating_sub(now.saturating_duration_since(oldest)))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: HashMap<String, Budget>,
+    channels: HashMap<String, Budget>,
+    /// When a command was last used where, keyed on (command, channel)
+    last_used: HashMap<(String, String), Instant>,
+    /// Users we already told to slow down, until they get to use a command again
+    warned: HashMap<String, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Decides whether the user gets to use the command in the given place (a channel, or the
+    /// user's nick for private messages). Allowed commands count towards the budgets.
+    pub fn check(&mut self, now: Instant, user: &str, place: &str, command: &str) -> Verdict {
+        let window = Duration::from_secs(self.config.window_secs);
+        self.forget(now, window);
+
+        let user_key = user.to_lowercase();
+        let place_key = place.to_lowercase();
+        let cooldown_key = (command.to_owned(), place_key.clone());
+
+        let unused = Budget::default();
+        let user_wait = self.users.get(&user_key).unwrap_or(&unused).wait(
+            now,
+            self.config.user_commands,
+            window,
+        );
+        let channel_wait = self.channels.get(&place_key).unwrap_or(&unused).wait(
+            now,
+            self.config.channel_commands,
+            window,
+        );
+        let cooldown_wait = match (
+            self.config.cooldowns.get(command),
+            self.last_used.get(&cooldown_key),
+        ) {
+            (Some(&cooldown), Some(&last_used)) => Duration::from_secs(cooldown)
+                .checked_sub(now.saturating_duration_since(last_used))
+                .filter(|wait| !wait.is_zero()),
+            _ => None,
+        };
+
+        let wait = [user_wait, channel_wait, cooldown_wait]
+            .into_iter()
+            .flatten()
+            .max();
+        match wait {
+            None => {
+                self.users
+                    .entry(user_key.clone())
+                    .or_default()
+                    .uses
+                    .push_back(now);
+                self.channels
+                    .entry(place_key)
+                    .or_default()
+                    .uses
+                    .push_back(now);
+                self.last_used.insert(cooldown_key, now);
+                self.warned.remove(&user_key);
+                Verdict::Allowed
+            }
+            Some(wait) => {
+                log::debug!("Rate limited {} for !{} in {}", user, command, place);
+                if self.config.response == LimitResponse::Warn
+                    && !self.warned.contains_key(&user_key)
+                {
+                    self.warned.insert(user_key, now);
+                    Verdict::Warn(wait)
+                } else {
+                    Verdict::Limited
+                }
+            }
+        }
+    }
+
+    /// Drops whatever is too old to matter, so this does not grow forever.
+    fn forget(&mut self, now: Instant, window: Duration) {
+        let start = now.checked_sub(window).unwrap_or(now);
+        for budgets in [&mut self.users, &mut self.channels] {
+            budgets.retain(|_, budget| {
+                budget.forget_before(start);
+                !budget.uses.is_empty()
+            });
+        }
+        let longest_cooldown = self.config.cooldowns.values().max().copied().unwrap_or(0);
+        self.last_used.retain(|_, &mut used| {
+            now.saturating_duration_since(used) < Duration::from_secs(longest_cooldown)
+        });
+        self.warned.retain(|_, &mut warned| warned >= start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(cooldowns: &[(&str, u64)]) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            user_commands: 2,
+            channel_commands: 3,
+            window_secs: 60,
+            cooldowns: cooldowns
+                .iter()
+                .map(|(command, secs)| (command.to_string(), *secs))
+                .collect(),
+            response: LimitResponse::Warn,
+        })
+    }
+
+    #[test]
+    fn user_budget() {
+        let mut limiter = limiter(&[]);
+        let start = Instant::now();
+        assert_eq!(
+            limiter.check(start, "ward", "#chan", "elo"),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limiter.check(start, "ward", "#chan", "elo"),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limiter.check(start, "Ward", "#chan", "elo"),
+            Verdict::Warn(Duration::from_secs(60))
+        );
+        // Only told once
+        assert_eq!(
+            limiter.check(start, "ward", "#chan", "elo"),
+            Verdict::Limited
+        );
+        // Others can still go
+        assert_eq!(
+            limiter.check(start, "other", "#chan", "elo"),
+            Verdict::Allowed
+        );
+        // And after a while, so can we
+        let later = start + Duration::from_secs(61);
+        assert_eq!(
+            limiter.check(later, "ward", "#chan", "elo"),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn channel_budget() {
+        let mut limiter = limiter(&[]);
+        let start = Instant::now();
+        for user in ["a", "b", "c"] {
+            assert_eq!(
+                limiter.check(start, user, "#chan", "time"),
+                Verdict::Allowed
+            );
+        }
+        assert!(matches!(
+            limiter.check(start, "d", "#chan", "time"),
+            Verdict::Warn(_)
+        ));
+        assert_eq!(
+            limiter.check(start, "d", "#other", "time"),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut limiter = limiter(&[("strava", 30)]);
+        let start = Instant::now();
+        assert_eq!(
+            limiter.check(start, "a", "#chan", "strava"),
+            Verdict::Allowed
+        );
+        let soon = start + Duration::from_secs(10);
+        assert_eq!(
+            limiter.check(soon, "b", "#chan", "strava"),
+            Verdict::Warn(Duration::from_secs(20))
+        );
+        assert_eq!(
+            limiter.check(soon, "b", "#other", "strava"),
+            Verdict::Allowed
+        );
+        assert_eq!(limiter.check(soon, "b", "#chan", "games"), Verdict::Allowed);
+        let later = start + Duration::from_secs(30);
+        assert_eq!(
+            limiter.check(later, "c", "#chan", "strava"),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn zero_budget() {
+        let mut limiter = limiter(&[]);
+        limiter.config.user_commands = 0;
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert_ne!(
+                limiter.check(start, "ward", "#chan", "elo"),
+                Verdict::Allowed
+            );
+        }
+        assert_eq!(limiter.config.problems().len(), 1);
+        assert!(limiter.config.problems()[0].starts_with("ratelimit.user_commands"));
+        assert!(RateLimitConfig::default().problems().is_empty());
+    }
+
+    #[test]
+    fn silent() {
+        let mut limiter = limiter(&[]);
+        limiter.config.response = LimitResponse::Silent;
+        let start = Instant::now();
+        limiter.check(start, "ward", "#chan", "elo");
+        limiter.check(start, "ward", "#chan", "elo");
+        assert_eq!(
+            limiter.check(start, "ward", "#chan", "elo"),
+            Verdict::Limited
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Finds out which plugin a `!command` is meant for. Plugins declare their commands with a
+//! `CommandSpec` and the router takes care of matching the command name (or one of its aliases)
+//! and splitting up the arguments. Every command belongs to exactly one plugin, so one message
+//! never triggers two of them, and bad input gets the same usage reply no matter the plugin.
+
+use std::collections::HashMap;
+use std::fmt;
+
+const COMMAND_PREFIX: char = '!';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A single word
+    Word,
+    /// A single word that has to be a whole number
+    Number,
+    /// Everything that is left of the message. Only makes sense as the last argument.
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub struct Arg {
+    name: String,
+    kind: ArgKind,
+    optional: bool,
+}
+
+impl Arg {
+    pub fn word(name: &str) -> Self {
+        Self::new(name, ArgKind::Word)
+    }
+
+    pub fn number(name: &str) -> Self {
+        Self::new(name, ArgKind::Number)
+    }
+
+    pub fn text(name: &str) -> Self {
+        Self::new(name, ArgKind::Text)
+    }
+
+    fn new(name: &str, kind: ArgKind) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind,
+            optional: false,
+        }
+    }
+
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.optional {
+            write!(f, "[{}]", self.name)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+/// A command as a plugin declares it. The first name is the one the plugin gets to see in the
+/// `Invocation`, the others are aliases.
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    names: Vec<String>,
+    args: Vec<Arg>,
+    description: String,
+}
+
+impl CommandSpec {
+    /// The name goes without the `!`.
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            names: vec![name.to_lowercase()],
+            args: vec![],
+            description: description.to_owned(),
+        }
+    }
+
+    pub fn alias(mut self, name: &str) -> Self {
+        self.names.push(name.to_lowercase());
+        self
+    }
+
+    /// Arguments are matched in the order they are added. Once an argument is optional, all the
+    /// ones after it have to be too, and nothing can come after a text argument.
+    pub fn arg(mut self, arg: Arg) -> Self {
+        if let Some(last) = self.args.last() {
+            assert!(
+                last.kind != ArgKind::Text,
+                "!{}: no arguments can follow {}",
+                self.name(),
+                last.name
+            );
+            assert!(
+                arg.optional || !last.optional,
+                "!{}: {} cannot follow optional {}",
+                self.name(),
+                arg.name,
+                last.name
+            );
+        }
+        self.args.push(arg);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.names[0]
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Something like `!seen / !lastseen NICK`
+    pub fn usage(&self) -> String {
+        let mut usage = self
+            .names
+            .iter()
+            .map(|name| format!("{}{}", COMMAND_PREFIX, name))
+            .collect::<Vec<_>>()
+            .join(" / ");
+        for arg in &self.args {
+            usage.push(' ');
+            usage.push_str(&arg.to_string());
+        }
+        usage
+    }
+
+    /// Matches the arguments (everything after the command itself) against what we expect.
+    fn parse_args(&self, input: &str) -> Result<HashMap<String, String>, UsageError> {
+        let mut result = HashMap::new();
+        let mut rest = input.trim();
+        for arg in &self.args {
+            if rest.is_empty() {
+                if arg.optional {
+                    break;
+                }
+                return Err(self.usage_error(format!("{} is missing.", arg.name)));
+            }
+            let value = match arg.kind {
+                ArgKind::Text => std::mem::take(&mut rest),
+                ArgKind::Word | ArgKind::Number => {
+                    let (word, remainder) =
+                        rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    rest = remainder.trim_start();
+                    word
+                }
+            };
+            if arg.kind == ArgKind::Number && value.parse::<i64>().is_err() {
+                return Err(self.usage_error(format!("{} should be a number.", arg.name)));
+            }
+            result.insert(arg.name.clone(), value.to_owned());
+        }
+        if !rest.is_empty() {
+            return Err(self.usage_error(format!("Did not expect '{}'.", rest)));
+        }
+        Ok(result)
+    }
+
+    fn usage_error(&self, problem: String) -> UsageError {
+        UsageError {
+            command: self.name().to_owned(),
+            problem,
+            usage: self.usage(),
+        }
+    }
+}
+
+/// What a plugin gets when one of its commands is used.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    /// The main name of the command, even if an alias was used
+    pub command: String,
+    /// What was actually typed, lowercased and without the `!`
+    pub trigger: String,
+    args: HashMap<String, String>,
+}
+
+impl Invocation {
+    pub fn get(&self, arg: &str) -> Option<&str> {
+        self.args.get(arg).map(|value| value.as_str())
+    }
+
+    /// Only use this on `ArgKind::Number` arguments, the router already checked those.
+    pub fn number(&self, arg: &str) -> Option<i64> {
+        self.get(arg).and_then(|value| value.parse().ok())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageError {
+    command: String,
+    problem: String,
+    usage: String,
+}
+
+impl UsageError {
+    /// The main name of the command that was used wrong
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Usage: {}", self.problem, self.usage)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Knows every command and which plugin (identified by a number, the dispatcher uses the position
+/// of its queue) it belongs to.
+#[derive(Default)]
+pub struct Router {
+    commands: Vec<(usize, CommandSpec)>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A name that is already taken stays with the plugin that was added first.
+    pub fn add(&mut self, owner: usize, plugin_name: &str, commands: Vec<CommandSpec>) {
+        for command in commands {
+            let position = self.commands.len();
+            for name in &command.names {
+                if self.by_name.contains_key(name) {
+                    log::error!(
+                        "Command {}{} of plugin {} is already taken, ignoring it",
+                        COMMAND_PREFIX,
+                        name,
+                        plugin_name
+                    );
+                } else {
+                    self.by_name.insert(name.clone(), position);
+                }
+            }
+            self.commands.push((owner, command));
+        }
+    }
+
+    /// Drops whatever the owner had and adds its new commands. These come last, so they lose
+    /// any name that got taken in the meantime.
+    pub fn replace(&mut self, owner: usize, plugin_name: &str, commands: Vec<CommandSpec>) {
+        let others: Vec<(usize, CommandSpec)> = std::mem::take(&mut self.commands)
+            .into_iter()
+            .filter(|(o, _)| *o != owner)
+            .collect();
+        self.by_name.clear();
+        for (o, command) in others {
+            let position = self.commands.len();
+            for name in &command.names {
+                self.by_name.entry(name.clone()).or_insert(position);
+            }
+            self.commands.push((o, command));
+        }
+        self.add(owner, plugin_name, commands);
+    }
+
+    /// None if the text is not a known command. Otherwise who the command belongs to and either
+    /// what to hand them or what was wrong with the arguments.
+    pub fn route(&self, text: &str) -> Option<(usize, Result<Invocation, UsageError>)> {
+        let text = text.trim().strip_prefix(COMMAND_PREFIX)?;
+        let (trigger, input) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let trigger = trigger.to_lowercase();
+        let (owner, command) = &self.commands[*self.by_name.get(&trigger)?];
+        let invocation = command.parse_args(input).map(|args| Invocation {
+            command: command.name().to_owned(),
+            trigger,
+            args,
+        });
+        Some((*owner, invocation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.add(
+            0,
+            "rank",
+            vec![CommandSpec::new("rank", "Ranking")
+                .arg(Arg::word("LEAGUE"))
+                .arg(Arg::number("POSITION").optional())],
+        );
+        router.add(
+            1,
+            "games",
+            vec![CommandSpec::new("games", "Games")
+                .alias("game")
+                .arg(Arg::text("QUERY").optional())],
+        );
+        router
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let router = router();
+        assert!(router.route("rank epl").is_none());
+        assert!(router.route("!ranking epl").is_none());
+        assert!(router.route("!").is_none());
+    }
+
+    #[test]
+    fn words_and_numbers() {
+        let router = router();
+        let (owner, invocation) = router.route("!RANK epl 4").unwrap();
+        let invocation = invocation.unwrap();
+        assert_eq!(owner, 0);
+        assert_eq!(invocation.command, "rank");
+        assert_eq!(invocation.get("LEAGUE"), Some("epl"));
+        assert_eq!(invocation.number("POSITION"), Some(4));
+        let (_, invocation) = router.route("!rank  epl ").unwrap();
+        assert_eq!(invocation.unwrap().get("POSITION"), None);
+    }
+
+    #[test]
+    fn usage_errors() {
+        let router = router();
+        let (owner, error) = router.route("!rank").unwrap();
+        assert_eq!(owner, 0);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "LEAGUE is missing. Usage: !rank LEAGUE [POSITION]"
+        );
+        let (_, error) = router.route("!rank epl fourth").unwrap();
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "POSITION should be a number. Usage: !rank LEAGUE [POSITION]"
+        );
+        let (_, error) = router.route("!rank epl 4 5").unwrap();
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "Did not expect '5'. Usage: !rank LEAGUE [POSITION]"
+        );
+    }
+
+    #[test]
+    fn aliases_and_text() {
+        let router = router();
+        let (owner, invocation) = router.route("!game belgium @today").unwrap();
+        let invocation = invocation.unwrap();
+        assert_eq!(owner, 1);
+        assert_eq!(invocation.command, "games");
+        assert_eq!(invocation.trigger, "game");
+        assert_eq!(invocation.get("QUERY"), Some("belgium @today"));
+        let (_, invocation) = router.route("!games").unwrap();
+        assert_eq!(invocation.unwrap().get("QUERY"), None);
+    }
+
+    #[test]
+    fn first_plugin_keeps_command() {
+        let mut router = router();
+        router.add(2, "other", vec![CommandSpec::new("game", "Not games")]);
+        let (owner, _) = router.route("!game").unwrap();
+        assert_eq!(owner, 1);
+    }
+
+    #[test]
+    fn replace_commands() {
+        let mut router = router();
+        router.replace(0, "rank", vec![CommandSpec::new("table", "Ranking")]);
+        assert!(router.route("!rank epl").is_none());
+        assert_eq!(router.route("!table").unwrap().0, 0);
+        assert_eq!(router.route("!game").unwrap().0, 1);
+    }
+
+    #[test]
+    fn unicode_input() {
+        let router = router();
+        assert!(router.route("🤓🤓🤓🤓").is_none());
+        let (_, invocation) = router.route("!games 🤓").unwrap();
+        assert_eq!(invocation.unwrap().get("QUERY"), Some("🤓"));
+    }
+}
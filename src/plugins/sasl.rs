@@ -0,0 +1,366 @@
+//! Logging in with SASL while we register. Which mechanism to use is set in the `[options]` of
+//! bot.toml:
+//!
+//! - `sasl = "plain"` logs in with `sasl_account` (defaults to the nickname) and `sasl_password`
+//!   (defaults to `nick_password`).
+//! - `sasl = "external"` uses the client certificate (`client_cert_path` and `client_cert_pass`,
+//!   p12 format). This is the default if there is a certificate.
+//! - `sasl = "none"` skips SASL.
+//!
+//! When logging in fails, `sasl_on_failure = "continue"` (the default) carries on without being
+//! logged in, `sasl_on_failure = "abort"` stops the bot.
+//!
+//! Nothing in here sends anything itself, it only says what to send. That keeps it testable.
+
+use base64::Engine;
+use irc::client::prelude::*;
+use irc::proto::CapSubCommand;
+use std::fmt;
+
+/// AUTHENTICATE payloads are sent in chunks of this many bytes.
+const CHUNK_BYTES: usize = 400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mechanism {
+    Plain { account: String, password: String },
+    External,
+}
+
+impl Mechanism {
+    fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Plain { .. } => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+
+    /// What we answer once the server is ready for us, base64 encoded and all.
+    fn payload(&self) -> String {
+        match self {
+            Mechanism::Plain { account, password } => base64::engine::general_purpose::STANDARD
+                .encode(format!("{}\0{}\0{}", account, account, password)),
+            Mechanism::External => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Register without being logged in
+    Continue,
+    /// Give up on connecting altogether
+    Abort,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslConfig {
+    /// None to skip SASL
+    pub mechanism: Option<Mechanism>,
+    pub on_failure: OnFailure,
+}
+
+impl SaslConfig {
+    pub fn new(irc_config: &Config) -> Result<Self, SaslError> {
+        let option = |name: &str| irc_config.options.get(name).map(|value| value.as_str());
+        let mechanism = match option("sasl").map(|value| value.to_lowercase()).as_deref() {
+            Some("plain") => {
+                let account = option("sasl_account")
+                    .or(irc_config.nickname.as_deref())
+                    .unwrap_or_default();
+                let password = option("sasl_password")
+                    .or(irc_config.nick_password.as_deref())
+                    .unwrap_or_default();
+                if account.is_empty() || password.is_empty() {
+                    return Err(SaslError::Config(String::from(
+                        "sasl = \"plain\" needs sasl_account and sasl_password",
+                    )));
+                }
+                Some(Mechanism::Plain {
+                    account: account.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+            Some("external") => Some(Mechanism::External),
+            Some("none") => None,
+            None if irc_config.client_cert_path.is_some() => Some(Mechanism::External),
+            None => None,
+            Some(other) => {
+                return Err(SaslError::Config(format!(
+                    "Unknown sasl mechanism '{}', use plain, external or none",
+                    other
+                )))
+            }
+        };
+        let on_failure = match option("sasl_on_failure") {
+            None | Some("continue") => OnFailure::Continue,
+            Some("abort") => OnFailure::Abort,
+            Some(other) => {
+                return Err(SaslError::Config(format!(
+                    "Unknown sasl_on_failure '{}', use continue or abort",
+                    other
+                )))
+            }
+        };
+        Ok(Self {
+            mechanism,
+            on_failure,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaslError {
+    /// The options in bot.toml do not make sense
+    Config(String),
+    /// Logging in failed and we were told to give up when that happens
+    Failed(String),
+}
+
+impl fmt::Display for SaslError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaslError::Config(problem) => write!(f, "SASL configuration: {}", problem),
+            SaslError::Failed(problem) => write!(f, "SASL failed: {}", problem),
+        }
+    }
+}
+
+impl std::error::Error for SaslError {}
+
+/// One login attempt, make a new one for every connection.
+pub struct Sasl {
+    config: SaslConfig,
+    /// Whether we are still holding up registration with CAP
+    in_progress: bool,
+}
+
+impl Sasl {
+    pub fn new(config: SaslConfig) -> Self {
+        Self {
+            config,
+            in_progress: false,
+        }
+    }
+
+    /// To send before NICK and USER. Asking for the capability makes the server wait with
+    /// registering us until we send CAP END.
+    pub fn start(&mut self) -> Vec<Command> {
+        if self.config.mechanism.is_none() {
+            return vec![];
+        }
+        self.in_progress = true;
+        vec![Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            None,
+            Some(String::from("sasl")),
+        )]
+    }
+
+    /// Needs to see every incoming message. Err if we should stop connecting.
+    pub fn handle(&mut self, msg: &Message) -> Result<Vec<Command>, SaslError> {
+        let mechanism = match self.config.mechanism {
+            Some(ref mechanism) if self.in_progress => mechanism.clone(),
+            _ => return Ok(vec![]),
+        };
+        match msg.command {
+            Command::CAP(_, ref subcommand, ref code, ref caps) if mentions_sasl(code, caps) => {
+                match subcommand {
+                    CapSubCommand::ACK => {
+                        log::info!("Server supports SASL, trying {}", mechanism.name());
+                        Ok(vec![Command::AUTHENTICATE(mechanism.name().to_owned())])
+                    }
+                    CapSubCommand::NAK => self.fail("the server does not do SASL"),
+                    _ => Ok(vec![]),
+                }
+            }
+            Command::AUTHENTICATE(ref data) if data == "+" => {
+                log::info!("Sending SASL {} credentials", mechanism.name());
+                Ok(authenticate(&mechanism.payload()))
+            }
+            Command::Response(Response::RPL_LOGGEDIN, ref args) => {
+                log::info!("Logged in as {}", args.get(2).map_or("?", |a| a.as_str()));
+                Ok(vec![])
+            }
+            Command::Response(Response::RPL_SASLMECHS, ref args) => {
+                log::warn!(
+                    "Server does not offer SASL {}, only {}",
+                    mechanism.name(),
+                    args.get(1).map_or("?", |a| a.as_str())
+                );
+                Ok(vec![])
+            }
+            Command::Response(Response::RPL_SASLSUCCESS, _) => {
+                log::info!("Successfully authenticated");
+                self.in_progress = false;
+                Ok(vec![cap_end()])
+            }
+            Command::Response(Response::ERR_NICKLOCKED, _) => self.fail("the account is locked"),
+            Command::Response(Response::ERR_SASLFAIL, _) => {
+                self.fail("the server rejected our credentials (or certificate)")
+            }
+            Command::Response(Response::ERR_SASLTOOLONG, _) => {
+                self.fail("the server says our credentials were too long")
+            }
+            Command::Response(Response::ERR_SASLABORT, _) => {
+                self.fail("the server aborted the authentication")
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn fail(&mut self, problem: &str) -> Result<Vec<Command>, SaslError> {
+        self.in_progress = false;
+        match self.config.on_failure {
+            OnFailure::Continue => {
+                log::error!("SASL failed, continuing without logging in: {}", problem);
+                Ok(vec![cap_end()])
+            }
+            OnFailure::Abort => {
+                log::error!("SASL failed, giving up: {}", problem);
+                Err(SaslError::Failed(problem.to_owned()))
+            }
+        }
+    }
+}
+
+/// Depending on the server, the capabilities are in either of the two last parameters.
+fn mentions_sasl(code: &Option<String>, caps: &Option<String>) -> bool {
+    [code, caps]
+        .into_iter()
+        .flatten()
+        .flat_map(|list| list.split_whitespace())
+        .any(|cap| cap.eq_ignore_ascii_case("sasl"))
+}
+
+/// Payloads go in chunks of 400 bytes. An empty payload, or a last chunk that is exactly 400
+/// bytes, is followed by a lone `+`.
+fn authenticate(payload: &str) -> Vec<Command> {
+    let mut commands: Vec<Command> = payload
+        .as_bytes()
+        .chunks(CHUNK_BYTES)
+        // base64 is ascii, so this never splits a character
+        .map(|chunk| Command::AUTHENTICATE(String::from_utf8_lossy(chunk).into_owned()))
+        .collect();
+    if payload.len().is_multiple_of(CHUNK_BYTES) {
+        commands.push(Command::AUTHENTICATE(String::from("+")));
+    }
+    commands
+}
+
+fn cap_end() -> Command {
+    Command::CAP(None, CapSubCommand::END, None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(options: &[(&str, &str)]) -> Config {
+        Config {
+            nickname: Some(String::from("butler")),
+            options: options
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn handle(sasl: &mut Sasl, line: &str) -> Result<Vec<String>, SaslError> {
+        let msg: Message = line.parse().unwrap();
+        sasl.handle(&msg).map(|commands| {
+            commands
+                .into_iter()
+                .map(|command| Message::from(command).to_string().trim_end().to_owned())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn choose_mechanism() {
+        let plain = SaslConfig::new(&config(&[("sasl", "PLAIN"), ("sasl_password", "pw")]));
+        assert_eq!(
+            plain.unwrap().mechanism,
+            Some(Mechanism::Plain {
+                account: String::from("butler"),
+                password: String::from("pw")
+            })
+        );
+        assert!(SaslConfig::new(&config(&[("sasl", "plain")])).is_err());
+        assert!(SaslConfig::new(&config(&[("sasl", "scram")])).is_err());
+        assert!(SaslConfig::new(&config(&[("sasl_on_failure", "panic")])).is_err());
+        assert_eq!(SaslConfig::new(&config(&[])).unwrap().mechanism, None);
+        let mut with_cert = config(&[]);
+        with_cert.client_cert_path = Some("butler.p12".into());
+        assert_eq!(
+            SaslConfig::new(&with_cert).unwrap().mechanism,
+            Some(Mechanism::External)
+        );
+    }
+
+    #[test]
+    fn plain_login() {
+        let config = config(&[
+            ("sasl", "plain"),
+            ("sasl_account", "ward"),
+            ("sasl_password", "hunter2"),
+        ]);
+        let mut sasl = Sasl::new(SaslConfig::new(&config).unwrap());
+        assert_eq!(sasl.start().len(), 1);
+        assert_eq!(
+            handle(&mut sasl, ":server CAP * ACK :sasl").unwrap(),
+            vec!["AUTHENTICATE PLAIN"]
+        );
+        assert_eq!(
+            handle(&mut sasl, "AUTHENTICATE +").unwrap(),
+            vec!["AUTHENTICATE d2FyZAB3YXJkAGh1bnRlcjI="]
+        );
+        assert_eq!(
+            handle(
+                &mut sasl,
+                ":server 903 butler :SASL authentication successful"
+            )
+            .unwrap(),
+            vec!["CAP END"]
+        );
+    }
+
+    #[test]
+    fn failure_policy() {
+        let mut sasl = Sasl::new(SaslConfig {
+            mechanism: Some(Mechanism::External),
+            on_failure: OnFailure::Continue,
+        });
+        sasl.start();
+        assert_eq!(
+            handle(&mut sasl, "AUTHENTICATE +").unwrap(),
+            vec!["AUTHENTICATE +"]
+        );
+        assert_eq!(
+            handle(&mut sasl, ":server 904 butler :SASL authentication failed").unwrap(),
+            vec!["CAP END"]
+        );
+        // Done, later messages are none of our business
+        assert!(handle(&mut sasl, ":server 906 butler :Aborted")
+            .unwrap()
+            .is_empty());
+
+        let mut sasl = Sasl::new(SaslConfig {
+            mechanism: Some(Mechanism::External),
+            on_failure: OnFailure::Abort,
+        });
+        sasl.start();
+        assert!(handle(&mut sasl, ":server CAP * NAK :sasl").is_err());
+    }
+
+    #[test]
+    fn long_payloads() {
+        assert_eq!(authenticate(&"a".repeat(500)).len(), 2);
+        assert_eq!(authenticate(&"a".repeat(800)).len(), 3);
+        assert_eq!(
+            authenticate(""),
+            vec![Command::AUTHENTICATE(String::from("+"))]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Plugins that want to do something without being asked, say every few minutes or at 9:00 on
+//! Mondays. A plugin gets a `Scheduler` when it starts (`Plugin::schedule`) and can add jobs to it
+//! then or whenever it likes later. When a job is due the plugin's `run_job` is called, in the
+//! plugin's own task like everything else (see `dispatch`), so it has its state and an `Outbound`.
+//!
+//! Jobs keep running through reconnects. Whatever they say while we are not connected is dropped
+//! (see `outbound`). A disabled or quarantined plugin's jobs are skipped, they are not made up for
+//! later. Replacing a plugin (a reload) stops the old one's jobs, the new one schedules its own.
+//!
+//! Times are UTC, by `clock::now`.
+
+use super::clock;
+use chrono::{DateTime, Datelike, Duration, DurationRound, NaiveDate, Timelike, Utc};
+use std::fmt;
+use std::rc::Rc;
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Once(DateTime<Utc>),
+    /// The first time one interval from when the job is added
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The first time the job should run after `after`, None if it is done.
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Once(at) if *at > after => Some(*at),
+            Schedule::Once(_) => None,
+            Schedule::Every(interval) => Some(after + *interval),
+            Schedule::Cron(cron) => cron.next(after),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// What `run_job` gets, so the plugin knows which job is due
+    pub name: String,
+    pub schedule: Schedule,
+}
+
+impl Job {
+    pub fn once(name: &str, at: DateTime<Utc>) -> Self {
+        Self {
+            name: name.to_owned(),
+            schedule: Schedule::Once(at),
+        }
+    }
+
+    pub fn every(name: &str, interval: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            schedule: Schedule::Every(interval),
+        }
+    }
+
+    /// `expression` as in a crontab, see `Cron`.
+    pub fn cron(name: &str, expression: &str) -> Result<Self, CronError> {
+        Ok(Self {
+            name: name.to_owned(),
+            schedule: Schedule::Cron(expression.parse()?),
+        })
+    }
+}
+
+/// Five fields like in a crontab: minute, hour, day of the month, month, day of the week (0 or 7
+/// is Sunday). Each is `*`, a number, a range `1-5` or a list of those `1,3,5`, optionally with a
+/// step `*/15`. Like cron, when both days are restricted either of them will do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid cron expression: {}", self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+impl std::str::FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(CronError(format!(
+                "'{}' should have 5 fields, not {}",
+                expression,
+                fields.len()
+            )));
+        }
+        let mut weekdays = field(fields[4], 0, 7)?;
+        // Sunday is both 0 and 7
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field(fields[0], 0, 59)?,
+            hours: field(fields[1], 0, 23)?,
+            days: field(fields[2], 1, 31)?,
+            months: field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+/// One field as a bit set, bit n set if n matches.
+fn field(text: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let number = |n: &str| -> Result<u32, CronError> {
+        match n.parse() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(CronError(format!(
+                "'{}' is not a number from {} to {}",
+                n, min, max
+            ))),
+        }
+    };
+    let mut bits = 0;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(CronError(format!("'{}' is not a valid step", step))),
+            },
+            None => (item, None),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            // `5/10` is 5, 15, 25...
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if from > to {
+            return Err(CronError(format!("'{}' goes backwards", range)));
+        }
+        for n in (from..=to).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+fn matches(bits: u64, n: u32) -> bool {
+    bits & (1 << n) != 0
+}
+
+impl Cron {
+    /// The first whole minute after `after` that matches. None if there is none within a few
+    /// years, think February 30.
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        let mut date = start.date_naive();
+        // Long enough to get to the next February 29
+        for _ in 0..(4 * 366) {
+            if self.matches_day(date) {
+                let first = if date == start.date_naive() {
+                    (start.hour(), start.minute())
+                } else {
+                    (0, 0)
+                };
+                if let Some((hour, minute)) = self.time_of_day(first) {
+                    return Some(date.and_hms_opt(hour, minute, 0)?.and_utc());
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !matches(self.months, date.month()) {
+            return false;
+        }
+        let day = matches(self.days, date.day());
+        let weekday = matches(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching hour and minute from `first` on, in one day.
+    fn time_of_day(&self, first: (u32, u32)) -> Option<(u32, u32)> {
+        (first.0..24)
+            .filter(|hour| matches(self.hours, *hour))
+            .flat_map(|hour| {
+                let from = if hour == first.0 { first.1 } else { 0 };
+                (from..60).map(move |minute| (hour, minute))
+            })
+            .find(|(_, minute)| matches(self.minutes, *minute))
+    }
+}
+
+/// Where a plugin adds its jobs. Cheap to clone, a plugin can keep one around to add jobs later.
+#[derive(Clone)]
+pub struct Scheduler {
+    /// Tells the plugin a job is due. False once the plugin is gone, which ends the job.
+    due: Rc<dyn Fn(&str) -> bool>,
+}
+
+impl Scheduler {
+    pub fn new(due: impl Fn(&str) -> bool + 'static) -> Self {
+        Self { due: Rc::new(due) }
+    }
+
+    /// Runs the job until its schedule is done, or the plugin is. Has to be called from within a
+    /// `LocalSet`.
+    pub fn add(&self, job: Job) {
+        let due = Rc::clone(&self.due);
+        tokio::task::spawn_local(async move {
+            let mut after = clock::now();
+            while let Some(next) = job.schedule.next(after) {
+                let wait = (next - clock::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                if !due(&job.name) {
+                    break;
+                }
+                // Only catch up on one run when we fell behind (a suspended laptop, say)
+                after = next.max(clock::now());
+            }
+            log::debug!("Job {} is done", job.name);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<String> {
+        let cron: Cron = expression.parse().unwrap();
+        cron.next(at(after)).map(|next| next.to_rfc3339())
+    }
+
+    #[test]
+    fn cron_expressions() {
+        // 2026-06-20 is a Saturday
+        let now = "2026-06-20T18:00:30Z";
+        assert_eq!(next("* * * * *", now).unwrap(), "2026-06-20T18:01:00+00:00");
+        assert_eq!(
+            next("*/15 * * * *", now).unwrap(),
+            "2026-06-20T18:15:00+00:00"
+        );
+        assert_eq!(
+            next("5/20 * * * *", now).unwrap(),
+            "2026-06-20T18:05:00+00:00"
+        );
+        assert_eq!(next("0 9 * * 1", now).unwrap(), "2026-06-22T09:00:00+00:00");
+        assert_eq!(next("0 9 * * 7", now).unwrap(), "2026-06-21T09:00:00+00:00");
+        assert_eq!(
+            next("30 8-10 1 * *", now).unwrap(),
+            "2026-07-01T08:30:00+00:00"
+        );
+        // Either day will do
+        assert_eq!(next("0 0 1 * 0", now).unwrap(), "2026-06-21T00:00:00+00:00");
+        assert_eq!(
+            next("0 0 29 2 *", now).unwrap(),
+            "2028-02-29T00:00:00+00:00"
+        );
+        assert_eq!(next("0 0 30 2 *", now), None);
+    }
+
+    #[test]
+    fn bad_cron_expressions() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expression.parse::<Cron>().is_err(), "{}", expression);
+        }
+        assert_eq!(
+            Job::cron("x", "* * *").unwrap_err().to_string(),
+            "Invalid cron expression: '* * *' should have 5 fields, not 3"
+        );
+    }
+
+    #[test]
+    fn schedules() {
+        let now = at("2026-06-20T18:00:00Z");
+        let once = Schedule::Once(at("2026-06-20T19:00:00Z"));
+        assert_eq!(once.next(now), Some(at("2026-06-20T19:00:00Z")));
+        assert_eq!(once.next(at("2026-06-20T19:00:00Z")), None);
+        let every = Schedule::Every(Duration::minutes(5));
+        assert_eq!(every.next(now), Some(at("2026-06-20T18:05:00Z")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jobs_run() {
+        clock::freeze(at("2026-06-20T18:00:00Z"));
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let ran = Rc::new(RefCell::new(vec![]));
+                let scheduler = |stop_after: usize| {
+                    let ran = Rc::clone(&ran);
+                    let calls = RefCell::new(0);
+                    Scheduler::new(move |name| {
+                        ran.borrow_mut().push(name.to_owned());
+                        *calls.borrow_mut() += 1;
+                        // Gone after this many runs
+                        *calls.borrow() < stop_after
+                    })
+                };
+                scheduler(3).add(Job::every("tick", Duration::minutes(5)));
+                scheduler(10).add(Job::once("once", clock::now() + Duration::minutes(12)));
+                tokio::task::yield_now().await;
+                for _ in 0..12 {
+                    clock::freeze(clock::now() + Duration::minutes(5));
+                    tokio::time::advance(std::time::Duration::from_secs(5 * 60)).await;
+                }
+                let ran = ran.borrow();
+                assert_eq!(ran.iter().filter(|name| *name == "once").count(), 1);
+                assert_eq!(ran.iter().filter(|name| *name == "tick").count(), 3);
+            })
+            .await;
+    }
+}
@@ -1,3 +1,5 @@
+use super::outbound::Outbound;
+use async_trait::async_trait;
 use irc::client::prelude::*;
 use rand::seq::SliceRandom;
 
@@ -31,11 +33,12 @@ impl SimpleReplyHandler {
     }
 }
 
-impl super::Handler for SimpleReplyHandler {
-    fn handle(&self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
+#[async_trait(?Send)]
+impl super::Plugin for SimpleReplyHandler {
+    async fn handle(&mut self, outbound: &Outbound, msg: &Message) {
+        if let Command::PRIVMSG(_, ref message) = msg.command {
             if let Some(result) = self.matcher(message) {
-                client.send_privmsg(&channel, &result).unwrap();
+                outbound.reply(msg, &result);
             }
         }
     }
@@ -43,7 +46,7 @@ impl super::Handler for SimpleReplyHandler {
 
 impl super::help::Help for SimpleReplyHandler {
     fn name(&self) -> String {
-        String::from("simply_reply")
+        String::from("simple_reply")
     }
 
     fn help(&self) -> Vec<super::help::HelpEntry> {
@@ -73,7 +76,7 @@ impl SimpleReply {
     fn get_reply(&self) -> Option<String> {
         if self.replies.len() == 1 {
             // Shortcut if there is no choice to be made
-            self.replies.get(0).map(|s| s.to_owned())
+            self.replies.first().map(|s| s.to_owned())
         } else if let Some(choice) = self.replies.choose(&mut rand::thread_rng()) {
             Some(choice.to_owned())
         } else {
@@ -88,8 +91,34 @@ impl SimpleReply {
 
 #[cfg(test)]
 mod tests {
+    use super::super::Plugin;
     use super::*;
 
+    #[tokio::test]
+    async fn reply_where_asked() {
+        let (outbound, recorder) = Outbound::recording();
+        let mut handler = SimpleReplyHandler {
+            replies: vec![SimpleReply {
+                triggers: vec![String::from("hello")],
+                replies: vec![String::from("\x02yo\x0f there")],
+            }],
+        };
+        for line in [
+            ":ward!ward@example.org PRIVMSG #chan : Hello ",
+            ":ward!ward@example.org PRIVMSG #chan :hello there",
+            ":ward!ward@example.org PRIVMSG butler :hello",
+        ] {
+            handler.handle(&outbound, &line.parse().unwrap()).await;
+        }
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "PRIVMSG #chan \x02yo\x0f there",
+                "PRIVMSG ward \x02yo\x0f there"
+            ]
+        );
+    }
+
     #[test]
     fn test_single_trigger() {
         let reply = SimpleReply {
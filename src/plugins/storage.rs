@@ -0,0 +1,245 @@
+//! Where plugins keep what should survive a restart (or a crash). Everything goes in one SQLite
+//! database. Plugins get a `Storage` when they are built and can either:
+//!
+//! - Keep serde values in the key/value store with `get` and `set`. Fine for a few settings or a
+//!   small blob of data that gets saved in one go.
+//! - Make their own tables through `migrate`, for data that grows or needs querying.
+//!
+//! Every plugin uses its own namespace (its name will do), so they do not trip over each other.
+//! Writes happen in transactions, so a crash halfway leaves the old data, not half of the new.
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// The SQLite database file, created if it does not exist.
+    pub path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("butler.sqlite"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    /// A stored value did not (de)serialize
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => write!(f, "Storage error: {}", e),
+            StorageError::Json(e) => write!(f, "Storage error, bad value: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Json(e)
+    }
+}
+
+/// Cheap to clone, every clone uses the same database connection.
+#[derive(Clone)]
+pub struct Storage {
+    connection: Rc<Connection>,
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
+        let connection = Connection::open(&config.path)?;
+        // WAL keeps a crash from corrupting anything and lets us read while writing
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::new(connection)
+    }
+
+    /// Forgets everything once dropped. For tests.
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(connection: Connection) -> Result<Self, StorageError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS key_value (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            );
+            CREATE TABLE IF NOT EXISTS migrations (
+                namespace TEXT PRIMARY KEY,
+                version INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Self {
+            connection: Rc::new(connection),
+        })
+    }
+
+    pub fn get<T: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<T>, StorageError> {
+        let value: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT value FROM key_value WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set<T: Serialize>(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &T,
+    ) -> Result<(), StorageError> {
+        let value = serde_json::to_string(value)?;
+        self.connection.execute(
+            "INSERT INTO key_value (namespace, key, value) VALUES (?1, ?2, ?3)
+            ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value",
+            params![namespace, key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, namespace: &str, key: &str) -> Result<(), StorageError> {
+        self.connection.execute(
+            "DELETE FROM key_value WHERE namespace = ?1 AND key = ?2",
+            params![namespace, key],
+        )?;
+        Ok(())
+    }
+
+    /// Brings the namespace's own tables up to date. Every entry is a batch of SQL statements,
+    /// run once and in order. Only ever add to the end of the list, never change what is there.
+    pub fn migrate(&self, namespace: &str, migrations: &[&str]) -> Result<(), StorageError> {
+        let version: usize = self
+            .connection
+            .query_row(
+                "SELECT version FROM migrations WHERE namespace = ?1",
+                params![namespace],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+        for (index, migration) in migrations.iter().enumerate().skip(version) {
+            log::info!(
+                "Migrating storage of {} to version {}",
+                namespace,
+                index + 1
+            );
+            self.transaction(|transaction| {
+                transaction.execute_batch(migration)?;
+                transaction.execute(
+                    "INSERT INTO migrations (namespace, version) VALUES (?1, ?2)
+                    ON CONFLICT (namespace) DO UPDATE SET version = excluded.version",
+                    params![namespace, index + 1],
+                )?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Either everything `f` writes ends up in the database or none of it does.
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Transaction) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let result = f(&transaction)?;
+        transaction.commit()?;
+        Ok(result)
+    }
+
+    /// For reading a plugin's own tables.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_value() {
+        let storage = Storage::in_memory().unwrap();
+        assert_eq!(storage.get::<Vec<u32>>("test", "numbers").unwrap(), None);
+        storage.set("test", "numbers", &vec![1, 2, 3]).unwrap();
+        storage.set("test", "numbers", &vec![4]).unwrap();
+        storage.set("other", "numbers", &vec![5]).unwrap();
+        assert_eq!(
+            storage.get::<Vec<u32>>("test", "numbers").unwrap(),
+            Some(vec![4])
+        );
+        assert!(storage.get::<String>("test", "numbers").is_err());
+        storage.remove("test", "numbers").unwrap();
+        assert_eq!(storage.get::<Vec<u32>>("test", "numbers").unwrap(), None);
+        assert_eq!(
+            storage.get::<Vec<u32>>("other", "numbers").unwrap(),
+            Some(vec![5])
+        );
+    }
+
+    #[test]
+    fn migrations() {
+        let storage = Storage::in_memory().unwrap();
+        let first = ["CREATE TABLE test_things (name TEXT);"];
+        storage.migrate("test", &first).unwrap();
+        storage.migrate("test", &first).unwrap();
+        let second = [
+            first[0],
+            "ALTER TABLE test_things ADD COLUMN amount INTEGER;",
+        ];
+        storage.migrate("test", &second).unwrap();
+        storage
+            .connection()
+            .execute("INSERT INTO test_things (name, amount) VALUES ('a', 1)", [])
+            .unwrap();
+    }
+
+    #[test]
+    fn failed_transaction() {
+        let storage = Storage::in_memory().unwrap();
+        let result: Result<(), StorageError> = storage.transaction(|transaction| {
+            transaction.execute(
+                "INSERT INTO key_value (namespace, key, value) VALUES ('test', 'a', '1')",
+                [],
+            )?;
+            transaction.execute("INSERT INTO nowhere VALUES (1)", [])?;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(storage.get::<u32>("test", "a").unwrap(), None);
+    }
+}
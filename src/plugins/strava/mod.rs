@@ -1,45 +1,79 @@
+use super::bus::{Bus, LeaderboardUpdate, Seen};
+use super::cache::{Cache, Ttl, TtlConfig};
+use super::clock;
 use super::formatting;
+use super::http::{Http, HttpError};
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
 use irc::client::prelude::*;
 use reqwest::cookie::Jar;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
+use std::rc::Rc;
 use std::str::FromStr;
-use unicode_segmentation::UnicodeSegmentation;
 
 mod strava_irc_link;
 
+/// Athletes who linked their IRC nicks only show up in `!strava` if one of those nicks did
+/// something on IRC in this many days.
+const ACTIVE_DAYS: i64 = 30;
+
 pub struct StravaHandler {
+    http: Http,
+    leaderboard: Cache<ClubLeaderboard>,
     irc_links: strava_irc_link::StravaIrcLink,
     cookies: Vec<String>,
+    bus: Bus,
+    /// The last leaderboard that went out as a `bus::LeaderboardUpdate`
+    published: Option<Rc<ClubLeaderboard>>,
 }
 
 impl StravaHandler {
-    pub fn new(config: &super::config::Config) -> StravaHandler {
-        let irc_links = strava_irc_link::StravaIrcLink::from_file_or_new("irc_links.json");
+    /// Asks the `bus::Seen` service who is active, publishes a `bus::LeaderboardUpdate` for every
+    /// leaderboard it fetches.
+    pub fn new(
+        config: &super::config::Config,
+        storage: &super::storage::Storage,
+        http: &Http,
+        bus: &Bus,
+    ) -> StravaHandler {
+        let irc_links = strava_irc_link::StravaIrcLink::load(storage, "irc_links.json")
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load strava irc links. {}", e);
+                Default::default()
+            });
         let cookies = if let Some(c) = &config.strava {
             c.cookies.split("; ").map(|s| s.to_owned()).collect()
         } else {
             vec![]
         };
-        StravaHandler { irc_links, cookies }
-    }
-
-    fn match_club(msg: &str) -> bool {
-        let first_seven: String = msg.graphemes(true).take(7).collect();
-        first_seven.eq_ignore_ascii_case("!strava")
+        StravaHandler {
+            http: http.clone(),
+            leaderboard: leaderboard_cache(&config.cache),
+            irc_links,
+            cookies,
+            bus: bus.clone(),
+            published: None,
+        }
     }
 
-    async fn handle_club(&self, msg: &str) -> Vec<String> {
+    async fn handle_club(&mut self, input: &str) -> Vec<String> {
         let mut result = vec![];
-        let input: String = msg.graphemes(true).skip(7).collect();
-        let input = input.trim();
         println!("Handling club");
         let club_id = "223460"; // Libera ##running (TODO: make this plugin config)
 
-        let leaderboard = ClubLeaderboard::fetch(club_id, &self.cookies).await;
+        let (http, cookies) = (self.http.clone(), self.cookies.clone());
+        let leaderboard = self
+            .leaderboard
+            .get(|| ClubLeaderboard::fetch(http, club_id.to_owned(), cookies))
+            .await;
         match leaderboard {
-            Ok(mut leaderboard) => {
+            Some(leaderboard) => {
+                self.publish(club_id, &leaderboard);
+                let mut leaderboard = (*leaderboard).clone();
                 match input.parse() {
                     Ok(sort_by) => leaderboard.sort(sort_by),
                     Err(e) => eprintln!(
@@ -50,29 +84,72 @@ impl StravaHandler {
                 // Note that this removes names not in the strava links file!!
                 leaderboard.override_names(&self.irc_links);
                 leaderboard.drop_ignored(&self.irc_links);
+                // Without the seen plugin, there is no telling who is around
+                if let Some(seen) = self.bus.service::<dyn Seen>() {
+                    let since = clock::now() - Duration::days(ACTIVE_DAYS);
+                    leaderboard.drop_inactive(&self.irc_links, &*seen, since);
+                }
                 result.push(leaderboard.to_string())
             }
-            Err(e) => eprintln!("Error fetching leaderboard: {}", e),
+            None => eprintln!("No leaderboard to show"),
         }
 
         result
     }
-}
 
-// So this one is not actually currrently mutating anything. Probably _should_ make it cache the
-// leaderboard for at least one minute though.
-#[async_trait]
-impl super::AsyncMutableHandler for StravaHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if StravaHandler::match_club(message) {
-                let club_reply = self.handle_club(message).await;
-                for reply in club_reply {
-                    println!("SEND: {}", reply);
-                    client.send_privmsg(&channel, &reply).unwrap()
-                }
+    /// Unless it is the one from the cache that already went out.
+    fn publish(&mut self, club_id: &str, leaderboard: &Rc<ClubLeaderboard>) {
+        if let Some(published) = &self.published {
+            if Rc::ptr_eq(published, leaderboard) {
+                return;
             }
         }
+        self.published = Some(Rc::clone(leaderboard));
+        let mut named = (**leaderboard).clone();
+        named.sort(ClubLeaderboardSort::Distance);
+        named.override_names(&self.irc_links);
+        named.drop_ignored(&self.irc_links);
+        self.bus.publish(LeaderboardUpdate {
+            club: club_id.to_owned(),
+            athletes: named
+                .ranking
+                .into_iter()
+                .map(|athlete| athlete.first_name)
+                .collect(),
+        });
+    }
+}
+
+fn leaderboard_cache(cache_config: &HashMap<String, TtlConfig>) -> Cache<ClubLeaderboard> {
+    let ttl = Ttl {
+        fresh: Duration::minutes(1),
+        stale: Duration::minutes(5),
+        error: Duration::minutes(1),
+        // Only fetched when asked for
+        refresh: None,
+    };
+    Cache::new("strava", ttl, cache_config)
+}
+
+// So this one is not actually currrently mutating anything.
+#[async_trait(?Send)]
+impl super::Plugin for StravaHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new(
+            "strava",
+            "Show the Strava freenode_running leaderboard for the given metric (distance, slope, elevation, pace or time). Defaults to distance if none given.",
+        )
+        .arg(Arg::word("METRIC").optional())]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        let club_reply = self
+            .handle_club(invocation.get("METRIC").unwrap_or_default())
+            .await;
+        for reply in club_reply {
+            println!("SEND: {}", reply);
+            outbound.reply(msg, &reply)
+        }
     }
 }
 
@@ -80,18 +157,9 @@ impl super::help::Help for StravaHandler {
     fn name(&self) -> String {
         String::from("strava")
     }
-
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        let result = vec![
-            super::help::HelpEntry::new(
-            "!strava (distance|slope|elevation|pace|time)",
-            "Show the Strava freenode_running leaderboard for the given metric. Defaults to distance if none given.",
-        )];
-        result
-    }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 struct ClubLeaderboard {
     #[serde(rename = "data")]
     ranking: Vec<ClubLeaderboardAthlete>,
@@ -102,24 +170,23 @@ struct ClubLeaderboard {
 
 impl ClubLeaderboard {
     async fn fetch(
-        id: &str,
-        cookies: &Vec<String>,
-    ) -> Result<ClubLeaderboard, reqwest::Error> {
-        let url = format!("https://www.strava.com/clubs/{}/leaderboard", id);
+        http: Http,
+        id: String,
+        cookies: Vec<String>,
+    ) -> Result<ClubLeaderboard, HttpError> {
+        let path = format!("/clubs/{}/leaderboard", id);
+        let url = http.url("strava", "https://www.strava.com", &path);
         let strava_domain: reqwest::Url = "https://strava.com".parse().unwrap();
         let jar = Jar::default();
-        for cookie in cookies {
+        for cookie in &cookies {
             let cookie = format!("{}; Domain=.strava.com; Path=/;", cookie);
             jar.add_cookie_str(&cookie, &strava_domain);
         }
-        let client = reqwest::ClientBuilder::new()
-            .cookie_provider(jar.into())
-            .build()?;
+        let client = http.client_builder().cookie_provider(jar.into()).build()?;
         let req = client.get(&url)
             .header("Accept", "text/javascript, application/javascript, application/ecmascript, application/x-ecmascript")
-            .header("X-Requested-With", "XmlHttpRequest")
-            .send().await?;
-        req.json().await
+            .header("X-Requested-With", "XmlHttpRequest");
+        http.json(req).await
     }
 
     fn sort(&mut self, sort_by: ClubLeaderboardSort) {
@@ -155,6 +222,22 @@ impl ClubLeaderboard {
         self.ranking
             .retain(|athlete| !irc_links.is_ignored(athlete.strava_id));
     }
+    /// Drops those with linked nicks who did not use any of them on IRC since `since`. Nothing to
+    /// go on for the others, so they stay.
+    fn drop_inactive(
+        &mut self,
+        irc_links: &strava_irc_link::StravaIrcLink,
+        seen: &dyn Seen,
+        since: DateTime<Utc>,
+    ) {
+        self.ranking
+            .retain(|athlete| match irc_links.get_nicks(athlete.strava_id) {
+                Some(nicks) => nicks
+                    .iter()
+                    .any(|nick| seen.last_seen(nick).is_some_and(|at| at >= since)),
+                None => true,
+            });
+    }
 }
 
 impl fmt::Display for ClubLeaderboard {
@@ -171,7 +254,7 @@ impl fmt::Display for ClubLeaderboard {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 struct ClubLeaderboardAthlete {
     #[serde(rename = "athlete_id")]
     strava_id: u64,
@@ -230,21 +313,16 @@ impl fmt::Display for ClubLeaderboardAthlete {
 /// Enum to handle the different inputs by which the leaderboard can be sorted.
 /// Ensures in the actual sorting we only deal with some known values. The input string is parsed
 /// into one of the enum's values.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Default, Clone)]
 enum ClubLeaderboardSort {
     Elevation,
+    #[default]
     Distance,
     Moving,
     Pace,
     Slope,
 }
 
-impl Default for ClubLeaderboardSort {
-    fn default() -> Self {
-        ClubLeaderboardSort::Distance
-    }
-}
-
 impl FromStr for ClubLeaderboardSort {
     type Err = ParseClubLeaderboardSortError;
 
@@ -286,24 +364,40 @@ fn format_time(seconds: u32) -> String {
     let minutes = (f64::from(seconds % 3600) / 60.0).floor();
     let seconds = seconds % 60;
     if hours == 0.0 {
-        return format!("{}:{:02}", minutes, seconds);
+        format!("{}:{:02}", minutes, seconds)
     } else {
-        return format!("{}:{:02}:{:02}", hours, minutes, seconds);
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::router::Router;
+    use super::super::Plugin;
     use super::*;
 
+    fn router() -> Router {
+        let mut router = Router::new();
+        let strava = StravaHandler {
+            http: Http::new(&Default::default()).unwrap(),
+            leaderboard: leaderboard_cache(&HashMap::new()),
+            irc_links: Default::default(),
+            cookies: vec![],
+            bus: Bus::new(),
+            published: None,
+        };
+        router.add(0, "strava", strava.commands());
+        router
+    }
+
     #[test]
     fn match_club() {
-        let input = "!strava";
-        assert!(StravaHandler::match_club(input));
-        let input = "!stravasdifohoefsbv";
-        assert!(StravaHandler::match_club(input));
-        let input = "!strava pace";
-        assert!(StravaHandler::match_club(input));
+        let router = router();
+        let (_, invocation) = router.route("!strava").unwrap();
+        assert_eq!(invocation.unwrap().get("METRIC"), None);
+        assert!(router.route("!stravasdifohoefsbv").is_none());
+        let (_, invocation) = router.route("!strava pace").unwrap();
+        assert_eq!(invocation.unwrap().get("METRIC"), Some("pace"));
     }
 
     #[test]
@@ -312,7 +406,7 @@ mod tests {
         // In production we got a panic that we were splitting halfway through a character.
         // This crashed the bot
         let input = "🏃🏃";
-        assert!(!StravaHandler::match_club(input));
+        assert!(router().route(input).is_none());
     }
 
     #[test]
@@ -362,6 +456,68 @@ mod tests {
         assert_eq!(leaderboard.ranking[0].strava_id, 2521741);
     }
 
+    struct SeenAt(HashMap<&'static str, DateTime<Utc>>);
+
+    impl Seen for SeenAt {
+        fn last_seen(&self, nick: &str) -> Option<DateTime<Utc>> {
+            self.0.get(nick).copied()
+        }
+    }
+
+    #[test]
+    fn inactive_athletes() {
+        let mut leaderboard: ClubLeaderboard =
+            serde_json::from_str(include_str!("strava_leaderboard.json")).unwrap();
+        let athletes = leaderboard.ranking.len();
+        // Chad and Jason linked their nicks, Žilvinas did not
+        let irc_links: strava_irc_link::StravaIrcLink = serde_json::from_str(
+            r#"{"users": {"2521741": {"nicks": ["chad", "chad_"]}, "92442622": {"nicks": ["jason"]}}}"#,
+        )
+        .unwrap();
+        let since = "2026-06-01T00:00:00Z".parse().unwrap();
+        let seen = SeenAt(HashMap::from([
+            ("chad_", "2026-06-20T18:00:00Z".parse().unwrap()),
+            ("jason", "2026-05-20T18:00:00Z".parse().unwrap()),
+        ]));
+        leaderboard.drop_inactive(&irc_links, &seen, since);
+        assert_eq!(leaderboard.ranking.len(), athletes - 1);
+        assert_eq!(leaderboard.ranking[0].first_name, "Chad");
+        assert_eq!(leaderboard.ranking[1].first_name, "Žilvinas");
+        assert_eq!(leaderboard.ranking[2].first_name, "Tomas 🥓");
+    }
+
+    #[test]
+    fn leaderboard_updates() {
+        let bus = Bus::new();
+        let updates = Rc::new(std::cell::RefCell::new(vec![]));
+        {
+            let updates = Rc::clone(&updates);
+            bus.subscribe(move |update: &LeaderboardUpdate| {
+                updates.borrow_mut().push(update.clone())
+            });
+        }
+        let mut strava = StravaHandler {
+            http: Http::new(&Default::default()).unwrap(),
+            leaderboard: leaderboard_cache(&HashMap::new()),
+            irc_links: serde_json::from_str(r#"{"users": {"2521741": {"nicks": ["chad"]}}}"#)
+                .unwrap(),
+            cookies: vec![],
+            bus,
+            published: None,
+        };
+        let leaderboard: Rc<ClubLeaderboard> =
+            Rc::new(serde_json::from_str(include_str!("strava_leaderboard.json")).unwrap());
+        strava.publish("223460", &leaderboard);
+        // Same one, from the cache
+        strava.publish("223460", &leaderboard);
+        strava.publish("223460", &Rc::new((*leaderboard).clone()));
+        let updates = updates.borrow();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].club, "223460");
+        // Only Chad linked a nick, the others are left out like they are on IRC
+        assert_eq!(updates[0].athletes, ["chad"]);
+    }
+
     // #[tokio::test]
     // async fn fetch_libera_club() {
     //     let _ = env_logger::builder().is_test(true).try_init();
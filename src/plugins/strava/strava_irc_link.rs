@@ -1,7 +1,11 @@
+use super::super::storage::{Storage, StorageError};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::io::Write;
+
+/// Where the links live in storage.
+const NAMESPACE: &str = "strava";
+const KEY: &str = "irc_links";
 
 /// Link Strava user IDs to IRC nicks. This struct also provides the convenience functions to
 /// access things.
@@ -19,13 +23,21 @@ struct StravaIrcUser {
 }
 
 impl StravaIrcLink {
-    pub fn new() -> StravaIrcLink {
-        StravaIrcLink {
-            users: HashMap::new(),
+    /// From storage. The first time round, the links are taken over from the old json file (if
+    /// there is one).
+    pub fn load(storage: &Storage, filename: &str) -> Result<StravaIrcLink, StorageError> {
+        match storage.get(NAMESPACE, KEY)? {
+            Some(links) => Ok(links),
+            None => {
+                let links = StravaIrcLink::from_file(filename).unwrap_or_default();
+                links.save(storage)?;
+                Ok(links)
+            }
         }
     }
-    pub fn from_file_or_new(filename: &str) -> StravaIrcLink {
-        StravaIrcLink::from_file(filename).unwrap_or_else(StravaIrcLink::new)
+
+    pub fn save(&self, storage: &Storage) -> Result<(), StorageError> {
+        storage.set(NAMESPACE, KEY, self)
     }
 
     pub fn from_file(filename: &str) -> Option<StravaIrcLink> {
@@ -46,19 +58,7 @@ impl StravaIrcLink {
         None
     }
 
-    pub fn _to_file(&self, filename: &str) {
-        // TODO Need to handle failure here better
-        match File::create(filename) {
-            Ok(mut f) => {
-                if let Ok(serialized) = serde_json::to_string(self) {
-                    f.write_all(serialized.as_bytes()).unwrap();
-                }
-            }
-            Err(e) => panic!("Failed to save, {}", e),
-        }
-    }
-
-    pub fn _get_nicks(&self, strava_id: u64) -> Option<Vec<String>> {
+    pub fn get_nicks(&self, strava_id: u64) -> Option<Vec<String>> {
         let mut res = vec![];
         for nick in &self.users.get(&strava_id)?.nicks {
             res.push(nick.clone())
@@ -75,7 +75,7 @@ impl StravaIrcLink {
         if self.users.get(&strava_id)?.nicks.is_empty() {
             None
         } else {
-            Some(nicks.get(0).unwrap().to_owned())
+            Some(nicks.first().unwrap().to_owned())
         }
     }
 
@@ -139,26 +139,29 @@ mod tests {
 
     #[test]
     fn strava_irc_link() {
-        let mut db = StravaIrcLink::new();
+        let mut db = StravaIrcLink::default();
         db._insert_connection(123, "ward");
-        let result = db._get_nicks(123);
+        let result = db.get_nicks(123);
         assert!(result.is_some());
         let result = result.unwrap();
         assert_eq!(1, result.len());
-        assert_eq!("ward", result.get(0).unwrap());
+        assert_eq!("ward", result.first().unwrap());
         db._insert_connection(123, "ward_");
         db._insert_connection(234, "butler");
-        db._to_file("testresult.json");
-        let result = db._get_nicks(123);
+        let storage = Storage::in_memory().unwrap();
+        db.save(&storage).unwrap();
+        let db_reloaded = StravaIrcLink::load(&storage, "does-not-exist.json").unwrap();
+        assert_eq!(db_reloaded.get_nicks(234), db.get_nicks(234));
+        let result = db.get_nicks(123);
         assert!(result.is_some());
         let result = result.unwrap();
-        assert_eq!("ward", result.get(0).unwrap());
+        assert_eq!("ward", result.first().unwrap());
         assert_eq!("ward_", result.get(1).unwrap());
-        let result = db._get_nicks(234).unwrap();
-        assert_eq!("butler", result.get(0).unwrap());
+        let result = db.get_nicks(234).unwrap();
+        assert_eq!("butler", result.first().unwrap());
         assert_eq!(1, result.len());
         db._remove_nick("butler");
-        assert!(db._get_nicks(234).is_none());
+        assert!(db.get_nicks(234).is_none());
         db._remove_strava_id(123);
         assert!(db._get_strava_id("ward_").is_none());
     }
@@ -174,7 +177,7 @@ mod tests {
                   \"ignore\": true
                 }
                 }}";
-        let parsed: StravaIrcLink = serde_json::from_str(&input).unwrap();
+        let parsed: StravaIrcLink = serde_json::from_str(input).unwrap();
         assert!(parsed.is_ignored(2));
         assert!(!parsed.is_ignored(1));
         assert_eq!(parsed.get_first_nick(1).unwrap(), "ward");
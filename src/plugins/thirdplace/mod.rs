@@ -1,66 +1,56 @@
-use super::send_privmsg;
+use super::cache::{Cache, Ttl, TtlConfig};
+use super::http::{Http, HttpError};
+use super::outbound::Outbound;
+use super::router::{CommandSpec, Invocation};
 use async_trait::async_trait;
-use chrono::prelude::*;
 use chrono::Duration;
 use irc::client::prelude::*;
 use regex::Regex;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 
 pub struct ThirdPlaceHandler {
+    http: Http,
     /// Just going to reparse every time for now
-    content: String,
-    cached_at: DateTime<Utc>,
-    cache_threshold: Duration,
+    content: Cache<String>,
 }
 
 impl ThirdPlaceHandler {
-    pub async fn new() -> Self {
-        let cache_threshold = Duration::minutes(2);
-        let cached_at = Utc::now() - cache_threshold;
-        let content = String::from("");
+    pub async fn new(http: &Http, cache_config: &HashMap<String, TtlConfig>) -> Self {
+        let ttl = Ttl {
+            fresh: Duration::minutes(2),
+            stale: Duration::minutes(10),
+            error: Duration::minutes(1),
+            refresh: Some(Duration::minutes(5)),
+        };
+        let content = Cache::new("wikipedia", ttl, cache_config);
+        let refresh_http = http.clone();
+        content.refresh_in_background(move || Self::fetch(refresh_http.clone()));
         Self {
+            http: http.clone(),
             content,
-            cached_at,
-            cache_threshold,
         }
     }
 
-    /// Fetch wiki page, save it, update cache
-    async fn update(&mut self) -> Result<(), reqwest::Error> {
+    /// Fetch wiki page, save it
+    async fn fetch(http: Http) -> Result<String, HttpError> {
         println!("Running update");
-        let url = "https://en.wikipedia.org/wiki/2026_FIFA_World_Cup";
-        let client = reqwest::ClientBuilder::new().build()?;
-        let req = client
-            .get(url)
-            .header(
-                "Accept",
-                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
-            )
-            .header(
-                reqwest::header::USER_AGENT,
-                "rusty-butler-third-place-plugin",
-            )
-            .send()
-            .await?;
-        let content = req.text().await?;
+        let url = http.url(
+            "wikipedia",
+            "https://en.wikipedia.org",
+            "/wiki/2026_FIFA_World_Cup",
+        );
+        let req = http.get(&url).header(
+            "Accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        );
+        let content = http.text(req).await?;
         println!("Content received");
         if let Ok(mut f) = File::create("debug.thirdplace.html") {
             let _ = write!(f, "{}", content);
         }
-        self.content = content;
-        self.cached_at = Utc::now();
-        Ok(())
-    }
-
-    /// Check cache, call update if past cache
-    async fn update_maybe(&mut self) {
-        if Utc::now() > self.cached_at + self.cache_threshold {
-            match self.update().await {
-                Ok(_) => {}
-                Err(e) => eprintln!("Error updating ThirdPlaceHandler, {:?}", e),
-            }
-        }
+        Ok(content)
     }
 
     /// This is a class method for testing purposes, otherwise need to mock the reqwest. Going to
@@ -112,25 +102,22 @@ impl ThirdPlaceHandler {
     }
 }
 
-#[async_trait]
-impl super::AsyncMutableHandler for ThirdPlaceHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            let input = message.trim();
-            if input.eq_ignore_ascii_case("!3rd") || input.eq_ignore_ascii_case("!third") {
-                self.update_maybe().await;
-                if let Some(ranking) = ThirdPlaceHandler::parse_content(&self.content) {
-                    send_privmsg(
-                        client,
-                        &channel,
-                        &format!("[3rd] {}", ranking[0..6].join("; ")),
-                    );
-                    send_privmsg(
-                        client,
-                        &channel,
-                        &format!("[3rd] {}", ranking[6..12].join("; ")),
-                    );
-                }
+#[async_trait(?Send)]
+impl super::Plugin for ThirdPlaceHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new("3rd", "List third place ranking").alias("third")]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, _invocation: &Invocation) {
+        let http = self.http.clone();
+        let content = match self.content.get(|| Self::fetch(http)).await {
+            Some(content) => content,
+            None => return,
+        };
+        if let Some(ranking) = ThirdPlaceHandler::parse_content(&content) {
+            // Six per line, there should be twelve
+            for line in ranking.chunks(6).take(2) {
+                outbound.reply(msg, &format!("[3rd] {}", line.join("; ")));
             }
         }
     }
@@ -140,12 +127,6 @@ impl super::help::Help for ThirdPlaceHandler {
     fn name(&self) -> String {
         String::from("3rd")
     }
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        vec![super::help::HelpEntry::new(
-            "!3rd",
-            "List third place ranking",
-        )]
-    }
 }
 
 #[cfg(test)]
@@ -1,3 +1,7 @@
+use super::clock;
+use super::outbound::Outbound;
+use super::router::{CommandSpec, Invocation};
+use async_trait::async_trait;
 use chrono::prelude::{DateTime, Utc};
 use irc::client::prelude::*;
 
@@ -7,31 +11,30 @@ impl TimeHandler {
     pub fn new() -> TimeHandler {
         TimeHandler {}
     }
+}
 
-    fn matcher(msg: &str) -> bool {
-        msg.eq_ignore_ascii_case("!time")
-            || msg.eq_ignore_ascii_case("!gmt")
-            || msg.eq_ignore_ascii_case("!utc")
-            || msg.eq_ignore_ascii_case("!now")
+#[async_trait(?Send)]
+impl super::Plugin for TimeHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new("time", "Show the current time in UTC")
+                .alias("utc")
+                .alias("now"),
+            CommandSpec::new("gmt", "GMT is deprecated."),
+        ]
     }
-}
 
-impl super::Handler for TimeHandler {
-    fn handle(&self, client: &Client, msg: &Message) {
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if TimeHandler::matcher(message) {
-                let now: DateTime<Utc> = Utc::now();
-                let now = now
-                    .format("It is currently %A %d %B %Y %H:%M:%S UTC.")
-                    .to_string();
-                let now = if message.eq_ignore_ascii_case("!gmt") {
-                    String::from("Lol GMT, get with the times, grandpa. ") + &now
-                } else {
-                    now
-                };
-                client.send_privmsg(&channel, &now).unwrap();
-            }
-        }
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        let now: DateTime<Utc> = clock::now();
+        let now = now
+            .format("It is currently %A %d %B %Y %H:%M:%S UTC.")
+            .to_string();
+        let now = if invocation.command == "gmt" {
+            String::from("Lol GMT, get with the times, grandpa. ") + &now
+        } else {
+            now
+        };
+        outbound.reply(msg, &now);
     }
 }
 
@@ -39,14 +42,6 @@ impl super::help::Help for TimeHandler {
     fn name(&self) -> String {
         String::from("time")
     }
-
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        let result = vec![
-            super::help::HelpEntry::new("!time / !utc / !now", "Show the current time in UTC"),
-            super::help::HelpEntry::new("!gmt", "GMT is deprecated."),
-        ];
-        result
-    }
 }
 
 impl Default for TimeHandler {
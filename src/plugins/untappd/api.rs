@@ -2,42 +2,44 @@
 //!
 //! TODO Also fetch the rating for a beer (downside: another API call required)
 
-const USER_AGENT: &str = "rusty-butler-untappd-plugin";
+use super::super::http::Http;
+use super::super::metrics;
 
-pub async fn search(query: &str, client_id: &str, client_secret: &str) -> Vec<BeerResult> {
-    let url = "https://api.untappd.com/v4/search/beer";
-    let client = reqwest::Client::new();
-    let req = client
-        .get(url)
-        .query(&[
-            ("client_id", client_id),
-            ("client_secret", client_secret),
-            ("q", query), // Encodes it for us
-        ])
-        .header(reqwest::header::USER_AGENT, USER_AGENT);
+pub async fn search(
+    http: &Http,
+    query: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> Vec<BeerResult> {
+    let url = http.url("untappd", "https://api.untappd.com/v4", "/search/beer");
+    let req = http.get(&url).query(&[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("q", query), // Encodes it for us
+    ]);
     // TODO Keep track of failure information to pass it to the user
-    match req.send().await {
-        Ok(resp) => match resp.text().await {
-            Ok(untappd_str) => match serde_json::from_str::<UntappdApiReply>(&untappd_str) {
-                Ok(untappd_search) => match untappd_search.response {
-                    Some(response) => response.beers.items,
-                    None => {
-                        eprintln!("Received error from Untappd API: {:?}", untappd_search);
-                        vec![]
-                    }
-                },
-                Err(e) => {
-                    eprintln!("Error parsing json: {}", e);
-                    eprintln!("Response: {}", untappd_str);
+    match http.text(req).await {
+        Ok(untappd_str) => match serde_json::from_str::<UntappdApiReply>(&untappd_str) {
+            Ok(untappd_search) => match untappd_search.response {
+                Some(response) => {
+                    metrics::fetched("untappd", true);
+                    response.beers.items
+                }
+                None => {
+                    metrics::fetched("untappd", false);
+                    eprintln!("Received error from Untappd API: {:?}", untappd_search);
                     vec![]
                 }
             },
             Err(e) => {
-                eprintln!("Failed to get text from Untappd Response: {}", e);
+                metrics::fetched("untappd", false);
+                eprintln!("Error parsing json: {}", e);
+                eprintln!("Response: {}", untappd_str);
                 vec![]
             }
         },
         Err(e) => {
+            metrics::fetched("untappd", false);
             eprintln!("Error fetching from Untappd: {}", e);
             vec![]
         }
@@ -176,7 +178,7 @@ mod tests {
             )
         };
         let response = include_str!("untappd.rochefort.json");
-        let response: UntappdApiReply = serde_json::from_str(&response).unwrap();
+        let response: UntappdApiReply = serde_json::from_str(response).unwrap();
         println!("{:#?}", response);
         assert_eq!(
             response.response.unwrap().beers.items[0],
@@ -201,7 +203,7 @@ mod tests {
             response: None,
         };
         let response = include_str!("untappd.api.failure.json");
-        let response: UntappdApiReply = serde_json::from_str(&response).unwrap();
+        let response: UntappdApiReply = serde_json::from_str(response).unwrap();
         println!("{:#?}", response);
         assert_eq!(parsed_reponse, response);
     }
@@ -1,37 +1,30 @@
+use super::outbound::Outbound;
+use super::router::{Arg, CommandSpec, Invocation};
 use async_trait::async_trait;
 use irc::client::prelude::*;
-use regex::Regex;
 
 pub mod api;
 
 pub struct UntappdHandler {
-    client_id: Option<String>,
-    client_secret: Option<String>,
-    untappd_matcher: Regex,
+    http: super::http::Http,
+    client_id: String,
+    client_secret: String,
 }
 
 impl UntappdHandler {
     /// Create UntappdHandler using a valid irc config. Requires untappd_client_id and
-    /// untappd_client_secret to be set in the options section.
-    pub fn new(config: &Config) -> Self {
-        let untappd_matcher = Regex::new(r"^!(?:untappd|beer) (.*)$").unwrap();
+    /// untappd_client_secret to be set in the options section, None if they are missing.
+    pub fn new(config: &Config, http: &super::http::Http) -> Option<Self> {
         match (
             config.options.get("untappd_client_id"),
             config.options.get("untappd_client_secret"),
         ) {
-            (Some(client_id), Some(client_secret)) => Self {
-                client_id: Some(client_id.to_owned()),
-                client_secret: Some(client_secret.to_owned()),
-                untappd_matcher,
-            },
-            _ => {
-                println!("Missing untappd_client_id or untappd_client_secret in options section, disabling plugin");
-                Self {
-                    client_id: None,
-                    client_secret: None,
-                    untappd_matcher,
-                }
-            }
+            (Some(client_id), Some(client_secret)) => Some(Self {
+                http: http.clone(),
+                client_id: client_id.to_owned(),
+                client_secret: client_secret.to_owned(),
+            }),
+            _ => None,
         }
     }
 }
@@ -41,39 +34,32 @@ impl UntappdHandler {
 
 // This one is most definitely not in need of mutability at the time of writing, but I only have
 // the one async one.
-#[async_trait]
-impl super::AsyncMutableHandler for UntappdHandler {
-    async fn handle(&mut self, client: &Client, msg: &Message) {
-        if self.client_id.is_none() || self.client_secret.is_none() {
-            return;
-        }
-        if let Command::PRIVMSG(ref channel, ref message) = msg.command {
-            if let Some(captures) = self.untappd_matcher.captures(message) {
-                if let Some(query) = captures.get(1) {
-                    let query = query.as_str();
-                    let beers = api::search(
-                        query,
-                        self.client_id.as_ref().unwrap(),
-                        self.client_secret.as_ref().unwrap(),
-                    )
-                    .await;
-                    if beers.is_empty() {
-                        super::send_privmsg(client, channel, "Your query returned no results");
-                    } else if beers.len() == 1 {
-                        super::send_privmsg(client, channel, &beers[0].to_irc());
-                    } else {
-                        super::send_privmsg(
-                            client,
-                            channel,
-                            &format!(
-                                "{} --- {} more results",
-                                &beers[0].to_irc(),
-                                beers.len() - 1,
-                            ),
-                        );
-                    }
-                }
-            }
+#[async_trait(?Send)]
+impl super::Plugin for UntappdHandler {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new("untappd", "Search for beer matching your search term.")
+                .alias("beer")
+                .arg(Arg::text("SEARCHTERM")),
+        ]
+    }
+
+    async fn command(&mut self, outbound: &Outbound, msg: &Message, invocation: &Invocation) {
+        let query = invocation.get("SEARCHTERM").unwrap_or_default();
+        let beers = api::search(&self.http, query, &self.client_id, &self.client_secret).await;
+        if beers.is_empty() {
+            outbound.reply(msg, "Your query returned no results");
+        } else if beers.len() == 1 {
+            outbound.reply(msg, &beers[0].to_irc());
+        } else {
+            outbound.reply(
+                msg,
+                &format!(
+                    "{} --- {} more results",
+                    &beers[0].to_irc(),
+                    beers.len() - 1,
+                ),
+            );
         }
     }
 }
@@ -83,21 +69,11 @@ impl super::help::Help for UntappdHandler {
         String::from("untappd")
     }
 
-    fn help(&self) -> Vec<super::help::HelpEntry> {
-        let result = vec![
-            super::help::HelpEntry::new(
-                "!untappd searchterm",
-                "Search for beer matching your search term.",
-            ),
-            super::help::HelpEntry::new(
-                "!beer searchterm",
-                "Search for beer matching your search term.",
-            ),
-        ];
-        // result.push(super::help::HelpEntry::new(
-        //     "@NUMBER",
-        //     "Modifier for your search, return the NUMBERth result.",
-        // ));
-        result
-    }
+    // TODO once @number is in
+    // fn help(&self) -> Vec<super::help::HelpEntry> {
+    //     vec![super::help::HelpEntry::new(
+    //         "@NUMBER",
+    //         "Modifier for your search, return the NUMBERth result.",
+    //     )]
+    // }
 }
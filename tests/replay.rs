@@ -0,0 +1,117 @@
+//! Golden file tests. Every `*.irc` file in tests/replay is a transcript of lines coming in from
+//! the server. They go through all the plugins with the clock stopped and HTTP answered from the
+//! fixtures in tests/replay/fixtures (see `plugins::http`). What the bot says back has to match the `.golden` file next to it.
+//!
+//! When replies change on purpose, run `UPDATE_GOLDEN=1 cargo test --test replay` to write new
+//! golden files and check their diff.
+
+use irc::client::prelude::*;
+use rusty_butler_lib::plugins;
+use std::path::Path;
+
+const FROZEN_AT: &str = "2026-06-20T18:00:00Z";
+
+fn irc_config() -> Config {
+    let options = [
+        ("untappd_client_id", "replay"),
+        ("untappd_client_secret", "replay"),
+        ("admin_hostmasks", "ward!*@admin.example.org"),
+    ];
+    Config {
+        nickname: Some(String::from("butler")),
+        options: options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Runs the transcript, gives every line that came in (`>`) followed by what we sent (`<`).
+fn replay(transcript: &str) -> String {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&runtime, async {
+        plugins::clock::freeze(FROZEN_AT.parse().unwrap());
+        let plugin_config: plugins::config::Config =
+            toml::from_str(&std::fs::read_to_string("tests/replay/plugins.toml").unwrap()).unwrap();
+        let storage = plugins::storage::Storage::in_memory().unwrap();
+        // Only athletes with a link show up on the leaderboard, those with nicks only if they
+        // were seen lately
+        let links: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/replay/strava_irc_links.json").unwrap(),
+        )
+        .unwrap();
+        storage.set("strava", "irc_links", &links).unwrap();
+        let http = plugins::http::Http::new(&plugin_config.http).unwrap();
+        let (admin_requests, _admin_actions) = tokio::sync::mpsc::unbounded_channel();
+        let handlers = plugins::build(
+            &irc_config(),
+            &plugin_config,
+            &storage,
+            &http,
+            &plugins::bus::Bus::new(),
+            admin_requests,
+        )
+        .await;
+        let (outbound, recorder) = plugins::outbound::Outbound::recording();
+        let limiter = plugins::ratelimit::RateLimiter::new(plugin_config.ratelimit.clone());
+        let mut dispatcher =
+            plugins::dispatch::Dispatcher::new(outbound, limiter, plugin_config.quarantine.clone());
+        for handler in handlers {
+            let settings = plugin_config.plugin(&handler.name());
+            dispatcher.add(handler, settings);
+        }
+        let alias_plugin = plugins::alias::AliasPlugin::new(&plugin_config);
+
+        let mut output = String::new();
+        for line in transcript.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let msg: Message = line.parse().expect("Transcript lines should be valid IRC");
+            dispatcher.dispatch(alias_plugin.rewrite(msg));
+            dispatcher.flush().await;
+            output.push_str(&format!("> {}\n", line));
+            for sent in recorder.take() {
+                output.push_str(&format!("< {}\n", sent));
+            }
+        }
+        output
+    })
+}
+
+#[test]
+fn golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut transcripts: Vec<_> = std::fs::read_dir("tests/replay")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "irc"))
+        .collect();
+    transcripts.sort();
+    assert!(!transcripts.is_empty());
+    for transcript in transcripts {
+        let output = replay(&std::fs::read_to_string(&transcript).unwrap());
+        let golden = transcript.with_extension("golden");
+        if update {
+            std::fs::write(&golden, &output).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&golden).unwrap_or_else(|_| {
+            panic!(
+                "No {}, run with UPDATE_GOLDEN=1 to create it",
+                golden.display()
+            )
+        });
+        assert_eq!(
+            output,
+            expected,
+            "Replies to {} changed",
+            Path::new(&transcript).display()
+        );
+    }
+}